@@ -8,9 +8,21 @@ use flate2::Compression;
 use tar::Builder;
 
 use crate::database;
+use crate::database::storage;
+use crate::database::storage::Storage;
+use crate::models::access;
+use crate::models::account;
 use crate::models::audiobook;
+use crate::models::author;
+use crate::models::collection;
+use crate::models::export;
+use crate::models::invite;
 use crate::models::position;
+use crate::models::series;
+use crate::models::session;
 use crate::utils::error;
+use crate::utils::ldap;
+use crate::utils::validation;
 
 #[derive(serde::Serialize)]
 pub struct Answer {
@@ -23,19 +35,80 @@ pub struct ApiKey {
     pub key: String,
 }
 
+/// Answer of a login, the permanent api key unless a session was asked for
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+pub enum Login {
+    Key(ApiKey),
+    Session(session::Session),
+}
+
 pub async fn get_audiobooks(
-    pool: &sqlx::Pool<sqlx::Sqlite>,
-) -> Result<Json<audiobook::Audiobooks>, Json<error::Answer>> {
-    let audiobooks = match database::schema::query_audiobooks(pool).await {
+    query: audiobook::AudiobookQuery,
+    user: String,
+    db: &dyn Storage,
+) -> Result<Json<audiobook::Audiobooks>, error::Answer> {
+    let search = match query.parse() {
+        Some(search) => search,
+        None => {
+            return Err(error::query_invalid());
+        }
+    };
+
+    let audiobooks = match db.query_audiobooks(&search, user).await {
         // TODO - this is probably bad.. right?
         Ok(audiobooks) => audiobooks,
         Err(_) => {
-            return Err(Json(error::audiobooks_cant_query()));
+            return Err(error::audiobooks_cant_query());
         }
     };
     Ok(Json(audiobooks))
 }
 
+pub async fn get_authors(
+    user: String,
+    db: &dyn Storage,
+) -> Result<Json<author::Authors>, error::Answer> {
+    match db.query_authors(user).await {
+        Ok(authors) => Ok(Json(authors)),
+        Err(_) => Err(error::authors_cant_query()),
+    }
+}
+
+pub async fn get_author_audiobooks(
+    id: String,
+    user: String,
+    db: &dyn Storage,
+) -> Result<Json<author::AuthorAudiobooks>, error::Answer> {
+    match db.query_author_audiobooks(id, user).await {
+        Ok(audiobooks) => Ok(Json(audiobooks)),
+        Err(sqlx::Error::RowNotFound) => Err(error::author_not_found()),
+        Err(_) => Err(error::authors_cant_query()),
+    }
+}
+
+pub async fn get_series(
+    user: String,
+    db: &dyn Storage,
+) -> Result<Json<series::Series>, error::Answer> {
+    match db.query_series(user).await {
+        Ok(series) => Ok(Json(series)),
+        Err(_) => Err(error::series_cant_query()),
+    }
+}
+
+pub async fn get_series_audiobooks(
+    id: String,
+    user: String,
+    db: &dyn Storage,
+) -> Result<Json<series::SeriesAudiobooks>, error::Answer> {
+    match db.query_series_audiobooks(id, user).await {
+        Ok(audiobooks) => Ok(Json(audiobooks)),
+        Err(sqlx::Error::RowNotFound) => Err(error::series_not_found()),
+        Err(_) => Err(error::series_cant_query()),
+    }
+}
+
 pub async fn archive_directory(dir: &Path) -> Result<Vec<u8>, std::io::Error> {
     let dir = match std::fs::read_dir(dir) {
         Ok(dir) => dir,
@@ -61,10 +134,7 @@ pub async fn archive_directory(dir: &Path) -> Result<Vec<u8>, std::io::Error> {
                 let file_name = match path.file_name() {
                     Some(file_name) => file_name.to_owned(),
                     None => {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "Could not get file name",
-                        ));
+                        return Err(std::io::Error::other("Could not get file name"));
                     }
                 };
                 match builder.append_path_with_name(&path, &file_name) {
@@ -89,14 +159,15 @@ pub async fn archive_directory(dir: &Path) -> Result<Vec<u8>, std::io::Error> {
 
 pub async fn get_audiobook(
     hash: String,
-    pool: &sqlx::Pool<sqlx::Sqlite>,
-) -> Result<Vec<u8>, Json<error::Answer>> {
-    let path = database::schema::query_audiobook(hash, pool).await;
+    user: String,
+    db: &dyn Storage,
+) -> Result<Vec<u8>, error::Answer> {
+    let path = db.query_audiobook(hash, user).await;
 
     let path = match path {
         Ok(path) => path,
         Err(_) => {
-            return Err(Json(error::hash_cant_query()));
+            return Err(error::hash_cant_query());
         }
     };
 
@@ -105,82 +176,948 @@ pub async fn get_audiobook(
     let binary_data = match binary_data {
         Ok(binary_data) => binary_data,
         Err(_) => {
-            return Err(Json(error::binary_cant_create()));
+            return Err(error::binary_cant_create());
         }
     };
 
     Ok(binary_data)
 }
 
+/// Checks that a book exists and that the user can see it
+async fn check_audiobook(
+    hash: String,
+    user: String,
+    db: &dyn Storage,
+) -> Result<(), error::Answer> {
+    match db.query_audiobook(hash, user).await {
+        Ok(_) => Ok(()),
+        Err(sqlx::Error::RowNotFound) => Err(error::hash_cant_query()),
+        Err(_) => Err(error::audiobooks_cant_query()),
+    }
+}
+
 pub async fn post_audiobook_position(
     hash: String,
     user: String,
-    file: String,
-    position: u32,
-    pool: &sqlx::Pool<sqlx::Sqlite>,
-) -> Json<error::Answer> {
-    let res = database::schema::insert_position(hash, user, file, position, pool).await;
+    position: &position::NewPosition,
+    db: &dyn Storage,
+) -> error::Answer {
+    let position_ms = match position.to_ms() {
+        Some(position_ms) => position_ms,
+        None => {
+            return error::field_invalid("position_ms", "is required");
+        }
+    };
 
-    match res {
-        Ok(_) => {
-            return Json(error::success());
+    if let Some(speed) = position.speed {
+        if !(speed > 0.0 && speed <= 16.0) {
+            return error::field_invalid("speed", "must be above 0 and at most 16");
+        }
+    }
+
+    if let Err(err) = check_audiobook(hash.clone(), user.clone(), db).await {
+        return err;
+    }
+
+    let duration = match db
+        .query_audiobook_file(hash.clone(), position.file.clone())
+        .await
+    {
+        Ok(duration) => duration,
+        Err(sqlx::Error::RowNotFound) => {
+            return error::field_invalid("file", "is not an audio file of this audiobook");
         }
         Err(_) => {
-            return Json(error::position_cant_update());
+            return error::position_cant_update();
         }
     };
+
+    // durations read from the files are rounded, so allow the last second
+    if let Some(duration) = duration {
+        let duration_ms = duration.ceil() * 1000.0;
+        if position_ms as f64 > duration_ms {
+            return error::field_invalid(
+                "position_ms",
+                &format!(
+                    "is past the end of the file ({} milliseconds long)",
+                    duration_ms
+                ),
+            );
+        }
+    }
+
+    let res = db
+        .insert_position(
+            hash,
+            user,
+            position.file.clone(),
+            position_ms,
+            position.speed,
+            position.chapter,
+        )
+        .await;
+
+    match res {
+        Ok(_) => error::success(),
+        Err(_) => error::position_cant_update(),
+    }
 }
 
 pub async fn get_audiobook_position(
     hash: String,
     user: String,
-    pool: &sqlx::Pool<sqlx::Sqlite>,
-) -> Result<Json<position::Position>, Json<error::Answer>> {
-    let position = database::schema::select_position(hash, user, pool).await;
+    db: &dyn Storage,
+) -> Result<Json<position::Position>, error::Answer> {
+    check_audiobook(hash.clone(), user.clone(), db).await?;
+
+    let position = db.select_position(hash, user).await;
 
     match position {
-        Ok(position) => {
-            return Ok(Json(position::Position {
-                file: position.file,
-                position: position.position,
-            }));
+        Ok(Some(position)) => Ok(Json(position)),
+        Ok(None) => Err(error::position_not_found()),
+        Err(_) => Err(error::position_cant_query()),
+    }
+}
+
+/// Checks a new username and password against the validation rules, and
+/// that no account already has the same name regardless of case
+async fn check_new_account(
+    user: &str,
+    password: &str,
+    rules: &validation::Rules,
+    db: &dyn Storage,
+) -> Result<(), error::Answer> {
+    rules.check_user(user)?;
+
+    rules.check_password(password)?;
+
+    match db.query_account_nocase(user.to_string()).await {
+        Ok(_) => Err(error::account_exists()),
+        Err(sqlx::Error::RowNotFound) => Ok(()),
+        Err(_) => Err(error::cant_register()),
+    }
+}
+
+pub async fn post_account(
+    user: String,
+    password: String,
+    rules: &validation::Rules,
+    db: &dyn Storage,
+) -> Result<Json<ApiKey>, error::Answer> {
+    check_new_account(&user, &password, rules, db).await?;
+
+    let key = db.insert_user(user, password).await;
+
+    match key {
+        Ok(key) => Ok(Json(ApiKey { key })),
+        Err(err) if storage::is_unique_violation(&err) => Err(error::account_exists()),
+        Err(_) => Err(error::cant_register()),
+    }
+}
+
+pub async fn post_invited_account(
+    user: String,
+    password: String,
+    invite: Option<String>,
+    rules: &validation::Rules,
+    db: &dyn Storage,
+) -> Result<Json<ApiKey>, error::Answer> {
+    let invite = match invite {
+        Some(invite) => invite,
+        None => {
+            return Err(error::invite_invalid());
+        }
+    };
+
+    // whether the name is taken is only told to callers holding a valid
+    // invite, the insert checks the invite before the unique index does
+    rules.check_user(&user)?;
+
+    rules.check_password(&password)?;
+
+    let key = db.insert_user_with_invite(user, password, invite).await;
+
+    match key {
+        Ok(key) => Ok(Json(ApiKey { key })),
+        Err(sqlx::Error::RowNotFound) => Err(error::invite_invalid()),
+        Err(err) if storage::is_unique_violation(&err) => Err(error::account_exists()),
+        Err(_) => Err(error::cant_register()),
+    }
+}
+
+/// Authenticates a user against the ldap server, creating their local
+/// account the first time they login. Gives none when ldap did not
+/// authenticate them, or when the name belongs to an account that was not
+/// created from ldap.
+#[cfg(feature = "ldap")]
+async fn ldap_login(
+    config: &ldap::LdapConfig,
+    user: String,
+    password: String,
+    db: &dyn Storage,
+) -> Option<String> {
+    let answer = ldap::authenticate(config, &user, &password).await;
+    ldap_answer(answer, user, db).await
+}
+
+/// Acts on what the ldap server answered to a login: a member of the group
+/// gets the key of their ldap account, one who left the group has it disabled
+#[cfg(feature = "ldap")]
+async fn ldap_answer(
+    answer: Result<ldap::Authentication, ldap3::LdapError>,
+    user: String,
+    db: &dyn Storage,
+) -> Option<String> {
+    match answer {
+        Ok(ldap::Authentication::Accepted) => db.query_or_insert_ldap_key(user).await.ok(),
+        Ok(ldap::Authentication::NotInGroup) => {
+            // an administrator has to enable the account again
+            if let Err(err) = db.update_ldap_disabled(user).await {
+                eprintln!(
+                    "Could not disable an account that left the ldap group: {}",
+                    err
+                );
+            }
+            None
+        }
+        Ok(ldap::Authentication::Rejected) => None,
+        Err(err) => {
+            eprintln!("Could not authenticate against ldap: {}", err);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "ldap"))]
+async fn ldap_login(
+    _config: &ldap::LdapConfig,
+    _user: String,
+    _password: String,
+    _db: &dyn Storage,
+) -> Option<String> {
+    None
+}
+
+/// Finds the key of a user authenticated by a reverse proxy. Their account is
+/// created on the first request, but only when the name follows the same rules
+/// as a registration; accounts created before the rules changed still work.
+pub async fn get_proxy_key(
+    user: String,
+    rules: &validation::Rules,
+    db: &dyn Storage,
+) -> Option<String> {
+    match db.query_account(user.clone()).await {
+        Ok(_) => {}
+        Err(sqlx::Error::RowNotFound) => {
+            if rules.check_user(&user).is_err() {
+                return None;
+            }
         }
         Err(_) => {
-            return Err(Json(error::position_cant_query()));
+            return None;
         }
-    };
+    }
+
+    db.query_or_insert_user_key(user).await.ok()
 }
 
-pub async fn post_account(
+pub async fn get_account(
     user: String,
     password: String,
-    pool: &sqlx::Pool<sqlx::Sqlite>,
-) -> Result<Json<ApiKey>, Json<error::Answer>> {
-    let key = database::schema::insert_user(user, password, pool).await;
+    session: bool,
+    lifetimes: &session::Lifetimes,
+    ldap: Option<&ldap::LdapConfig>,
+    db: &dyn Storage,
+) -> Result<Json<Login>, error::Answer> {
+    let ldap_key = match ldap {
+        Some(ldap) => ldap_login(ldap, user.clone(), password.clone(), db).await,
+        None => None,
+    };
 
-    match key {
-        Ok(key) => {
-            return Ok(Json(ApiKey { key: key }));
+    // accounts that only exist locally, like the first administrator, can
+    // still login with their own password
+    let key = match ldap_key {
+        Some(key) => key,
+        None => match db.select_user(user.clone(), password).await {
+            Ok(key) => key,
+            Err(_) => {
+                return Err(error::cant_login());
+            }
+        },
+    };
+
+    if !session {
+        return Ok(Json(Login::Key(ApiKey { key })));
+    }
+
+    match db.insert_session(user, lifetimes).await {
+        Ok(session) => Ok(Json(Login::Session(session))),
+        Err(_) => Err(error::cant_login()),
+    }
+}
+
+pub async fn post_refresh(
+    refresh_token: String,
+    lifetimes: &session::Lifetimes,
+    db: &dyn Storage,
+) -> Result<Json<session::Session>, error::Answer> {
+    match db.update_session(refresh_token, lifetimes).await {
+        Ok(session) => Ok(Json(session)),
+        Err(sqlx::Error::RowNotFound) => Err(error::session_invalid()),
+        Err(_) => Err(error::cant_login()),
+    }
+}
+
+pub async fn post_logout(token: String, db: &dyn Storage) -> error::Answer {
+    match db.delete_session(token).await {
+        Ok(_) => error::success(),
+        Err(_) => error::cant_login(),
+    }
+}
+
+pub async fn get_collections(
+    user: String,
+    db: &dyn Storage,
+) -> Result<Json<collection::Collections>, error::Answer> {
+    match db.query_collections(user).await {
+        Ok(collections) => Ok(Json(collections)),
+        Err(_) => Err(error::collection_cant_query()),
+    }
+}
+
+pub async fn get_collection(
+    id: i64,
+    user: String,
+    db: &dyn Storage,
+) -> Result<Json<collection::Collection>, error::Answer> {
+    match db.query_collection(id, user).await {
+        Ok(collection) => Ok(Json(collection)),
+        Err(sqlx::Error::RowNotFound) => Err(error::collection_not_found()),
+        Err(_) => Err(error::collection_cant_query()),
+    }
+}
+
+/// Checks that a collection has a name, blanks do not count
+fn check_collection_name(name: &str) -> Result<(), error::Answer> {
+    if name.trim().is_empty() {
+        return Err(error::field_invalid("name", "can not be empty"));
+    }
+
+    Ok(())
+}
+
+pub async fn post_collection(
+    user: String,
+    name: String,
+    db: &dyn Storage,
+) -> Result<Json<collection::Collection>, error::Answer> {
+    check_collection_name(&name)?;
+
+    let id = match db.insert_collection(user.clone(), name).await {
+        Ok(id) => id,
+        Err(_) => {
+            return Err(error::collection_cant_update());
+        }
+    };
+
+    get_collection(id, user, db).await
+}
+
+/// Fetches a collection the user is allowed to modify, shared collections
+/// are read only for everyone but their owner
+async fn owned_collection(
+    id: i64,
+    user: String,
+    db: &dyn Storage,
+) -> Result<collection::Collection, error::Answer> {
+    let collection = match db.query_collection(id, user.clone()).await {
+        Ok(collection) => collection,
+        Err(sqlx::Error::RowNotFound) => {
+            return Err(error::collection_not_found());
         }
         Err(_) => {
-            return Err(Json(error::cant_register()));
+            return Err(error::collection_cant_query());
         }
     };
+
+    if collection.owner != user {
+        return Err(error::collection_forbidden());
+    }
+
+    Ok(collection)
 }
 
-pub async fn get_account(
+pub async fn put_collection(
+    id: i64,
+    user: String,
+    name: String,
+    db: &dyn Storage,
+) -> error::Answer {
+    if let Err(err) = check_collection_name(&name) {
+        return err;
+    }
+
+    if let Err(err) = owned_collection(id, user, db).await {
+        return err;
+    }
+
+    match db.update_collection(id, name).await {
+        Ok(_) => error::success(),
+        Err(_) => error::collection_cant_update(),
+    }
+}
+
+pub async fn delete_collection(id: i64, user: String, db: &dyn Storage) -> error::Answer {
+    if let Err(err) = owned_collection(id, user, db).await {
+        return err;
+    }
+
+    match db.delete_collection(id).await {
+        Ok(_) => error::success(),
+        Err(_) => error::collection_cant_update(),
+    }
+}
+
+pub async fn put_collection_audiobook(
+    id: i64,
+    hash: String,
+    user: String,
+    db: &dyn Storage,
+) -> error::Answer {
+    if let Err(err) = owned_collection(id, user.clone(), db).await {
+        return err;
+    }
+
+    if db.query_audiobook(hash.clone(), user).await.is_err() {
+        return error::hash_cant_query();
+    }
+
+    match db.insert_collection_audiobook(id, hash).await {
+        Ok(_) => error::success(),
+        Err(_) => error::collection_cant_update(),
+    }
+}
+
+pub async fn delete_collection_audiobook(
+    id: i64,
+    hash: String,
+    user: String,
+    db: &dyn Storage,
+) -> error::Answer {
+    if let Err(err) = owned_collection(id, user, db).await {
+        return err;
+    }
+
+    match db.delete_collection_audiobook(id, hash).await {
+        Ok(_) => error::success(),
+        Err(_) => error::collection_cant_update(),
+    }
+}
+
+pub async fn put_collection_share(
+    id: i64,
+    share: String,
+    user: String,
+    db: &dyn Storage,
+) -> error::Answer {
+    if let Err(err) = owned_collection(id, user.clone(), db).await {
+        return err;
+    }
+
+    if share == user {
+        return error::field_invalid("user", "is the owner of the collection");
+    }
+
+    // sharing with a name nobody has answers like sharing with an account,
+    // or anyone could find out which usernames exist
+    match db.query_account(share.clone()).await {
+        Ok(_) => {}
+        Err(sqlx::Error::RowNotFound) => {
+            return error::success();
+        }
+        Err(_) => {
+            return error::collection_cant_update();
+        }
+    };
+
+    match db.insert_collection_share(id, share).await {
+        Ok(_) => error::success(),
+        Err(_) => error::collection_cant_update(),
+    }
+}
+
+pub async fn delete_collection_share(
+    id: i64,
+    share: String,
+    user: String,
+    db: &dyn Storage,
+) -> error::Answer {
+    if let Err(err) = owned_collection(id, user, db).await {
+        return err;
+    }
+
+    match db.delete_collection_share(id, share).await {
+        Ok(_) => error::success(),
+        Err(_) => error::collection_cant_update(),
+    }
+}
+
+/// Checks the password of a user before letting them modify their account
+async fn check_password(
     user: String,
     password: String,
-    pool: &sqlx::Pool<sqlx::Sqlite>,
-) -> Result<Json<ApiKey>, Json<error::Answer>> {
-    let key = database::schema::select_user(user, password, pool).await;
+    db: &dyn Storage,
+) -> Result<(), error::Answer> {
+    match db.select_user(user, password).await {
+        Ok(_) => Ok(()),
+        Err(sqlx::Error::RowNotFound) => Err(error::password_invalid()),
+        Err(_) => Err(error::account_cant_update()),
+    }
+}
 
-    match key {
-        Ok(key) => {
-            return Ok(Json(ApiKey { key: key }));
+pub async fn put_own_password(
+    user: String,
+    change: &account::PasswordChange,
+    rules: &validation::Rules,
+    db: &dyn Storage,
+) -> error::Answer {
+    if let Err(err) = check_password(user.clone(), change.old_password.clone(), db).await {
+        return err;
+    }
+
+    if let Err(err) = rules.check_password(&change.password) {
+        return err;
+    }
+
+    account_answer(db.update_password(user, change.password.clone()).await)
+}
+
+pub async fn post_own_key(user: String, db: &dyn Storage) -> Result<Json<ApiKey>, error::Answer> {
+    match db.update_key(user).await {
+        Ok(key) => Ok(Json(ApiKey { key })),
+        Err(_) => Err(error::account_cant_update()),
+    }
+}
+
+pub async fn put_own_name(
+    user: String,
+    rename: &account::Rename,
+    rules: &validation::Rules,
+    db: &dyn Storage,
+) -> error::Answer {
+    if let Err(err) = check_password(user.clone(), rename.password.clone(), db).await {
+        return err;
+    }
+
+    if let Err(err) = rules.check_user(&rename.user) {
+        return err;
+    }
+
+    // changing the case of one's own name is fine
+    match db.query_account_nocase(rename.user.clone()).await {
+        Ok(existing) if existing != user => {
+            return error::account_exists();
         }
+        Ok(_) | Err(sqlx::Error::RowNotFound) => {}
         Err(_) => {
-            return Err(Json(error::cant_login()));
+            return error::account_cant_update();
         }
     };
+
+    match db.update_user(user, rename.user.clone()).await {
+        Err(err) if storage::is_unique_violation(&err) => error::account_exists(),
+        res => account_answer(res),
+    }
+}
+
+pub async fn delete_own_account(user: String, password: String, db: &dyn Storage) -> error::Answer {
+    if let Err(err) = check_password(user.clone(), password, db).await {
+        return err;
+    }
+
+    account_answer(db.delete_user(user).await)
+}
+
+pub async fn get_export(
+    user: String,
+    db: &dyn Storage,
+) -> Result<Json<export::Export>, error::Answer> {
+    match database::storage::export_user(user, db).await {
+        Ok(export) => Ok(Json(export)),
+        Err(_) => Err(error::export_cant_query()),
+    }
+}
+
+pub async fn post_import(
+    user: String,
+    data: &export::Export,
+    db: &dyn Storage,
+) -> Result<Json<export::ImportReport>, error::Answer> {
+    if data.version > export::EXPORT_VERSION {
+        return Err(error::export_version_invalid());
+    }
+
+    match database::storage::import_user(user, data, false, db).await {
+        Ok(report) => Ok(Json(report)),
+        Err(_) => Err(error::import_cant_update()),
+    }
+}
+
+pub async fn get_accounts(db: &dyn Storage) -> Result<Json<account::Accounts>, error::Answer> {
+    match db.query_accounts().await {
+        Ok(accounts) => Ok(Json(accounts)),
+        Err(_) => Err(error::accounts_cant_query()),
+    }
+}
+
+pub async fn post_admin_account(
+    account: &account::AdminNewAccount,
+    rules: &validation::Rules,
+    db: &dyn Storage,
+) -> Result<Json<ApiKey>, error::Answer> {
+    check_new_account(&account.user, &account.password, rules, db).await?;
+
+    let key = db
+        .insert_admin_user(
+            account.user.clone(),
+            account.password.clone(),
+            account.is_admin,
+        )
+        .await;
+
+    match key {
+        Ok(key) => Ok(Json(ApiKey { key })),
+        Err(err) if storage::is_unique_violation(&err) => Err(error::account_exists()),
+        Err(_) => Err(error::cant_register()),
+    }
+}
+
+/// Turns the result of an update on an account into an answer
+fn account_answer(res: Result<(), sqlx::Error>) -> error::Answer {
+    match res {
+        Ok(_) => error::success(),
+        Err(sqlx::Error::RowNotFound) => error::account_not_found(),
+        Err(_) => error::account_cant_update(),
+    }
+}
+
+pub async fn put_account_disabled(
+    user: String,
+    admin: String,
+    disabled: bool,
+    db: &dyn Storage,
+) -> error::Answer {
+    // an administrator locking themselves out could leave the server without one
+    if user == admin {
+        return error::cant_modify_self();
+    }
+
+    account_answer(db.update_disabled(user, disabled).await)
+}
+
+pub async fn delete_account(user: String, admin: String, db: &dyn Storage) -> error::Answer {
+    if user == admin {
+        return error::cant_modify_self();
+    }
+
+    account_answer(db.delete_user(user).await)
+}
+
+pub async fn put_account_password(
+    user: String,
+    password: String,
+    rules: &validation::Rules,
+    db: &dyn Storage,
+) -> error::Answer {
+    if let Err(err) = rules.check_password(&password) {
+        return err;
+    }
+
+    account_answer(db.update_password(user, password).await)
+}
+
+pub async fn delete_account_key(user: String, db: &dyn Storage) -> error::Answer {
+    account_answer(db.update_key(user).await.map(|_| ()))
+}
+
+pub async fn post_rescan(
+    data: &PathBuf,
+    db: &dyn Storage,
+) -> Result<Json<audiobook::ScanReport>, error::Answer> {
+    match database::storage::scan_audiobooks(data, db).await {
+        Ok(report) => Ok(Json(report)),
+        Err(_) => Err(error::library_cant_scan()),
+    }
+}
+
+pub async fn get_invites(db: &dyn Storage) -> Result<Json<invite::Invites>, error::Answer> {
+    match db.query_invites().await {
+        Ok(invites) => Ok(Json(invites)),
+        Err(_) => Err(error::invite_cant_update()),
+    }
+}
+
+pub async fn post_invite(
+    invite: &invite::NewInvite,
+    admin: String,
+    db: &dyn Storage,
+) -> Result<Json<invite::Invite>, error::Answer> {
+    let uses = invite.uses.unwrap_or(1);
+    if uses == 0 {
+        return Err(error::field_invalid("uses", "must be above 0"));
+    }
+
+    let expires = match invite.expires_in {
+        Some(expires_in) if expires_in <= 0 => {
+            return Err(error::field_invalid("expires_in", "must be above 0"));
+        }
+        Some(expires_in) => match chrono::Utc::now().timestamp().checked_add(expires_in) {
+            Some(expires) => Some(expires),
+            None => {
+                return Err(error::field_invalid("expires_in", "is too large"));
+            }
+        },
+        None => None,
+    };
+
+    match db.insert_invite(uses, expires, admin).await {
+        Ok(invite) => Ok(Json(invite)),
+        Err(_) => Err(error::invite_cant_update()),
+    }
+}
+
+pub async fn delete_invite(code: String, db: &dyn Storage) -> error::Answer {
+    match db.delete_invite(code).await {
+        Ok(_) => error::success(),
+        Err(sqlx::Error::RowNotFound) => error::invite_invalid(),
+        Err(_) => error::invite_cant_update(),
+    }
+}
+
+pub async fn get_access(db: &dyn Storage) -> Result<Json<access::Access>, error::Answer> {
+    match db.query_access().await {
+        Ok(access) => Ok(Json(access)),
+        Err(_) => Err(error::access_cant_update()),
+    }
+}
+
+pub async fn post_access_rule(
+    rule: &access::NewAccessRule,
+    db: &dyn Storage,
+) -> Result<Json<access::AccessRule>, error::Answer> {
+    if !rule.is_valid() {
+        return Err(error::access_rule_invalid());
+    }
+
+    match db.insert_access_rule(rule).await {
+        Ok(rule) => Ok(Json(rule)),
+        Err(_) => Err(error::access_cant_update()),
+    }
+}
+
+pub async fn delete_access_rule(id: i64, db: &dyn Storage) -> error::Answer {
+    match db.delete_access_rule(id).await {
+        Ok(_) => error::success(),
+        Err(sqlx::Error::RowNotFound) => error::access_rule_not_found(),
+        Err(_) => error::access_cant_update(),
+    }
+}
+
+pub async fn put_group_member(group: String, user: String, db: &dyn Storage) -> error::Answer {
+    if db.query_account(user.clone()).await.is_err() {
+        return error::account_not_found();
+    }
+
+    match db.insert_group_member(group, user).await {
+        Ok(_) => error::success(),
+        Err(_) => error::access_cant_update(),
+    }
+}
+
+pub async fn delete_group_member(group: String, user: String, db: &dyn Storage) -> error::Answer {
+    match db.delete_group_member(group, user).await {
+        Ok(_) => error::success(),
+        Err(_) => error::access_cant_update(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Opens an empty database of its own for a test
+    async fn pool() -> sqlx::Pool<sqlx::Sqlite> {
+        static DATABASES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "illiad-controllers-{}-{}.sqlite",
+            std::process::id(),
+            DATABASES.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let pool = database::schema::create_pool(
+            path,
+            database::schema::PoolSettings {
+                max_connections: 5,
+                busy_timeout: std::time::Duration::from_secs(5),
+            },
+        )
+        .await;
+        database::migrations::migrate(&pool).await.unwrap();
+        pool
+    }
+
+    #[rocket::async_test]
+    async fn validates_positions_against_the_files() {
+        let pool = pool().await;
+        database::schema::insert_user(String::from("alice"), String::from("secret"), &pool)
+            .await
+            .unwrap();
+        let files = vec![
+            audiobook::AudiobookFile {
+                name: String::from("01.mp3"),
+                duration: Some(599.4),
+            },
+            audiobook::AudiobookFile {
+                name: String::from("02.mp3"),
+                duration: None,
+            },
+        ];
+        database::schema::insert_audiobook(
+            audiobook::Audiobook {
+                title: String::from("Dune"),
+                author: String::from("Frank Herbert"),
+                path: String::from("/data/Dune"),
+                narrator: None,
+                description: None,
+                series: None,
+                series_index: None,
+                genre: None,
+                language: None,
+                tags: Vec::new(),
+                directory: String::from("Dune"),
+                files,
+            },
+            &pool,
+        )
+        .await
+        .unwrap();
+        let hash = audiobook::compute_hash(String::from("Dune"), String::from("Frank Herbert"));
+        let post = |hash: &str, file: &str, position_ms: Option<u64>, position: Option<u64>| {
+            let position = position::NewPosition {
+                file: String::from(file),
+                position_ms,
+                position,
+                speed: None,
+                chapter: None,
+            };
+            let hash = String::from(hash);
+            let db = database::sqlite::SqliteStorage::new(pool.clone());
+            async move { post_audiobook_position(hash, String::from("alice"), &position, &db).await }
+        };
+
+        // the last, rounded, second of a file is still accepted
+        assert_eq!(
+            post(&hash, "01.mp3", Some(600000), None).await.kind,
+            "success"
+        );
+        assert_eq!(post(&hash, "01.mp3", None, Some(600)).await.kind, "success");
+        assert_eq!(
+            post(&hash, "02.mp3", Some(100000000), None).await.kind,
+            "success"
+        );
+
+        let answer = post(&hash, "01.mp3", Some(600001), None).await;
+        assert_eq!(answer.field.as_deref(), Some("position_ms"));
+        let answer = post(&hash, "01.mp3", None, Some(601)).await;
+        assert_eq!(answer.field.as_deref(), Some("position_ms"));
+        let answer = post(&hash, "01.mp3", None, None).await;
+        assert_eq!(answer.field.as_deref(), Some("position_ms"));
+        let answer = post(&hash, "03.mp3", Some(10), None).await;
+        assert_eq!(answer.field.as_deref(), Some("file"));
+
+        let answer = post_audiobook_position(
+            hash,
+            String::from("alice"),
+            &position::NewPosition {
+                file: String::from("01.mp3"),
+                position_ms: Some(10),
+                position: None,
+                speed: Some(0.0),
+                chapter: None,
+            },
+            &database::sqlite::SqliteStorage::new(pool.clone()),
+        )
+        .await;
+        assert_eq!(answer.field.as_deref(), Some("speed"));
+
+        let answer = post("unknown", "01.mp3", Some(10), None).await;
+        assert_eq!(answer.kind, "audiobook_not_found");
+    }
+
+    #[cfg(feature = "ldap")]
+    #[rocket::async_test]
+    async fn acts_on_the_answer_of_ldap() {
+        let db = database::sqlite::SqliteStorage::new(pool().await);
+        let lifetimes = session::Lifetimes {
+            access: 3600,
+            refresh: 3600,
+        };
+        let accepted = || Ok(ldap::Authentication::Accepted);
+        let user = || String::from("hackers");
+
+        // the first accepted login creates the account, later ones reuse it
+        let key = ldap_answer(accepted(), user(), &db).await.unwrap();
+        assert_eq!(ldap_answer(accepted(), user(), &db).await, Some(key));
+        // a rejected or unreachable ldap leaves the login to local accounts
+        assert!(ldap_answer(Ok(ldap::Authentication::Rejected), user(), &db)
+            .await
+            .is_none());
+        let unreachable = ldap3::LdapError::from(std::io::Error::other("unreachable"));
+        assert!(ldap_answer(Err(unreachable), user(), &db).await.is_none());
+
+        // out of the group, the account is disabled until an administrator
+        // enables it again, and the local password does not open it either
+        assert!(
+            ldap_answer(Ok(ldap::Authentication::NotInGroup), user(), &db)
+                .await
+                .is_none()
+        );
+        assert!(ldap_answer(accepted(), user(), &db).await.is_none());
+        assert!(
+            get_account(user(), String::new(), false, &lifetimes, None, &db)
+                .await
+                .is_err()
+        );
+
+        // local accounts are never logged into nor disabled through ldap
+        let local = db
+            .insert_user(String::from("alice"), String::from("secret"))
+            .await
+            .unwrap();
+        assert!(ldap_answer(accepted(), String::from("alice"), &db)
+            .await
+            .is_none());
+        assert!(ldap_answer(
+            Ok(ldap::Authentication::NotInGroup),
+            String::from("alice"),
+            &db
+        )
+        .await
+        .is_none());
+        let login = get_account(
+            String::from("alice"),
+            String::from("secret"),
+            false,
+            &lifetimes,
+            None,
+            &db,
+        )
+        .await;
+        match login.ok().map(|login| login.into_inner()) {
+            Some(Login::Key(key)) => assert_eq!(key.key, local),
+            _ => panic!("alice could not login"),
+        }
+    }
 }
@@ -4,17 +4,35 @@ use rocket::request::{FromRequest, Outcome, Request};
 // use rocket::response::status;
 use rocket::response::Response;
 use rocket::serde::json::Json;
-use rocket::{catch, get, post, Build, Rocket, State};
-use rocket_db_pools::sqlx;
+use rocket::{catch, delete, get, post, put, Build, Rocket, State};
 use std::net::IpAddr;
+use std::path::PathBuf;
 
 use crate::api;
 use crate::api::controllers;
-use crate::database;
+use crate::database::storage::Storage;
+use crate::models::access;
 use crate::models::account;
 use crate::models::audiobook;
+use crate::models::author;
+use crate::models::collection;
+use crate::models::export;
+use crate::models::invite;
 use crate::models::position;
+use crate::models::series;
+use crate::models::session;
+use crate::utils::cli::RegisterMode;
 use crate::utils::error;
+use crate::utils::ldap;
+use crate::utils::limiter::RateLimiter;
+use crate::utils::validation;
+
+/// Header set by an authenticating reverse proxy, it is only trusted when the
+/// request comes from one of the addresses of the proxy
+pub struct ProxyAuth {
+    pub header: String,
+    pub proxies: Vec<IpAddr>,
+}
 
 struct AuthToken(String);
 
@@ -22,6 +40,32 @@ struct AuthToken(String);
 impl<'r> FromRequest<'r> for AuthToken {
     type Error = ();
     async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some(proxy_auth) = req.rocket().state::<ProxyAuth>() {
+            let trusted = match req.remote() {
+                Some(remote) => proxy_auth.proxies.contains(&remote.ip()),
+                None => false,
+            };
+            let user = req.headers().get_one(&proxy_auth.header);
+            if let (true, Some(user)) = (trusted, user) {
+                let (db, rules) = match (
+                    req.rocket().state::<Box<dyn Storage>>(),
+                    req.rocket().state::<validation::Rules>(),
+                ) {
+                    (Some(db), Some(rules)) => (db, rules),
+                    _ => {
+                        return Outcome::Failure((Status::InternalServerError, ()));
+                    }
+                };
+                // the proxy already authenticated the user, the rest of the
+                // routes only need their api key
+                return match controllers::get_proxy_key(user.to_string(), rules, db.as_ref()).await
+                {
+                    Some(key) => Outcome::Success(AuthToken(key)),
+                    None => Outcome::Failure((Status::Unauthorized, ())),
+                };
+            }
+        }
+
         if let Some(auth_header) = req.headers().get_one("Auth") {
             return Outcome::Success(AuthToken(auth_header.to_string()));
         }
@@ -29,6 +73,44 @@ impl<'r> FromRequest<'r> for AuthToken {
     }
 }
 
+/// Address of the client, behind a trusted proxy it is read from X-Forwarded-For
+struct ClientIp(IpAddr);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = ();
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let remote = match req.remote() {
+            Some(remote) => remote.ip(),
+            None => {
+                return Outcome::Failure((Status::BadRequest, ()));
+            }
+        };
+        let limiter = match req.rocket().state::<RateLimiter>() {
+            Some(limiter) => limiter,
+            None => {
+                return Outcome::Success(ClientIp(remote));
+            }
+        };
+        let forwarded = req.headers().get_one("X-Forwarded-For");
+        Outcome::Success(ClientIp(limiter.client_ip(remote, forwarded)))
+    }
+}
+
+/// Data directory of the library, used to rescan it at runtime
+struct DataDir(PathBuf);
+
+/// State managed by the server, besides the storage
+pub struct ServerState {
+    pub register: RegisterMode,
+    pub data: PathBuf,
+    pub limiter: RateLimiter,
+    pub rules: validation::Rules,
+    pub lifetimes: session::Lifetimes,
+    pub proxy_auth: Option<ProxyAuth>,
+    pub ldap: Option<ldap::LdapConfig>,
+}
+
 struct AuthHeader;
 
 #[rocket::async_trait]
@@ -47,92 +129,260 @@ impl Fairing for AuthHeader {
 
 pub fn create_rocket(
     port: u16,
-    register: bool,
     address: IpAddr,
-    pool: sqlx::Pool<sqlx::Sqlite>,
+    state: ServerState,
+    db: Box<dyn Storage>,
 ) -> Rocket<Build> {
     let config = rocket::Config {
-        address: address,
-        port: port,
+        address,
+        port,
         ..rocket::Config::debug_default()
     };
-    rocket::custom(&config)
+    let rocket = rocket::custom(&config)
         .attach(AuthHeader)
         .mount(
             "/",
-            if register {
+            if state.register != RegisterMode::Closed {
                 rocket::routes![
                     get_audiobooks_route,
+                    get_authors_route,
+                    get_author_audiobooks_route,
+                    get_series_route,
+                    get_series_audiobooks_route,
                     get_audiobook_route,
                     get_audiobook_position_route,
                     post_audiobook_position_route,
+                    get_collections_route,
+                    post_collection_route,
+                    get_collection_route,
+                    put_collection_route,
+                    delete_collection_route,
+                    put_collection_audiobook_route,
+                    delete_collection_audiobook_route,
+                    put_collection_share_route,
+                    delete_collection_share_route,
+                    post_account_password_route,
+                    post_account_key_route,
+                    post_account_name_route,
+                    delete_account_route,
+                    get_export_route,
+                    post_import_route,
                     login_route,
+                    refresh_route,
+                    logout_route,
                     register_route,
                 ]
             } else {
                 rocket::routes![
                     get_audiobooks_route,
+                    get_authors_route,
+                    get_author_audiobooks_route,
+                    get_series_route,
+                    get_series_audiobooks_route,
                     get_audiobook_route,
                     get_audiobook_position_route,
                     post_audiobook_position_route,
+                    get_collections_route,
+                    post_collection_route,
+                    get_collection_route,
+                    put_collection_route,
+                    delete_collection_route,
+                    put_collection_audiobook_route,
+                    delete_collection_audiobook_route,
+                    put_collection_share_route,
+                    delete_collection_share_route,
+                    post_account_password_route,
+                    post_account_key_route,
+                    post_account_name_route,
+                    delete_account_route,
+                    get_export_route,
+                    post_import_route,
                     login_route,
+                    refresh_route,
+                    logout_route,
                 ]
             },
         )
-        .register("/", rocket::catchers![not_found])
-        .manage(pool)
+        .mount(
+            "/admin",
+            rocket::routes![
+                get_admin_users_route,
+                post_admin_user_route,
+                delete_admin_user_route,
+                post_admin_user_disable_route,
+                post_admin_user_enable_route,
+                post_admin_user_password_route,
+                delete_admin_user_key_route,
+                post_admin_rescan_route,
+                get_admin_invites_route,
+                post_admin_invite_route,
+                delete_admin_invite_route,
+                get_admin_access_route,
+                post_admin_access_rule_route,
+                delete_admin_access_rule_route,
+                put_admin_group_member_route,
+                delete_admin_group_member_route,
+            ],
+        )
+        .register(
+            "/",
+            rocket::catchers![
+                bad_request,
+                unauthorized,
+                not_found,
+                unprocessable,
+                internal_error
+            ],
+        )
+        .manage(db)
+        .manage(DataDir(state.data))
+        .manage(state.register)
+        .manage(state.limiter)
+        .manage(state.rules)
+        .manage(state.lifetimes)
+        .manage(state.ldap);
+
+    match state.proxy_auth {
+        Some(proxy_auth) => rocket.manage(proxy_auth),
+        None => rocket,
+    }
+}
+
+#[catch(400)]
+fn bad_request() -> error::Answer {
+    error::bad_request()
+}
+
+#[catch(401)]
+fn unauthorized() -> error::Answer {
+    error::cant_auth()
 }
 
 #[catch(404)]
-fn not_found() -> Json<error::Answer> {
-    Json(error::not_found())
+fn not_found() -> error::Answer {
+    error::not_found()
+}
+
+/// Raised by rocket when a json body or a query does not match what a route
+/// expects
+#[catch(422)]
+fn unprocessable() -> error::Answer {
+    error::request_invalid()
+}
+
+#[catch(500)]
+fn internal_error() -> error::Answer {
+    error::internal_error()
 }
 
-#[get("/audiobooks")]
+#[get("/audiobooks?<query..>")]
 async fn get_audiobooks_route(
-    pool: &State<sqlx::Pool<sqlx::Sqlite>>,
+    query: audiobook::AudiobookQuery,
+    db: &State<Box<dyn Storage>>,
     auth_token: AuthToken,
-) -> Result<Json<audiobook::Audiobooks>, Json<error::Answer>> {
-    let user = database::schema::query_user(auth_token.0, pool).await;
-    match user {
+) -> Result<Json<audiobook::Audiobooks>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
         Ok(user) => user,
         Err(_) => {
-            return Err(Json(error::cant_auth()));
+            return Err(error::cant_auth());
         }
     };
-    controllers::get_audiobooks(pool).await
+    controllers::get_audiobooks(query, user, db.inner().as_ref()).await
+}
+
+#[get("/authors")]
+async fn get_authors_route(
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> Result<Json<author::Authors>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return Err(error::cant_auth());
+        }
+    };
+    controllers::get_authors(user, db.inner().as_ref()).await
+}
+
+#[get("/authors/<id>/audiobooks")]
+async fn get_author_audiobooks_route(
+    id: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> Result<Json<author::AuthorAudiobooks>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return Err(error::cant_auth());
+        }
+    };
+    controllers::get_author_audiobooks(id, user, db.inner().as_ref()).await
+}
+
+#[get("/series")]
+async fn get_series_route(
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> Result<Json<series::Series>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return Err(error::cant_auth());
+        }
+    };
+    controllers::get_series(user, db.inner().as_ref()).await
+}
+
+#[get("/series/<id>")]
+async fn get_series_audiobooks_route(
+    id: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> Result<Json<series::SeriesAudiobooks>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return Err(error::cant_auth());
+        }
+    };
+    controllers::get_series_audiobooks(id, user, db.inner().as_ref()).await
 }
 
 #[get("/audiobook/<hash>")]
 async fn get_audiobook_route(
     hash: String,
-    pool: &State<sqlx::Pool<sqlx::Sqlite>>,
+    db: &State<Box<dyn Storage>>,
     auth_token: AuthToken,
-) -> Result<Vec<u8>, Json<error::Answer>> {
-    let user = database::schema::query_user(auth_token.0, pool).await;
-    match user {
+) -> Result<Vec<u8>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
         Ok(user) => user,
         Err(_) => {
-            return Err(Json(error::cant_auth()));
+            return Err(error::cant_auth());
         }
     };
-    controllers::get_audiobook(hash, pool).await
+    controllers::get_audiobook(hash, user, db.inner().as_ref()).await
 }
 
 #[get("/audiobook/<hash>/position")]
 async fn get_audiobook_position_route(
     hash: String,
-    pool: &State<sqlx::Pool<sqlx::Sqlite>>,
+    db: &State<Box<dyn Storage>>,
     auth_token: AuthToken,
-) -> Result<Json<position::Position>, Json<error::Answer>> {
-    let user = database::schema::query_user(auth_token.0, pool).await;
+) -> Result<Json<position::Position>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
     let user = match user {
         Ok(user) => user,
         Err(_) => {
-            return Err(Json(error::cant_auth()));
+            return Err(error::cant_auth());
         }
     };
-    api::controllers::get_audiobook_position(hash, user, pool).await
+    api::controllers::get_audiobook_position(hash, user, db.inner().as_ref()).await
 }
 
 #[post(
@@ -142,45 +392,629 @@ async fn get_audiobook_position_route(
 )]
 async fn post_audiobook_position_route(
     hash: String,
-    pool: &State<sqlx::Pool<sqlx::Sqlite>>,
-    position: Json<position::Position>,
+    db: &State<Box<dyn Storage>>,
+    position: Json<position::NewPosition>,
     auth_token: AuthToken,
-) -> Json<error::Answer> {
-    let user = database::schema::query_user(auth_token.0, pool).await;
+) -> error::Answer {
+    let user = db.query_user(auth_token.0).await;
 
     let user = match user {
         Ok(user) => user,
         Err(_) => {
-            return Json(error::Answer {
-                code: 2,
-                msg: String::from("Error, could not authenticate"),
-            });
+            return error::cant_auth();
         }
     };
     println!("{}", user);
 
-    api::controllers::post_audiobook_position(
-        hash,
-        user,
-        position.file.clone(),
-        position.position,
-        pool,
-    )
-    .await
+    api::controllers::post_audiobook_position(hash, user, &position, db.inner().as_ref()).await
+}
+
+#[get("/collections")]
+async fn get_collections_route(
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> Result<Json<collection::Collections>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return Err(error::cant_auth());
+        }
+    };
+    controllers::get_collections(user, db.inner().as_ref()).await
+}
+
+#[post("/collections", format = "application/json", data = "<collection>")]
+async fn post_collection_route(
+    db: &State<Box<dyn Storage>>,
+    collection: Json<collection::NewCollection>,
+    auth_token: AuthToken,
+) -> Result<Json<collection::Collection>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return Err(error::cant_auth());
+        }
+    };
+    controllers::post_collection(user, collection.name.clone(), db.inner().as_ref()).await
+}
+
+#[get("/collections/<id>")]
+async fn get_collection_route(
+    id: i64,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> Result<Json<collection::Collection>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return Err(error::cant_auth());
+        }
+    };
+    controllers::get_collection(id, user, db.inner().as_ref()).await
+}
+
+#[put(
+    "/collections/<id>",
+    format = "application/json",
+    data = "<collection>"
+)]
+async fn put_collection_route(
+    id: i64,
+    db: &State<Box<dyn Storage>>,
+    collection: Json<collection::NewCollection>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return error::cant_auth();
+        }
+    };
+    controllers::put_collection(id, user, collection.name.clone(), db.inner().as_ref()).await
+}
+
+#[delete("/collections/<id>")]
+async fn delete_collection_route(
+    id: i64,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return error::cant_auth();
+        }
+    };
+    controllers::delete_collection(id, user, db.inner().as_ref()).await
+}
+
+#[put("/collections/<id>/audiobooks/<hash>")]
+async fn put_collection_audiobook_route(
+    id: i64,
+    hash: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return error::cant_auth();
+        }
+    };
+    controllers::put_collection_audiobook(id, hash, user, db.inner().as_ref()).await
+}
+
+#[delete("/collections/<id>/audiobooks/<hash>")]
+async fn delete_collection_audiobook_route(
+    id: i64,
+    hash: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return error::cant_auth();
+        }
+    };
+    controllers::delete_collection_audiobook(id, hash, user, db.inner().as_ref()).await
+}
+
+#[put("/collections/<id>/shares/<share>")]
+async fn put_collection_share_route(
+    id: i64,
+    share: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return error::cant_auth();
+        }
+    };
+    controllers::put_collection_share(id, share, user, db.inner().as_ref()).await
+}
+
+#[delete("/collections/<id>/shares/<share>")]
+async fn delete_collection_share_route(
+    id: i64,
+    share: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return error::cant_auth();
+        }
+    };
+    controllers::delete_collection_share(id, share, user, db.inner().as_ref()).await
+}
+
+#[post("/account/password", format = "application/json", data = "<change>")]
+async fn post_account_password_route(
+    db: &State<Box<dyn Storage>>,
+    rules: &State<validation::Rules>,
+    change: Json<account::PasswordChange>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return error::cant_auth();
+        }
+    };
+    controllers::put_own_password(user, &change, rules, db.inner().as_ref()).await
+}
+
+#[post("/account/key")]
+async fn post_account_key_route(
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> Result<Json<controllers::ApiKey>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return Err(error::cant_auth());
+        }
+    };
+    controllers::post_own_key(user, db.inner().as_ref()).await
+}
+
+#[post("/account/name", format = "application/json", data = "<rename>")]
+async fn post_account_name_route(
+    db: &State<Box<dyn Storage>>,
+    rules: &State<validation::Rules>,
+    rename: Json<account::Rename>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return error::cant_auth();
+        }
+    };
+    controllers::put_own_name(user, &rename, rules, db.inner().as_ref()).await
+}
+
+#[delete("/account", format = "application/json", data = "<password>")]
+async fn delete_account_route(
+    db: &State<Box<dyn Storage>>,
+    password: Json<account::NewPassword>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return error::cant_auth();
+        }
+    };
+    controllers::delete_own_account(user, password.password.clone(), db.inner().as_ref()).await
+}
+
+#[get("/me/export")]
+async fn get_export_route(
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> Result<Json<export::Export>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return Err(error::cant_auth());
+        }
+    };
+    controllers::get_export(user, db.inner().as_ref()).await
+}
+
+#[post("/me/import", format = "application/json", data = "<data>")]
+async fn post_import_route(
+    db: &State<Box<dyn Storage>>,
+    data: Json<export::Export>,
+    auth_token: AuthToken,
+) -> Result<Json<export::ImportReport>, error::Answer> {
+    let user = db.query_user(auth_token.0).await;
+    let user = match user {
+        Ok(user) => user,
+        Err(_) => {
+            return Err(error::cant_auth());
+        }
+    };
+    controllers::post_import(user, &data, db.inner().as_ref()).await
 }
 
 #[post("/register", format = "application/json", data = "<account>")]
 async fn register_route(
-    pool: &State<sqlx::Pool<sqlx::Sqlite>>,
+    db: &State<Box<dyn Storage>>,
+    register: &State<RegisterMode>,
+    rules: &State<validation::Rules>,
+    limiter: &State<RateLimiter>,
+    client_ip: ClientIp,
     account: Json<account::NewAccount>,
-) -> Result<Json<controllers::ApiKey>, Json<error::Answer>> {
-    api::controllers::post_account(account.user.clone(), account.password.clone(), pool).await
+) -> Result<Json<controllers::ApiKey>, error::Answer> {
+    if !limiter.check(client_ip.0, None) {
+        return Err(error::too_many_attempts());
+    }
+
+    let key = if **register == RegisterMode::Invite {
+        api::controllers::post_invited_account(
+            account.user.clone(),
+            account.password.clone(),
+            account.invite.clone(),
+            rules,
+            db.inner().as_ref(),
+        )
+        .await
+    } else {
+        api::controllers::post_account(
+            account.user.clone(),
+            account.password.clone(),
+            rules,
+            db.inner().as_ref(),
+        )
+        .await
+    };
+
+    match key {
+        Ok(_) => limiter.record_attempt(client_ip.0),
+        Err(_) => limiter.record_failure(client_ip.0, None),
+    };
+    key
 }
 
 #[post("/login", format = "application/json", data = "<account>")]
 async fn login_route(
-    pool: &State<sqlx::Pool<sqlx::Sqlite>>,
+    db: &State<Box<dyn Storage>>,
+    limiter: &State<RateLimiter>,
+    lifetimes: &State<session::Lifetimes>,
+    ldap: &State<Option<ldap::LdapConfig>>,
+    client_ip: ClientIp,
     account: Json<account::NewAccount>,
-) -> Result<Json<controllers::ApiKey>, Json<error::Answer>> {
-    api::controllers::get_account(account.user.clone(), account.password.clone(), pool).await
+) -> Result<Json<controllers::Login>, error::Answer> {
+    if !limiter.check(client_ip.0, Some(&account.user)) {
+        return Err(error::too_many_attempts());
+    }
+
+    let key = api::controllers::get_account(
+        account.user.clone(),
+        account.password.clone(),
+        account.session,
+        lifetimes,
+        ldap.inner().as_ref(),
+        db.inner().as_ref(),
+    )
+    .await;
+
+    match key {
+        Ok(_) => limiter.record_success(&account.user),
+        Err(_) => limiter.record_failure(client_ip.0, Some(&account.user)),
+    };
+    key
+}
+
+#[post("/refresh", format = "application/json", data = "<refresh>")]
+async fn refresh_route(
+    db: &State<Box<dyn Storage>>,
+    lifetimes: &State<session::Lifetimes>,
+    refresh: Json<session::Refresh>,
+) -> Result<Json<session::Session>, error::Answer> {
+    api::controllers::post_refresh(
+        refresh.refresh_token.clone(),
+        lifetimes,
+        db.inner().as_ref(),
+    )
+    .await
+}
+
+/// Closes a session by its refresh token when one is given in the body, which
+/// still works once the access token expired, or by the `Auth` header
+#[post("/logout", data = "<refresh>")]
+async fn logout_route(
+    db: &State<Box<dyn Storage>>,
+    auth_token: Option<AuthToken>,
+    refresh: Option<Json<session::Refresh>>,
+) -> error::Answer {
+    let token = match (refresh, auth_token) {
+        (Some(refresh), _) => refresh.into_inner().refresh_token,
+        (None, Some(auth_token)) => auth_token.0,
+        (None, None) => return error::session_invalid(),
+    };
+    api::controllers::post_logout(token, db.inner().as_ref()).await
+}
+
+#[get("/users")]
+async fn get_admin_users_route(
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> Result<Json<account::Accounts>, error::Answer> {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return Err(error::not_admin());
+        }
+    };
+    controllers::get_accounts(db.inner().as_ref()).await
+}
+
+#[post("/users", format = "application/json", data = "<account>")]
+async fn post_admin_user_route(
+    db: &State<Box<dyn Storage>>,
+    rules: &State<validation::Rules>,
+    account: Json<account::AdminNewAccount>,
+    auth_token: AuthToken,
+) -> Result<Json<controllers::ApiKey>, error::Answer> {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return Err(error::not_admin());
+        }
+    };
+    controllers::post_admin_account(&account, rules, db.inner().as_ref()).await
+}
+
+#[delete("/users/<user>")]
+async fn delete_admin_user_route(
+    user: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let admin = db.query_admin(auth_token.0).await;
+    let admin = match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return error::not_admin();
+        }
+    };
+    controllers::delete_account(user, admin, db.inner().as_ref()).await
+}
+
+#[post("/users/<user>/disable")]
+async fn post_admin_user_disable_route(
+    user: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let admin = db.query_admin(auth_token.0).await;
+    let admin = match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return error::not_admin();
+        }
+    };
+    controllers::put_account_disabled(user, admin, true, db.inner().as_ref()).await
+}
+
+#[post("/users/<user>/enable")]
+async fn post_admin_user_enable_route(
+    user: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let admin = db.query_admin(auth_token.0).await;
+    let admin = match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return error::not_admin();
+        }
+    };
+    controllers::put_account_disabled(user, admin, false, db.inner().as_ref()).await
+}
+
+#[post(
+    "/users/<user>/password",
+    format = "application/json",
+    data = "<password>"
+)]
+async fn post_admin_user_password_route(
+    user: String,
+    db: &State<Box<dyn Storage>>,
+    rules: &State<validation::Rules>,
+    password: Json<account::NewPassword>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return error::not_admin();
+        }
+    };
+    controllers::put_account_password(user, password.password.clone(), rules, db.inner().as_ref())
+        .await
+}
+
+#[delete("/users/<user>/key")]
+async fn delete_admin_user_key_route(
+    user: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return error::not_admin();
+        }
+    };
+    controllers::delete_account_key(user, db.inner().as_ref()).await
+}
+
+#[post("/rescan")]
+async fn post_admin_rescan_route(
+    db: &State<Box<dyn Storage>>,
+    data: &State<DataDir>,
+    auth_token: AuthToken,
+) -> Result<Json<audiobook::ScanReport>, error::Answer> {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return Err(error::not_admin());
+        }
+    };
+    controllers::post_rescan(&data.0, db.inner().as_ref()).await
+}
+
+#[get("/invites")]
+async fn get_admin_invites_route(
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> Result<Json<invite::Invites>, error::Answer> {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return Err(error::not_admin());
+        }
+    };
+    controllers::get_invites(db.inner().as_ref()).await
+}
+
+#[post("/invites", format = "application/json", data = "<invite>")]
+async fn post_admin_invite_route(
+    db: &State<Box<dyn Storage>>,
+    invite: Json<invite::NewInvite>,
+    auth_token: AuthToken,
+) -> Result<Json<invite::Invite>, error::Answer> {
+    let admin = db.query_admin(auth_token.0).await;
+    let admin = match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return Err(error::not_admin());
+        }
+    };
+    controllers::post_invite(&invite, admin, db.inner().as_ref()).await
+}
+
+#[delete("/invites/<code>")]
+async fn delete_admin_invite_route(
+    code: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return error::not_admin();
+        }
+    };
+    controllers::delete_invite(code, db.inner().as_ref()).await
+}
+
+#[get("/access")]
+async fn get_admin_access_route(
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> Result<Json<access::Access>, error::Answer> {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return Err(error::not_admin());
+        }
+    };
+    controllers::get_access(db.inner().as_ref()).await
+}
+
+#[post("/access/rules", format = "application/json", data = "<rule>")]
+async fn post_admin_access_rule_route(
+    db: &State<Box<dyn Storage>>,
+    rule: Json<access::NewAccessRule>,
+    auth_token: AuthToken,
+) -> Result<Json<access::AccessRule>, error::Answer> {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return Err(error::not_admin());
+        }
+    };
+    controllers::post_access_rule(&rule, db.inner().as_ref()).await
+}
+
+#[delete("/access/rules/<id>")]
+async fn delete_admin_access_rule_route(
+    id: i64,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return error::not_admin();
+        }
+    };
+    controllers::delete_access_rule(id, db.inner().as_ref()).await
+}
+
+#[put("/groups/<group>/members/<user>")]
+async fn put_admin_group_member_route(
+    group: String,
+    user: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return error::not_admin();
+        }
+    };
+    controllers::put_group_member(group, user, db.inner().as_ref()).await
+}
+
+#[delete("/groups/<group>/members/<user>")]
+async fn delete_admin_group_member_route(
+    group: String,
+    user: String,
+    db: &State<Box<dyn Storage>>,
+    auth_token: AuthToken,
+) -> error::Answer {
+    let admin = db.query_admin(auth_token.0).await;
+    match admin {
+        Ok(admin) => admin,
+        Err(_) => {
+            return error::not_admin();
+        }
+    };
+    controllers::delete_group_member(group, user, db.inner().as_ref()).await
 }
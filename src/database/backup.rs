@@ -0,0 +1,375 @@
+use libsqlite3_sys as ffi;
+use rocket::tokio;
+use rocket_db_pools::sqlx;
+use rocket_db_pools::sqlx::{ConnectOptions, Connection};
+use std::ffi::{CStr, CString, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::Duration;
+
+use crate::database::migrations;
+
+#[derive(sqlx::FromRow, Debug)]
+struct IntegrityRow {
+    integrity_check: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct TableRow {
+    name: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct VersionRow {
+    version: i64,
+}
+
+/// Settings of the backups taken while the server runs
+pub struct BackupSettings {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    /// Number of backups kept in `dir`, the oldest ones are removed
+    pub keep: usize,
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    Database(sqlx::Error),
+    /// Error of the online backup api of sqlite
+    Sqlite(String),
+    Io(io::Error),
+    /// The file given is not something illiad can restore
+    Invalid(String),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::Database(err) => write!(f, "{}", err),
+            BackupError::Sqlite(err) => write!(f, "{}", err),
+            BackupError::Io(err) => write!(f, "{}", err),
+            BackupError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<sqlx::Error> for BackupError {
+    fn from(err: sqlx::Error) -> Self {
+        BackupError::Database(err)
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(err: io::Error) -> Self {
+        BackupError::Io(err)
+    }
+}
+
+/// Path next to `path` with `suffix` appended to its file name
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Connection opened with sqlite directly, sqlx has no way to open the file a
+/// backup is written to
+struct RawDatabase(*mut ffi::sqlite3);
+
+impl RawDatabase {
+    fn open(path: &Path, flags: c_int) -> Result<Self, BackupError> {
+        let name = match CString::new(path.to_string_lossy().as_bytes()) {
+            Ok(name) => name,
+            Err(_) => {
+                return Err(BackupError::Invalid(format!(
+                    "'{}' is not a valid path",
+                    path.display()
+                )));
+            }
+        };
+
+        let mut db = ptr::null_mut();
+        let code = unsafe { ffi::sqlite3_open_v2(name.as_ptr(), &mut db, flags, ptr::null()) };
+        // sqlite gives a connection back even when opening fails, it still
+        // has to be closed
+        let db = RawDatabase(db);
+        if code != ffi::SQLITE_OK {
+            return Err(sqlite_error(code));
+        }
+        Ok(db)
+    }
+
+    fn execute(&self, sql: &str) -> Result<(), BackupError> {
+        let sql = CString::new(sql).unwrap();
+        let code = unsafe {
+            ffi::sqlite3_exec(self.0, sql.as_ptr(), None, ptr::null_mut(), ptr::null_mut())
+        };
+        if code != ffi::SQLITE_OK {
+            return Err(sqlite_error(code));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RawDatabase {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_close(self.0);
+        }
+    }
+}
+
+fn sqlite_error(code: c_int) -> BackupError {
+    let msg = unsafe { CStr::from_ptr(ffi::sqlite3_errstr(code)) };
+    BackupError::Sqlite(msg.to_string_lossy().into_owned())
+}
+
+/// Copies the database of `src` over the one of `dest` with the online backup
+/// api of sqlite. The copy is done in a single step, so it is one consistent
+/// snapshot even while other connections write to `src`.
+fn copy_database(dest: *mut ffi::sqlite3, src: *mut ffi::sqlite3) -> Result<(), BackupError> {
+    let main = c"main".as_ptr();
+
+    unsafe {
+        let backup = ffi::sqlite3_backup_init(dest, main, src, main);
+        if backup.is_null() {
+            return Err(sqlite_error(ffi::sqlite3_extended_errcode(dest)));
+        }
+
+        let step = ffi::sqlite3_backup_step(backup, -1);
+        let finish = ffi::sqlite3_backup_finish(backup);
+        if step != ffi::SQLITE_DONE {
+            return Err(sqlite_error(step));
+        }
+        if finish != ffi::SQLITE_OK {
+            return Err(sqlite_error(finish));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a consistent copy of the database to `path` while it stays in use,
+/// with the online backup api of sqlite on one of the connections of the pool
+pub async fn backup(path: &Path, pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), BackupError> {
+    if path.exists() {
+        return Err(BackupError::Invalid(format!(
+            "'{}' already exists",
+            path.display()
+        )));
+    }
+
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    let mut conn = pool.acquire().await?;
+    let copied = {
+        // keeps the worker of sqlx off the connection while sqlite copies it
+        let mut handle = conn.lock_handle().await?;
+        RawDatabase::open(path, ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE).and_then(
+            |dest| {
+                copy_database(dest.0, handle.as_raw_handle().as_ptr())?;
+                // the copy inherits the write ahead log of the database, a
+                // backup is a single file
+                dest.execute(r#"PRAGMA journal_mode = DELETE"#)
+            },
+        )
+    };
+
+    if let Err(err) = copied {
+        let _ = fs::remove_file(path);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Checks that `path` is a sound illiad database this version can open
+async fn check_backup(path: &Path) -> Result<(), BackupError> {
+    if !path.is_file() {
+        return Err(BackupError::Invalid(format!(
+            "'{}' does not exist",
+            path.display()
+        )));
+    }
+
+    let options = sqlx::sqlite::SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true);
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await?;
+
+    let integrity = sqlx::query_as::<_, IntegrityRow>(r#"PRAGMA integrity_check"#)
+        .fetch_all(&pool)
+        .await
+        .map_err(|_| {
+            BackupError::Invalid(format!("'{}' is not a sqlite database", path.display()))
+        })?;
+    if integrity.len() != 1 || integrity[0].integrity_check != "ok" {
+        return Err(BackupError::Invalid(format!(
+            "'{}' is corrupted",
+            path.display()
+        )));
+    }
+
+    let tables = sqlx::query_as::<_, TableRow>(
+        r#"SELECT name FROM sqlite_master
+        WHERE type = 'table' AND name IN ('accounts', 'schema_version')"#,
+    )
+    .fetch_all(&pool)
+    .await?;
+    if !tables.iter().any(|table| table.name == "accounts") {
+        return Err(BackupError::Invalid(format!(
+            "'{}' is not an illiad database",
+            path.display()
+        )));
+    }
+
+    // backups older than the migrations are brought up to date on the next
+    // start, newer ones cannot be read by this version
+    if tables.iter().any(|table| table.name == "schema_version") {
+        let version = sqlx::query_as::<_, VersionRow>(
+            r#"SELECT COALESCE(MAX(version), 0) AS version FROM schema_version"#,
+        )
+        .fetch_one(&pool)
+        .await?
+        .version;
+        let latest = migrations::latest_version();
+        if version > latest {
+            return Err(BackupError::Invalid(format!(
+                "'{}' is at schema version {} but this illiad only knows up to {}",
+                path.display(),
+                version,
+                latest
+            )));
+        }
+    }
+
+    pool.close().await;
+    Ok(())
+}
+
+/// Replaces the database at `target` by the backup at `path`, the server must
+/// be stopped. The backup is checked before anything is touched, then copied
+/// over the database in a single transaction, so a failed restore leaves the
+/// database as it was.
+pub async fn restore(path: &Path, target: &Path) -> Result<(), BackupError> {
+    check_backup(path).await?;
+
+    if !target.exists() {
+        if let Some(parent) = target.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let tmp = sibling(target, ".restore");
+        fs::copy(path, &tmp)?;
+        return match fs::rename(&tmp, target) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                let _ = fs::remove_file(&tmp);
+                Err(BackupError::Io(err))
+            }
+        };
+    }
+
+    // in exclusive locking mode the lock taken by the first transaction is
+    // kept until the connection closes, and it can not be taken at all while
+    // a server has the database open
+    let mut conn = sqlx::sqlite::SqliteConnectOptions::new()
+        .filename(target)
+        .locking_mode(sqlx::sqlite::SqliteLockingMode::Exclusive)
+        .busy_timeout(Duration::from_secs(0))
+        .connect()
+        .await?;
+    let copied = copy_locked(path, target, &mut conn).await;
+    // a connection left open would keep the lock, even after a failure. It
+    // is closed by hand, a pool may still be handing it back when closed.
+    let closed = conn.close().await;
+    copied?;
+    closed?;
+    Ok(())
+}
+
+/// Locks the database of `conn` for itself then copies the backup at `path`
+/// over it
+async fn copy_locked(
+    path: &Path,
+    target: &Path,
+    conn: &mut sqlx::SqliteConnection,
+) -> Result<(), BackupError> {
+    if let Err(err) = sqlx::query(r#"BEGIN EXCLUSIVE"#).execute(&mut *conn).await {
+        return Err(BackupError::Invalid(format!(
+            "'{}' is in use, stop the server first ({})",
+            target.display(),
+            err
+        )));
+    }
+    sqlx::query(r#"COMMIT"#).execute(&mut *conn).await?;
+
+    // the write ahead log of the database, if any, is kept consistent by
+    // sqlite rather than removed from under it
+    let mut handle = conn.lock_handle().await?;
+    RawDatabase::open(path, ffi::SQLITE_OPEN_READONLY)
+        .and_then(|src| copy_database(handle.as_raw_handle().as_ptr(), src.0))
+}
+
+fn is_scheduled_backup(path: &Path) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name.starts_with("illiad-") && name.ends_with(".sqlite"),
+        None => false,
+    }
+}
+
+/// Removes the oldest scheduled backups of `dir` until `keep` are left, their
+/// names sort by date
+fn prune(dir: &Path, keep: usize) -> io::Result<()> {
+    let mut backups: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| is_scheduled_backup(path))
+        .collect();
+    backups.sort();
+
+    let excess = backups.len().saturating_sub(keep);
+    for backup in backups.iter().take(excess) {
+        fs::remove_file(backup)?;
+    }
+    Ok(())
+}
+
+/// Backs the database up on start then every `interval`, failures are logged
+/// and retried on the next round
+pub fn schedule(settings: BackupSettings, pool: sqlx::Pool<sqlx::Sqlite>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(settings.interval);
+        loop {
+            interval.tick().await;
+
+            let name = format!(
+                "illiad-{}.sqlite",
+                chrono::Utc::now().format("%Y%m%d-%H%M%S")
+            );
+            match backup(&settings.dir.join(name), &pool).await {
+                Ok(_) => {}
+                Err(err) => {
+                    eprintln!("Could not back up the database: {}", err);
+                    continue;
+                }
+            };
+
+            if let Err(err) = prune(&settings.dir, settings.keep) {
+                eprintln!("Could not remove old backups: {}", err);
+            }
+        }
+    });
+}
@@ -0,0 +1,594 @@
+use rocket_db_pools::sqlx;
+use std::fmt;
+
+/// A change to the schema, applied once, in order, and recorded in the
+/// `schema_version` table
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+/// Every migration illiad knows about, new ones are appended with the next
+/// version and never edited once released
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS accounts (
+        user TEXT PRIMARY KEY,
+        password TEXT,
+        key TEXT,
+        is_admin INTEGER NOT NULL DEFAULT 0,
+        disabled INTEGER NOT NULL DEFAULT 0)"#,
+            r#"CREATE TABLE IF NOT EXISTS sessions (
+        access_token TEXT PRIMARY KEY,
+        refresh_token TEXT UNIQUE,
+        user TEXT,
+        access_expires INTEGER,
+        refresh_expires INTEGER)"#,
+            r#"CREATE TABLE IF NOT EXISTS invites (
+        code TEXT PRIMARY KEY,
+        uses INTEGER,
+        expires INTEGER,
+        created_by TEXT)"#,
+            r#"CREATE TABLE IF NOT EXISTS access_rules (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        subject_kind TEXT,
+        subject TEXT,
+        kind TEXT,
+        value TEXT)"#,
+            r#"CREATE TABLE IF NOT EXISTS group_members (
+        group_name TEXT,
+        user TEXT,
+        PRIMARY KEY (group_name, user))"#,
+            r#"CREATE TABLE IF NOT EXISTS audiobooks (
+        hash TEXT PRIMARY KEY,
+        title TEXT,
+        author TEXT,
+        path TEXT,
+        narrator TEXT,
+        description TEXT,
+        series TEXT,
+        series_index REAL,
+        genre TEXT,
+        language TEXT,
+        directory TEXT)"#,
+            r#"CREATE VIRTUAL TABLE IF NOT EXISTS audiobooks_fts USING fts5 (
+        hash UNINDEXED,
+        title,
+        author,
+        narrator,
+        description)"#,
+            r#"CREATE TABLE IF NOT EXISTS audiobook_tags (
+        hash TEXT,
+        tag TEXT,
+        PRIMARY KEY (hash, tag))"#,
+            r#"CREATE TABLE IF NOT EXISTS audiobook_files (
+        hash TEXT,
+        file TEXT,
+        duration REAL,
+        PRIMARY KEY (hash, file))"#,
+            r#"CREATE TABLE IF NOT EXISTS positions (
+        hash TEXT,
+        user TEXT,
+        file TEXT,
+        position NUMBER,
+        last_modified DATE,
+        position_ms INTEGER,
+        speed REAL,
+        chapter INTEGER)"#,
+            r#"CREATE TABLE IF NOT EXISTS collections (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user TEXT,
+        name TEXT)"#,
+            r#"CREATE TABLE IF NOT EXISTS collection_audiobooks (
+        collection INTEGER,
+        hash TEXT,
+        PRIMARY KEY (collection, hash))"#,
+            r#"CREATE TABLE IF NOT EXISTS collection_shares (
+        collection INTEGER,
+        user TEXT,
+        PRIMARY KEY (collection, user))"#,
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "constraints, foreign keys and indexes",
+        // sqlite can not add constraints to a table, so tables are rebuilt,
+        // parents before children, leaving out duplicates and orphaned rows
+        statements: &[
+            r#"CREATE TABLE positions_new (
+        hash TEXT NOT NULL REFERENCES audiobooks (hash) ON DELETE CASCADE,
+        user TEXT NOT NULL REFERENCES accounts (user) ON UPDATE CASCADE ON DELETE CASCADE,
+        file TEXT NOT NULL,
+        position NUMBER,
+        last_modified DATE,
+        position_ms INTEGER,
+        speed REAL,
+        chapter INTEGER,
+        PRIMARY KEY (hash, user))"#,
+            // the last row written for a book and user is the one that was read
+            r#"INSERT INTO positions_new
+        SELECT hash, user, file, position, last_modified, position_ms, speed, chapter
+        FROM positions
+        WHERE rowid IN (SELECT MAX(rowid) FROM positions GROUP BY hash, user)
+        AND hash IN (SELECT hash FROM audiobooks)
+        AND user IN (SELECT user FROM accounts)
+        AND file IS NOT NULL"#,
+            r#"DROP TABLE positions"#,
+            r#"ALTER TABLE positions_new RENAME TO positions"#,
+            r#"CREATE TABLE sessions_new (
+        access_token TEXT PRIMARY KEY,
+        refresh_token TEXT NOT NULL UNIQUE,
+        user TEXT NOT NULL REFERENCES accounts (user) ON UPDATE CASCADE ON DELETE CASCADE,
+        access_expires INTEGER NOT NULL,
+        refresh_expires INTEGER NOT NULL)"#,
+            r#"INSERT INTO sessions_new
+        SELECT access_token, refresh_token, user, access_expires, refresh_expires
+        FROM sessions
+        WHERE user IN (SELECT user FROM accounts)"#,
+            r#"DROP TABLE sessions"#,
+            r#"ALTER TABLE sessions_new RENAME TO sessions"#,
+            r#"CREATE TABLE collections_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user TEXT NOT NULL REFERENCES accounts (user) ON UPDATE CASCADE ON DELETE CASCADE,
+        name TEXT NOT NULL)"#,
+            r#"INSERT INTO collections_new
+        SELECT id, user, name FROM collections
+        WHERE user IN (SELECT user FROM accounts)"#,
+            r#"DROP TABLE collections"#,
+            r#"ALTER TABLE collections_new RENAME TO collections"#,
+            r#"CREATE TABLE collection_audiobooks_new (
+        collection INTEGER NOT NULL REFERENCES collections (id) ON DELETE CASCADE,
+        hash TEXT NOT NULL REFERENCES audiobooks (hash) ON DELETE CASCADE,
+        PRIMARY KEY (collection, hash))"#,
+            r#"INSERT INTO collection_audiobooks_new
+        SELECT collection, hash FROM collection_audiobooks
+        WHERE collection IN (SELECT id FROM collections)
+        AND hash IN (SELECT hash FROM audiobooks)"#,
+            r#"DROP TABLE collection_audiobooks"#,
+            r#"ALTER TABLE collection_audiobooks_new RENAME TO collection_audiobooks"#,
+            r#"CREATE TABLE collection_shares_new (
+        collection INTEGER NOT NULL REFERENCES collections (id) ON DELETE CASCADE,
+        user TEXT NOT NULL REFERENCES accounts (user) ON UPDATE CASCADE ON DELETE CASCADE,
+        PRIMARY KEY (collection, user))"#,
+            r#"INSERT INTO collection_shares_new
+        SELECT collection, user FROM collection_shares
+        WHERE collection IN (SELECT id FROM collections)
+        AND user IN (SELECT user FROM accounts)"#,
+            r#"DROP TABLE collection_shares"#,
+            r#"ALTER TABLE collection_shares_new RENAME TO collection_shares"#,
+            r#"CREATE TABLE group_members_new (
+        group_name TEXT NOT NULL,
+        user TEXT NOT NULL REFERENCES accounts (user) ON UPDATE CASCADE ON DELETE CASCADE,
+        PRIMARY KEY (group_name, user))"#,
+            r#"INSERT INTO group_members_new
+        SELECT group_name, user FROM group_members
+        WHERE user IN (SELECT user FROM accounts)"#,
+            r#"DROP TABLE group_members"#,
+            r#"ALTER TABLE group_members_new RENAME TO group_members"#,
+            r#"CREATE TABLE audiobook_tags_new (
+        hash TEXT NOT NULL REFERENCES audiobooks (hash) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (hash, tag))"#,
+            r#"INSERT INTO audiobook_tags_new
+        SELECT hash, tag FROM audiobook_tags
+        WHERE hash IN (SELECT hash FROM audiobooks) AND tag IS NOT NULL"#,
+            r#"DROP TABLE audiobook_tags"#,
+            r#"ALTER TABLE audiobook_tags_new RENAME TO audiobook_tags"#,
+            r#"CREATE TABLE audiobook_files_new (
+        hash TEXT NOT NULL REFERENCES audiobooks (hash) ON DELETE CASCADE,
+        file TEXT NOT NULL,
+        duration REAL,
+        PRIMARY KEY (hash, file))"#,
+            r#"INSERT INTO audiobook_files_new
+        SELECT hash, file, duration FROM audiobook_files
+        WHERE hash IN (SELECT hash FROM audiobooks) AND file IS NOT NULL"#,
+            r#"DROP TABLE audiobook_files"#,
+            r#"ALTER TABLE audiobook_files_new RENAME TO audiobook_files"#,
+            r#"CREATE UNIQUE INDEX accounts_key ON accounts (key)"#,
+            r#"CREATE INDEX positions_user ON positions (user)"#,
+            r#"CREATE INDEX sessions_user ON sessions (user)"#,
+            r#"CREATE INDEX collections_user ON collections (user)"#,
+            r#"CREATE INDEX collection_shares_user ON collection_shares (user)"#,
+            r#"CREATE INDEX group_members_user ON group_members (user)"#,
+            r#"CREATE INDEX audiobook_tags_tag ON audiobook_tags (tag)"#,
+            r#"CREATE INDEX audiobooks_author ON audiobooks (author COLLATE NOCASE)"#,
+            r#"CREATE INDEX audiobooks_series ON audiobooks (series COLLATE NOCASE)"#,
+            r#"CREATE INDEX access_rules_subject ON access_rules (subject_kind, subject)"#,
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "origin of accounts",
+        // accounts created before were all treated alike, they stay local
+        statements: &[
+            r#"ALTER TABLE accounts ADD COLUMN auth_source TEXT NOT NULL DEFAULT 'local'"#,
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "usernames unique in any case",
+        // fails on databases where two names only differ by their case, one
+        // of them has to be renamed first
+        statements: &[
+            r#"CREATE UNIQUE INDEX accounts_user_nocase ON accounts (user COLLATE NOCASE)"#,
+        ],
+    },
+];
+
+/// Columns added to tables before migrations existed, a database created by
+/// one of those versions gets them before the initial schema is recorded
+const LEGACY_COLUMNS: &[(&str, &[(&str, &str)])] = &[
+    (
+        "accounts",
+        &[
+            ("is_admin", "INTEGER NOT NULL DEFAULT 0"),
+            ("disabled", "INTEGER NOT NULL DEFAULT 0"),
+        ],
+    ),
+    (
+        "audiobooks",
+        &[
+            ("narrator", "TEXT"),
+            ("description", "TEXT"),
+            ("series", "TEXT"),
+            ("series_index", "REAL"),
+            ("genre", "TEXT"),
+            ("language", "TEXT"),
+            ("directory", "TEXT"),
+        ],
+    ),
+    (
+        "positions",
+        &[
+            ("position_ms", "INTEGER"),
+            ("speed", "REAL"),
+            ("chapter", "INTEGER"),
+        ],
+    ),
+];
+
+#[derive(sqlx::FromRow, Debug)]
+struct VersionRow {
+    version: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct ColumnRow {
+    name: String,
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Database(sqlx::Error),
+    /// The database was migrated by a newer version of illiad
+    TooNew {
+        found: i64,
+        known: i64,
+    },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Database(err) => write!(f, "{}", err),
+            MigrationError::TooNew { found, known } => write!(
+                f,
+                "the database is at schema version {} but this illiad only knows up to {}, \
+                 please upgrade illiad",
+                found, known
+            ),
+        }
+    }
+}
+
+impl From<sqlx::Error> for MigrationError {
+    fn from(err: sqlx::Error) -> Self {
+        MigrationError::Database(err)
+    }
+}
+
+/// Version of the schema this build of illiad expects
+pub fn latest_version() -> i64 {
+    MIGRATIONS
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Version the database is at, 0 before its first migration
+pub async fn current_version(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<i64, sqlx::Error> {
+    let table = sqlx::query_as::<_, ColumnRow>(
+        r#"SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'"#,
+    )
+    .fetch_optional(pool)
+    .await?;
+    if table.is_none() {
+        return Ok(0);
+    }
+
+    let row = sqlx::query_as::<_, VersionRow>(
+        r#"SELECT COALESCE(MAX(version), 0) AS version FROM schema_version"#,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(row.version)
+}
+
+/// Brings the database up to the latest schema, in a single transaction so a
+/// failing migration leaves it untouched. Returns the number of migrations
+/// applied.
+pub async fn migrate(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<usize, MigrationError> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS schema_version (
+        version INTEGER PRIMARY KEY,
+        description TEXT,
+        applied_at INTEGER)"#,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    let current = sqlx::query_as::<_, VersionRow>(
+        r#"SELECT COALESCE(MAX(version), 0) AS version FROM schema_version"#,
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .version;
+
+    let latest = latest_version();
+    if current > latest {
+        return Err(MigrationError::TooNew {
+            found: current,
+            known: latest,
+        });
+    }
+
+    if current == 0 {
+        for (table, columns) in LEGACY_COLUMNS.iter() {
+            add_missing_columns(table, columns, &mut tx).await?;
+        }
+    }
+
+    let mut applied = 0;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        for statement in migration.statements.iter() {
+            sqlx::query(statement).execute(&mut tx).await?;
+        }
+
+        sqlx::query(
+            r#"INSERT INTO schema_version (version, description, applied_at)
+            VALUES (?, ?, ?)"#,
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&mut tx)
+        .await?;
+
+        applied += 1;
+    }
+
+    tx.commit().await?;
+
+    Ok(applied)
+}
+
+/// Adds the columns an existing table created by an older version of illiad
+/// is missing, tables that do not exist yet are left to the migrations
+async fn add_missing_columns(
+    table: &str,
+    columns: &[(&str, &str)],
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    let existing = sqlx::query_as::<_, ColumnRow>(&format!(
+        "SELECT name FROM pragma_table_info('{}')",
+        table
+    ))
+    .fetch_all(&mut *tx)
+    .await?;
+    if existing.is_empty() {
+        return Ok(());
+    }
+
+    for (name, kind) in columns.iter() {
+        if existing.iter().any(|column| column.name == *name) {
+            continue;
+        }
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, name, kind))
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::schema;
+
+    async fn pool(name: &str) -> sqlx::Pool<sqlx::Sqlite> {
+        let path = std::env::temp_dir().join(format!(
+            "illiad-migrations-{}-{}.sqlite",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_file(&path);
+        schema::create_pool(
+            path,
+            schema::PoolSettings {
+                max_connections: 5,
+                busy_timeout: std::time::Duration::from_secs(5),
+            },
+        )
+        .await
+    }
+
+    async fn columns(table: &str, pool: &sqlx::Pool<sqlx::Sqlite>) -> Vec<String> {
+        sqlx::query_as::<_, ColumnRow>(&format!("SELECT name FROM pragma_table_info('{}')", table))
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|column| column.name)
+            .collect()
+    }
+
+    #[rocket::async_test]
+    async fn migrates_a_database_from_before_migrations() {
+        let pool = pool("legacy").await;
+        let legacy = [
+            r#"CREATE TABLE accounts (user TEXT PRIMARY KEY, password TEXT, key TEXT)"#,
+            r#"CREATE TABLE audiobooks (hash TEXT PRIMARY KEY, title TEXT, author TEXT, path TEXT)"#,
+            r#"CREATE TABLE positions (hash TEXT, user TEXT, file TEXT, position NUMBER, last_modified DATE)"#,
+            r#"INSERT INTO accounts VALUES ('alice', 'secret', 'key')"#,
+            r#"INSERT INTO audiobooks VALUES ('hash', 'Dune', 'Frank Herbert', '/data/Dune')"#,
+            r#"INSERT INTO positions VALUES ('hash', 'alice', '01.mp3', 10, NULL)"#,
+            r#"INSERT INTO positions VALUES ('hash', 'alice', '01.mp3', 42, NULL)"#,
+            r#"INSERT INTO positions VALUES ('hash', 'bob', '01.mp3', 42, NULL)"#,
+            r#"INSERT INTO positions VALUES ('gone', 'alice', '01.mp3', 42, NULL)"#,
+        ];
+        for statement in legacy.iter() {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+
+        assert_eq!(migrate(&pool).await.unwrap(), MIGRATIONS.len());
+        assert!(columns("accounts", &pool)
+            .await
+            .contains(&String::from("is_admin")));
+        assert!(columns("audiobooks", &pool)
+            .await
+            .contains(&String::from("directory")));
+        assert!(columns("positions", &pool)
+            .await
+            .contains(&String::from("position_ms")));
+
+        // the data of the older version is still there
+        assert_eq!(
+            schema::query_user(String::from("key"), &pool)
+                .await
+                .unwrap(),
+            "alice"
+        );
+        let position = schema::select_position(String::from("hash"), String::from("alice"), &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        // of the duplicated positions only the last one written is kept, the
+        // ones of unknown users and books are dropped
+        assert_eq!(position.position_ms, 42000);
+        let rows = sqlx::query(r#"SELECT hash FROM positions"#)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+
+        assert_eq!(migrate(&pool).await.unwrap(), 0);
+    }
+
+    #[rocket::async_test]
+    async fn refuses_databases_of_newer_versions() {
+        let pool = pool("newer").await;
+        migrate(&pool).await.unwrap();
+        sqlx::query(r#"INSERT INTO schema_version (version, description, applied_at) VALUES (?, 'future', 0)"#)
+            .bind(latest_version() + 1)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            migrate(&pool).await,
+            Err(MigrationError::TooNew { .. })
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn enforces_the_constraints() {
+        let pool = pool("constraints").await;
+        migrate(&pool).await.unwrap();
+        schema::insert_user(String::from("alice"), String::from("secret"), &pool)
+            .await
+            .unwrap();
+        sqlx::query(r#"INSERT INTO audiobooks (hash, title, author) VALUES ('hash', 'Dune', 'Frank Herbert')"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let position = |hash: &'static str, user: &'static str| {
+            schema::insert_position(
+                String::from(hash),
+                String::from(user),
+                String::from("01.mp3"),
+                1000,
+                None,
+                None,
+                &pool,
+            )
+        };
+        assert!(position("hash", "alice").await.is_ok());
+        assert!(position("gone", "alice").await.is_err());
+        assert!(position("hash", "bob").await.is_err());
+
+        // renaming and deleting accounts cascades to what references them
+        sqlx::query(r#"UPDATE accounts SET user = 'alicia' WHERE user = 'alice'"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(
+            schema::select_position(String::from("hash"), String::from("alicia"), &pool)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        sqlx::query(r#"DELETE FROM accounts WHERE user = 'alicia'"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(
+            schema::select_position(String::from("hash"), String::from("alicia"), &pool)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[rocket::async_test]
+    async fn refuses_usernames_only_differing_by_case() {
+        let pool = pool("nocase").await;
+        let legacy = [
+            r#"CREATE TABLE accounts (user TEXT PRIMARY KEY, password TEXT, key TEXT)"#,
+            r#"INSERT INTO accounts VALUES ('alice', 'secret', 'key')"#,
+            r#"INSERT INTO accounts VALUES ('Alice', 'secret', 'other key')"#,
+        ];
+        for statement in legacy.iter() {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+
+        // the whole migration is rolled back, not only the failing version
+        assert!(matches!(
+            migrate(&pool).await,
+            Err(MigrationError::Database(_))
+        ));
+        assert!(!columns("accounts", &pool)
+            .await
+            .contains(&String::from("is_admin")));
+
+        sqlx::query(r#"UPDATE accounts SET user = 'alice2' WHERE user = 'Alice'"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(migrate(&pool).await.unwrap(), MIGRATIONS.len());
+        assert!(
+            schema::insert_user(String::from("ALICE"), String::from("secret"), &pool)
+                .await
+                .is_err()
+        );
+    }
+}
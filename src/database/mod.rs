@@ -1 +1,7 @@
+pub mod backup;
+pub mod migrations;
+#[cfg(feature = "postgres")]
+pub mod postgres;
 pub mod schema;
+pub mod sqlite;
+pub mod storage;
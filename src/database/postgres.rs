@@ -0,0 +1,1885 @@
+use rocket::async_trait;
+use rocket_db_pools::sqlx;
+
+use crate::database::migrations::{Migration, MigrationError};
+use crate::database::storage::Storage;
+use crate::models::access;
+use crate::models::account;
+use crate::models::audiobook;
+use crate::models::author;
+use crate::models::collection;
+use crate::models::export;
+use crate::models::invite;
+use crate::models::position;
+use crate::models::series;
+use crate::models::session;
+
+/// The migrations of the sqlite backend written for postgresql, versions have
+/// to stay in step with them. There never was a postgresql database without
+/// constraints, so the first version already has them.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "initial schema",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS accounts (
+            "user" TEXT PRIMARY KEY,
+            password TEXT,
+            key TEXT,
+            is_admin BOOLEAN NOT NULL DEFAULT FALSE,
+            disabled BOOLEAN NOT NULL DEFAULT FALSE)"#,
+            r#"CREATE TABLE IF NOT EXISTS sessions (
+            access_token TEXT PRIMARY KEY,
+            refresh_token TEXT NOT NULL UNIQUE,
+            "user" TEXT NOT NULL REFERENCES accounts ("user") ON UPDATE CASCADE ON DELETE CASCADE,
+            access_expires BIGINT NOT NULL,
+            refresh_expires BIGINT NOT NULL)"#,
+            r#"CREATE TABLE IF NOT EXISTS invites (
+            code TEXT PRIMARY KEY,
+            uses BIGINT,
+            expires BIGINT,
+            created_by TEXT)"#,
+            r#"CREATE TABLE IF NOT EXISTS access_rules (
+            id BIGSERIAL PRIMARY KEY,
+            subject_kind TEXT,
+            subject TEXT,
+            kind TEXT,
+            value TEXT)"#,
+            r#"CREATE TABLE IF NOT EXISTS group_members (
+            group_name TEXT NOT NULL,
+            "user" TEXT NOT NULL REFERENCES accounts ("user") ON UPDATE CASCADE ON DELETE CASCADE,
+            PRIMARY KEY (group_name, "user"))"#,
+            // the search column plays the part of the fts5 table of sqlite
+            r#"CREATE TABLE IF NOT EXISTS audiobooks (
+            hash TEXT PRIMARY KEY,
+            title TEXT,
+            author TEXT,
+            path TEXT,
+            narrator TEXT,
+            description TEXT,
+            series TEXT,
+            series_index DOUBLE PRECISION,
+            genre TEXT,
+            language TEXT,
+            directory TEXT,
+            search TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple',
+                COALESCE(title, '') || ' ' || COALESCE(author, '') || ' ' ||
+                COALESCE(narrator, '') || ' ' || COALESCE(description, ''))) STORED)"#,
+            r#"CREATE TABLE IF NOT EXISTS audiobook_tags (
+            hash TEXT NOT NULL REFERENCES audiobooks (hash) ON DELETE CASCADE,
+            tag TEXT NOT NULL,
+            PRIMARY KEY (hash, tag))"#,
+            r#"CREATE TABLE IF NOT EXISTS audiobook_files (
+            hash TEXT NOT NULL REFERENCES audiobooks (hash) ON DELETE CASCADE,
+            file TEXT NOT NULL,
+            duration DOUBLE PRECISION,
+            PRIMARY KEY (hash, file))"#,
+            r#"CREATE TABLE IF NOT EXISTS positions (
+            hash TEXT NOT NULL REFERENCES audiobooks (hash) ON DELETE CASCADE,
+            "user" TEXT NOT NULL REFERENCES accounts ("user") ON UPDATE CASCADE ON DELETE CASCADE,
+            file TEXT NOT NULL,
+            position_ms BIGINT NOT NULL,
+            speed DOUBLE PRECISION,
+            chapter INTEGER,
+            last_modified TIMESTAMPTZ,
+            PRIMARY KEY (hash, "user"))"#,
+            r#"CREATE TABLE IF NOT EXISTS collections (
+            id BIGSERIAL PRIMARY KEY,
+            "user" TEXT NOT NULL REFERENCES accounts ("user") ON UPDATE CASCADE ON DELETE CASCADE,
+            name TEXT NOT NULL)"#,
+            r#"CREATE TABLE IF NOT EXISTS collection_audiobooks (
+            collection BIGINT NOT NULL REFERENCES collections (id) ON DELETE CASCADE,
+            hash TEXT NOT NULL REFERENCES audiobooks (hash) ON DELETE CASCADE,
+            PRIMARY KEY (collection, hash))"#,
+            r#"CREATE TABLE IF NOT EXISTS collection_shares (
+            collection BIGINT NOT NULL REFERENCES collections (id) ON DELETE CASCADE,
+            "user" TEXT NOT NULL REFERENCES accounts ("user") ON UPDATE CASCADE ON DELETE CASCADE,
+            PRIMARY KEY (collection, "user"))"#,
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "constraints, foreign keys and indexes",
+        statements: &[
+            r#"CREATE UNIQUE INDEX accounts_key ON accounts (key)"#,
+            r#"CREATE INDEX positions_user ON positions ("user")"#,
+            r#"CREATE INDEX sessions_user ON sessions ("user")"#,
+            r#"CREATE INDEX collections_user ON collections ("user")"#,
+            r#"CREATE INDEX collection_shares_user ON collection_shares ("user")"#,
+            r#"CREATE INDEX group_members_user ON group_members ("user")"#,
+            r#"CREATE INDEX audiobook_tags_tag ON audiobook_tags (tag)"#,
+            r#"CREATE INDEX audiobooks_author ON audiobooks (LOWER(author))"#,
+            r#"CREATE INDEX audiobooks_series ON audiobooks (LOWER(series))"#,
+            r#"CREATE INDEX audiobooks_search ON audiobooks USING GIN (search)"#,
+            r#"CREATE INDEX access_rules_subject ON access_rules (subject_kind, subject)"#,
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "origin of accounts",
+        statements: &[
+            r#"ALTER TABLE accounts ADD COLUMN auth_source TEXT NOT NULL DEFAULT 'local'"#,
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "usernames unique in any case",
+        statements: &[r#"CREATE UNIQUE INDEX accounts_user_nocase ON accounts (LOWER("user"))"#],
+    },
+];
+
+#[derive(sqlx::FromRow, Debug)]
+struct VersionRow {
+    version: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AudiobookFmtRow {
+    hash: String,
+    title: String,
+    author: String,
+    narrator: Option<String>,
+    series: Option<String>,
+    series_index: Option<f64>,
+    genre: Option<String>,
+    language: Option<String>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AudiobookPageRow {
+    #[sqlx(flatten)]
+    audiobook: AudiobookFmtRow,
+    rank: f64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AuthorRow {
+    author: String,
+    count: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct SeriesRow {
+    series: String,
+    author: String,
+    count: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct CollectionRow {
+    id: i64,
+    user: String,
+    name: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct InviteRow {
+    code: String,
+    uses: i64,
+    expires: Option<i64>,
+    created_by: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AccessRuleRow {
+    id: i64,
+    subject_kind: String,
+    subject: String,
+    kind: String,
+    value: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct GroupMemberRow {
+    group_name: String,
+    user: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct IdRow {
+    id: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct HashRow {
+    hash: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct CountRow {
+    count: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AudiobookPathRow {
+    path: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct DurationRow {
+    duration: Option<f64>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct ScannedAudiobookRow {
+    title: String,
+    author: String,
+    path: String,
+    narrator: Option<String>,
+    description: Option<String>,
+    series: Option<String>,
+    series_index: Option<f64>,
+    genre: Option<String>,
+    language: Option<String>,
+    directory: Option<String>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct TagRow {
+    tag: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct FileRow {
+    file: String,
+    duration: Option<f64>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct PositionRow {
+    file: String,
+    position_ms: i64,
+    speed: Option<f64>,
+    chapter: Option<i32>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct UserPositionRow {
+    title: String,
+    author: String,
+    file: String,
+    position_ms: i64,
+    speed: Option<f64>,
+    chapter: Option<i32>,
+    last_modified: Option<i64>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AudiobookRefRow {
+    title: String,
+    author: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct UserRow {
+    user: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AccountRow {
+    user: String,
+    is_admin: bool,
+    disabled: bool,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct KeyDisabledRow {
+    key: String,
+    disabled: bool,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct KeyRow {
+    key: String,
+}
+
+/// Condition on the audiobooks table keeping the books a user is allowed to
+/// see, `user` is the placeholder the user is bound to. It follows the same
+/// rules as the sqlite backend.
+fn visible(user: &str) -> String {
+    format!(
+        r#"(EXISTS (SELECT 1 FROM accounts WHERE "user" = {u} AND is_admin)
+    OR NOT EXISTS (SELECT 1 FROM access_rules r WHERE
+        (r.subject_kind = 'user' AND r.subject = {u})
+        OR (r.subject_kind = 'group' AND r.subject IN (
+            SELECT group_name FROM group_members WHERE "user" = {u})))
+    OR EXISTS (SELECT 1 FROM access_rules r WHERE
+        ((r.subject_kind = 'user' AND r.subject = {u})
+        OR (r.subject_kind = 'group' AND r.subject IN (
+            SELECT group_name FROM group_members WHERE "user" = {u})))
+        AND ((r.kind = 'prefix' AND substr(audiobooks.directory, 1, length(r.value)) = r.value)
+        OR (r.kind = 'tag' AND EXISTS (SELECT 1 FROM audiobook_tags t
+            WHERE t.hash = audiobooks.hash AND t.tag = r.value))
+        OR (r.kind = 'grant' AND r.value = audiobooks.hash))))"#,
+        u = user
+    )
+}
+
+/// Adds a value to the binds of a query being built, returns its placeholder
+fn placeholder(binds: &mut Vec<String>, value: String) -> String {
+    binds.push(value);
+    format!("${}", binds.len())
+}
+
+/// sql of a key the audiobooks are sorted by and of a value to compare it
+/// with, `rank` is the relevance of the books when searching
+fn sort_expression(key: audiobook::SortKey, rank: &str) -> (String, &'static str) {
+    match key {
+        audiobook::SortKey::Rank => (String::from(rank), "{}"),
+        audiobook::SortKey::Title => (String::from("LOWER(audiobooks.title)"), "LOWER({})"),
+        audiobook::SortKey::Author => (String::from("LOWER(audiobooks.author)"), "LOWER({})"),
+        audiobook::SortKey::NoSeries => (
+            String::from("(audiobooks.series IS NULL)::INT::FLOAT8"),
+            "{}",
+        ),
+        audiobook::SortKey::Series => (
+            String::from("LOWER(COALESCE(audiobooks.series, ''))"),
+            "LOWER({})",
+        ),
+        audiobook::SortKey::SeriesIndex => {
+            (String::from("COALESCE(audiobooks.series_index, 0)"), "{}")
+        }
+        audiobook::SortKey::Hash => (String::from("audiobooks.hash"), "{}"),
+    }
+}
+
+/// Builds a tsquery out of a free text search, every word is quoted so the
+/// user cannot inject operators and matched as a prefix
+fn tsquery_expression(q: &str) -> Option<String> {
+    let terms: Vec<String> = q
+        .split_whitespace()
+        .map(|term| format!("'{}':*", term.replace('\\', "\\\\").replace('\'', "''")))
+        .collect();
+    if terms.is_empty() {
+        return None;
+    }
+    Some(terms.join(" & "))
+}
+
+fn audiobook_fmt(row: AudiobookFmtRow) -> audiobook::AudiobookFmt {
+    audiobook::AudiobookFmt {
+        hash: row.hash,
+        title: row.title,
+        author: row.author,
+        narrator: row.narrator,
+        series: row.series,
+        series_index: row.series_index,
+        genre: row.genre,
+        language: row.language,
+    }
+}
+
+/// Backend storing everything in a postgresql database, for setups where
+/// several services share one server
+pub struct PostgresStorage {
+    pub pool: sqlx::Pool<sqlx::Postgres>,
+}
+
+impl PostgresStorage {
+    pub async fn connect(url: &str, max_connections: u32) -> Result<Self, sqlx::Error> {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(max_connections)
+            .connect(url)
+            .await?;
+
+        Ok(Self { pool })
+    }
+
+    async fn collection_from_row(
+        &self,
+        row: CollectionRow,
+        user: String,
+    ) -> Result<collection::Collection, sqlx::Error> {
+        let sql = format!(
+            r#"SELECT audiobooks.hash FROM collection_audiobooks
+            JOIN audiobooks ON audiobooks.hash = collection_audiobooks.hash
+            WHERE collection_audiobooks.collection = $1 AND {}
+            ORDER BY audiobooks.hash"#,
+            visible("$2")
+        );
+        let audiobooks = sqlx::query_as::<_, HashRow>(&sql)
+            .bind(row.id)
+            .bind(user.clone())
+            .fetch_all(&self.pool)
+            .await?;
+
+        let shared_with = if row.user == user {
+            sqlx::query_as::<_, UserRow>(
+                r#"SELECT "user" FROM collection_shares WHERE collection = $1 ORDER BY "user""#,
+            )
+            .bind(row.id)
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|share| share.user)
+            .collect()
+        } else {
+            Vec::new()
+        };
+
+        Ok(collection::Collection {
+            id: row.id,
+            name: row.name,
+            owner: row.user,
+            shared_with,
+            audiobooks: audiobooks.into_iter().map(|row| row.hash).collect(),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn migrate(&self) -> Result<usize, MigrationError> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS schema_version (
+            version BIGINT PRIMARY KEY,
+            description TEXT,
+            applied_at BIGINT)"#,
+        )
+        .execute(&mut tx)
+        .await?;
+
+        // servers sharing the database may start at the same time
+        sqlx::query(r#"LOCK TABLE schema_version IN EXCLUSIVE MODE"#)
+            .execute(&mut tx)
+            .await?;
+
+        let current = sqlx::query_as::<_, VersionRow>(
+            r#"SELECT COALESCE(MAX(version), 0) AS version FROM schema_version"#,
+        )
+        .fetch_one(&mut tx)
+        .await?
+        .version;
+
+        let latest = MIGRATIONS
+            .iter()
+            .map(|migration| migration.version)
+            .max()
+            .unwrap_or(0);
+        if current > latest {
+            return Err(MigrationError::TooNew {
+                found: current,
+                known: latest,
+            });
+        }
+
+        let mut applied = 0;
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+            for statement in migration.statements.iter() {
+                sqlx::query(statement).execute(&mut tx).await?;
+            }
+
+            sqlx::query(
+                r#"INSERT INTO schema_version (version, description, applied_at)
+                VALUES ($1, $2, $3)"#,
+            )
+            .bind(migration.version)
+            .bind(migration.description)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&mut tx)
+            .await?;
+
+            applied += 1;
+        }
+
+        tx.commit().await?;
+
+        Ok(applied)
+    }
+
+    async fn schema_version(&self) -> Result<i64, sqlx::Error> {
+        let table = sqlx::query_as::<_, CountRow>(
+            r#"SELECT COUNT(*) AS count FROM information_schema.tables
+            WHERE table_schema = current_schema() AND table_name = 'schema_version'"#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if table.count == 0 {
+            return Ok(0);
+        }
+
+        let row = sqlx::query_as::<_, VersionRow>(
+            r#"SELECT COALESCE(MAX(version), 0) AS version FROM schema_version"#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.version)
+    }
+
+    async fn query_audiobooks(
+        &self,
+        search: &audiobook::AudiobookSearch,
+        user: String,
+    ) -> Result<audiobook::Audiobooks, sqlx::Error> {
+        let mut conditions: Vec<String> = Vec::new();
+        let mut binds: Vec<String> = Vec::new();
+        let user = placeholder(&mut binds, user);
+
+        // negated so that, like the other keys, lower comes first
+        let mut rank = None;
+        if let Some(tsquery) = search.q.as_deref().and_then(tsquery_expression) {
+            let tsquery = placeholder(&mut binds, tsquery);
+            conditions.push(format!(
+                "audiobooks.search @@ to_tsquery('simple', {})",
+                tsquery
+            ));
+            rank = Some(format!(
+                "-ts_rank(audiobooks.search, to_tsquery('simple', {}))::FLOAT8",
+                tsquery
+            ));
+        }
+
+        let columns = [
+            ("author", search.author.clone()),
+            ("series", search.series.clone()),
+            ("genre", search.genre.clone()),
+            ("language", search.language.clone()),
+        ];
+        for (column, value) in columns.iter() {
+            if let Some(value) = value {
+                conditions.push(format!(
+                    "LOWER(audiobooks.{}) = LOWER({})",
+                    column,
+                    placeholder(&mut binds, value.clone())
+                ));
+            }
+        }
+
+        match search.status {
+            Some(audiobook::AudiobookStatus::New) => {
+                conditions.push(format!(
+                    r#"audiobooks.hash NOT IN (SELECT hash FROM positions WHERE "user" = {})"#,
+                    user
+                ));
+            }
+            Some(audiobook::AudiobookStatus::Started) => {
+                conditions.push(format!(
+                    r#"audiobooks.hash IN (SELECT hash FROM positions WHERE "user" = {})"#,
+                    user
+                ));
+            }
+            None => {}
+        };
+
+        if let Some(collection) = search.collection {
+            let collection = placeholder(&mut binds, collection.to_string());
+            conditions.push(format!(
+                r#"audiobooks.hash IN (SELECT hash FROM collection_audiobooks WHERE collection IN (
+                SELECT id FROM collections WHERE id = {c}::BIGINT AND ("user" = {u} OR id IN (
+                SELECT collection FROM collection_shares WHERE "user" = {u}))))"#,
+                c = collection,
+                u = user
+            ));
+        }
+
+        conditions.push(visible(&user));
+
+        let filter = format!(" WHERE {}", conditions.join(" AND "));
+
+        let keys = search.sort.keys(rank.is_some());
+        let rank = rank.unwrap_or_else(|| String::from("0::FLOAT8"));
+        let order: Vec<String> = keys
+            .iter()
+            .map(|(key, desc)| {
+                format!(
+                    "{} {}",
+                    sort_expression(*key, &rank).0,
+                    if *desc { "DESC" } else { "ASC" }
+                )
+            })
+            .collect();
+
+        // the books sorted after the cursor, key by key: a greater first key,
+        // or the same first key and a greater second one, and so on
+        let mut after = String::new();
+        let mut after_binds = Vec::new();
+        if let Some(cursor) = &search.after {
+            let mut compare = |key: audiobook::SortKey, operator: &str| {
+                let (column, value) = sort_expression(key, &rank);
+                after_binds.push(cursor.value(key));
+                let bind = format!("${}", binds.len() + after_binds.len());
+                format!("{} {} {}", column, operator, value.replace("{}", &bind))
+            };
+            let mut terms = Vec::new();
+            for (i, (key, desc)) in keys.iter().enumerate() {
+                let mut parts = Vec::new();
+                for (previous, _) in keys[..i].iter() {
+                    parts.push(compare(*previous, "="));
+                }
+                parts.push(compare(*key, if *desc { "<" } else { ">" }));
+                terms.push(format!("({})", parts.join(" AND ")));
+            }
+            after = format!(" AND ({})", terms.join(" OR "));
+        }
+
+        let count_sql = format!("SELECT COUNT(*) AS count FROM audiobooks{}", filter);
+        let mut count_query = sqlx::query_as::<_, CountRow>(&count_sql);
+        for bind in binds.iter() {
+            count_query = count_query.bind(bind.clone());
+        }
+        let total = count_query.fetch_one(&self.pool).await?.count;
+
+        // one more book than asked for tells whether there is a next page
+        let rows_sql = format!(
+            r#"SELECT audiobooks.hash, audiobooks.title, audiobooks.author, audiobooks.narrator,
+            audiobooks.series, audiobooks.series_index, audiobooks.genre, audiobooks.language,
+            {} AS rank
+            FROM audiobooks{}{} ORDER BY {} LIMIT {}"#,
+            rank,
+            filter,
+            after,
+            order.join(", "),
+            search.limit + 1
+        );
+        let mut rows_query = sqlx::query_as::<_, AudiobookPageRow>(&rows_sql);
+        for bind in binds.iter() {
+            rows_query = rows_query.bind(bind.clone());
+        }
+        for bind in after_binds {
+            rows_query = match bind {
+                audiobook::SortValue::Text(text) => rows_query.bind(text),
+                audiobook::SortValue::Real(real) => rows_query.bind(real),
+            };
+        }
+        let mut rows = rows_query.fetch_all(&self.pool).await?;
+
+        let more = rows.len() as i64 > search.limit;
+        rows.truncate(search.limit as usize);
+        let last_rank = rows.last().map(|row| row.rank);
+
+        let audiobooks: Vec<audiobook::AudiobookFmt> = rows
+            .into_iter()
+            .map(|row| audiobook_fmt(row.audiobook))
+            .collect();
+
+        let next = match (more, audiobooks.last(), last_rank) {
+            (true, Some(last), Some(rank)) => {
+                Some(audiobook::AudiobookCursor::new(last, rank).encode())
+            }
+            _ => None,
+        };
+
+        Ok(audiobook::Audiobooks {
+            audiobooks,
+            total,
+            next,
+        })
+    }
+
+    async fn query_authors(&self, user: String) -> Result<author::Authors, sqlx::Error> {
+        let sql = format!(
+            r#"SELECT author, COUNT(*) AS count FROM audiobooks WHERE {}
+            GROUP BY author ORDER BY LOWER(author)"#,
+            visible("$1")
+        );
+        let rows = sqlx::query_as::<_, AuthorRow>(&sql)
+            .bind(user)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let authors = rows
+            .into_iter()
+            .map(|row| author::AuthorFmt {
+                id: author::compute_id(row.author.clone()),
+                name: row.author,
+                count: row.count,
+            })
+            .collect();
+
+        Ok(author::Authors { authors })
+    }
+
+    async fn query_author_audiobooks(
+        &self,
+        id: String,
+        user: String,
+    ) -> Result<author::AuthorAudiobooks, sqlx::Error> {
+        let authors = self.query_authors(user.clone()).await?;
+        let name = match authors.authors.into_iter().find(|author| author.id == id) {
+            Some(author) => author.name,
+            None => {
+                return Err(sqlx::Error::RowNotFound);
+            }
+        };
+
+        let sql = format!(
+            r#"SELECT hash, title, author, narrator, series, series_index, genre, language
+            FROM audiobooks WHERE author = $1 AND {}
+            ORDER BY series IS NULL, LOWER(series), series_index, LOWER(title)"#,
+            visible("$2")
+        );
+        let rows = sqlx::query_as::<_, AudiobookFmtRow>(&sql)
+            .bind(name.clone())
+            .bind(user)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(author::AuthorAudiobooks {
+            id,
+            name,
+            audiobooks: rows.into_iter().map(audiobook_fmt).collect(),
+        })
+    }
+
+    async fn query_series(&self, user: String) -> Result<series::Series, sqlx::Error> {
+        let sql = format!(
+            r#"SELECT series, author, COUNT(*) AS count FROM audiobooks
+            WHERE series IS NOT NULL AND {}
+            GROUP BY series, author ORDER BY LOWER(series), LOWER(author)"#,
+            visible("$1")
+        );
+        let rows = sqlx::query_as::<_, SeriesRow>(&sql)
+            .bind(user)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let series = rows
+            .into_iter()
+            .map(|row| series::SeriesFmt {
+                id: series::compute_id(row.series.clone(), row.author.clone()),
+                name: row.series,
+                author: row.author,
+                count: row.count,
+            })
+            .collect();
+
+        Ok(series::Series { series })
+    }
+
+    async fn query_series_audiobooks(
+        &self,
+        id: String,
+        user: String,
+    ) -> Result<series::SeriesAudiobooks, sqlx::Error> {
+        let series = self.query_series(user.clone()).await?;
+        let (name, author) = match series.series.into_iter().find(|series| series.id == id) {
+            Some(series) => (series.name, series.author),
+            None => {
+                return Err(sqlx::Error::RowNotFound);
+            }
+        };
+
+        let sql = format!(
+            r#"SELECT hash, title, author, narrator, series, series_index, genre, language
+            FROM audiobooks WHERE series = $1 AND author = $2 AND {}
+            ORDER BY series_index IS NULL, series_index, LOWER(title)"#,
+            visible("$3")
+        );
+        let rows = sqlx::query_as::<_, AudiobookFmtRow>(&sql)
+            .bind(name.clone())
+            .bind(author.clone())
+            .bind(user)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(series::SeriesAudiobooks {
+            id,
+            name,
+            author,
+            audiobooks: rows.into_iter().map(audiobook_fmt).collect(),
+        })
+    }
+
+    async fn query_audiobook(&self, hash: String, user: String) -> Result<String, sqlx::Error> {
+        let sql = format!(
+            r#"SELECT path FROM audiobooks WHERE hash = $1 AND {}"#,
+            visible("$2")
+        );
+        let row = sqlx::query_as::<_, AudiobookPathRow>(&sql)
+            .bind(hash)
+            .bind(user)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.path)
+    }
+
+    async fn insert_audiobook(&self, audiobook: audiobook::Audiobook) -> Result<(), sqlx::Error> {
+        let hash = audiobook::compute_hash(audiobook.title.clone(), audiobook.author.clone());
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            r#"INSERT INTO audiobooks (hash, title, author, path, narrator,
+            description, series, series_index, genre, language, directory)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (hash) DO UPDATE SET
+            title = EXCLUDED.title,
+            author = EXCLUDED.author,
+            path = EXCLUDED.path,
+            narrator = EXCLUDED.narrator,
+            description = EXCLUDED.description,
+            series = EXCLUDED.series,
+            series_index = EXCLUDED.series_index,
+            genre = EXCLUDED.genre,
+            language = EXCLUDED.language,
+            directory = EXCLUDED.directory"#,
+        )
+        .bind(hash.clone())
+        .bind(audiobook.title)
+        .bind(audiobook.author)
+        .bind(audiobook.path)
+        .bind(audiobook.narrator)
+        .bind(audiobook.description)
+        .bind(audiobook.series)
+        .bind(audiobook.series_index)
+        .bind(audiobook.genre)
+        .bind(audiobook.language)
+        .bind(audiobook.directory)
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query(r#"DELETE FROM audiobook_tags WHERE hash = $1"#)
+            .bind(hash.clone())
+            .execute(&mut tx)
+            .await?;
+        for tag in audiobook.tags {
+            sqlx::query(
+                r#"INSERT INTO audiobook_tags (hash, tag) VALUES ($1, $2)
+                ON CONFLICT DO NOTHING"#,
+            )
+            .bind(hash.clone())
+            .bind(tag)
+            .execute(&mut tx)
+            .await?;
+        }
+
+        sqlx::query(r#"DELETE FROM audiobook_files WHERE hash = $1"#)
+            .bind(hash.clone())
+            .execute(&mut tx)
+            .await?;
+        for file in audiobook.files {
+            sqlx::query(
+                r#"INSERT INTO audiobook_files (hash, file, duration) VALUES ($1, $2, $3)
+                ON CONFLICT (hash, file) DO UPDATE SET duration = EXCLUDED.duration"#,
+            )
+            .bind(hash.clone())
+            .bind(file.name)
+            .bind(file.duration)
+            .execute(&mut tx)
+            .await?;
+        }
+
+        tx.commit().await
+    }
+
+    async fn query_scanned_audiobook(
+        &self,
+        hash: String,
+    ) -> Result<Option<audiobook::Audiobook>, sqlx::Error> {
+        let row = sqlx::query_as::<_, ScannedAudiobookRow>(
+            r#"SELECT title, author, path, narrator, description, series, series_index,
+            genre, language, directory
+            FROM audiobooks WHERE hash = $1"#,
+        )
+        .bind(hash.clone())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => {
+                return Ok(None);
+            }
+        };
+
+        // sorted byte by byte like the scanner does, whatever the locale
+        let tags = sqlx::query_as::<_, TagRow>(
+            r#"SELECT tag FROM audiobook_tags WHERE hash = $1 ORDER BY tag COLLATE "C""#,
+        )
+        .bind(hash.clone())
+        .fetch_all(&self.pool)
+        .await?;
+
+        let files = sqlx::query_as::<_, FileRow>(
+            r#"SELECT file, duration FROM audiobook_files WHERE hash = $1
+            ORDER BY file COLLATE "C""#,
+        )
+        .bind(hash)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Some(audiobook::Audiobook {
+            title: row.title,
+            author: row.author,
+            path: row.path,
+            narrator: row.narrator,
+            description: row.description,
+            series: row.series,
+            series_index: row.series_index,
+            genre: row.genre,
+            language: row.language,
+            tags: tags.into_iter().map(|row| row.tag).collect(),
+            directory: row.directory.unwrap_or_default(),
+            files: files
+                .into_iter()
+                .map(|row| audiobook::AudiobookFile {
+                    name: row.file,
+                    duration: row.duration,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn query_audiobook_file(
+        &self,
+        hash: String,
+        file: String,
+    ) -> Result<Option<f64>, sqlx::Error> {
+        let row = sqlx::query_as::<_, DurationRow>(
+            r#"SELECT duration FROM audiobook_files WHERE hash = $1 AND file = $2"#,
+        )
+        .bind(hash)
+        .bind(file)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.duration)
+    }
+
+    async fn insert_position(
+        &self,
+        hash: String,
+        user: String,
+        file: String,
+        position_ms: u64,
+        speed: Option<f64>,
+        chapter: Option<u32>,
+    ) -> Result<(), sqlx::Error> {
+        let position_ms = position_ms.min(i64::MAX as u64) as i64;
+        let chapter = chapter.map(|chapter| chapter.min(i32::MAX as u32) as i32);
+
+        sqlx::query(
+            r#"INSERT INTO positions (hash, "user", file, position_ms, speed, chapter, last_modified)
+            VALUES ($1, $2, $3, $4, $5, $6, NOW())
+            ON CONFLICT (hash, "user") DO UPDATE SET
+            file = EXCLUDED.file,
+            position_ms = EXCLUDED.position_ms,
+            speed = EXCLUDED.speed,
+            chapter = EXCLUDED.chapter,
+            last_modified = EXCLUDED.last_modified"#,
+        )
+        .bind(hash)
+        .bind(user)
+        .bind(file)
+        .bind(position_ms)
+        .bind(speed)
+        .bind(chapter)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn select_position(
+        &self,
+        hash: String,
+        user: String,
+    ) -> Result<Option<position::Position>, sqlx::Error> {
+        let row = sqlx::query_as::<_, PositionRow>(
+            r#"SELECT file, position_ms, speed, chapter FROM positions
+            WHERE hash = $1 AND "user" = $2"#,
+        )
+        .bind(hash)
+        .bind(user)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| {
+            position::Position::new(
+                row.file,
+                row.position_ms.max(0) as u64,
+                row.speed,
+                row.chapter.map(|chapter| chapter.max(0) as u32),
+            )
+        }))
+    }
+
+    async fn select_positions(
+        &self,
+        user: String,
+    ) -> Result<Vec<export::ExportPosition>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, UserPositionRow>(
+            r#"SELECT audiobooks.title, audiobooks.author, positions.file,
+            positions.position_ms, positions.speed, positions.chapter,
+            CAST(EXTRACT(EPOCH FROM positions.last_modified) AS BIGINT) AS last_modified
+            FROM positions JOIN audiobooks ON audiobooks.hash = positions.hash
+            WHERE positions."user" = $1
+            ORDER BY LOWER(audiobooks.title), LOWER(audiobooks.author)"#,
+        )
+        .bind(user)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| export::ExportPosition {
+                audiobook: export::AudiobookRef {
+                    title: row.title,
+                    author: row.author,
+                },
+                file: row.file,
+                position_ms: row.position_ms.max(0) as u64,
+                speed: row.speed,
+                chapter: row.chapter.map(|chapter| chapter.max(0) as u32),
+                last_modified: row.last_modified,
+            })
+            .collect())
+    }
+
+    async fn insert_import(
+        &self,
+        user: String,
+        account: Option<&export::ExportAccount>,
+        positions: &[export::ImportPosition],
+        collections: &[export::ImportCollection],
+    ) -> Result<(usize, usize), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        if let Some(account) = account {
+            let res = sqlx::query(
+                r#"UPDATE accounts SET is_admin = $1, disabled = $2 WHERE "user" = $3"#,
+            )
+            .bind(account.is_admin)
+            .bind(account.disabled)
+            .bind(user.clone())
+            .execute(&mut tx)
+            .await?;
+
+            if res.rows_affected() == 0 {
+                return Err(sqlx::Error::RowNotFound);
+            }
+        }
+
+        let mut saved = 0;
+        for position in positions.iter() {
+            let position_ms = position.position_ms.min(i64::MAX as u64) as i64;
+            let chapter = position
+                .chapter
+                .map(|chapter| chapter.min(i32::MAX as u32) as i32);
+
+            let res = sqlx::query(
+                r#"INSERT INTO positions (hash, "user", file, position_ms, speed, chapter, last_modified)
+                VALUES ($1, $2, $3, $4, $5, $6, TO_TIMESTAMP($7))
+                ON CONFLICT (hash, "user") DO UPDATE SET
+                file = EXCLUDED.file,
+                position_ms = EXCLUDED.position_ms,
+                speed = EXCLUDED.speed,
+                chapter = EXCLUDED.chapter,
+                last_modified = EXCLUDED.last_modified
+                WHERE positions.last_modified IS NULL
+                OR positions.last_modified < EXCLUDED.last_modified"#,
+            )
+            .bind(position.hash.clone())
+            .bind(user.clone())
+            .bind(position.file.clone())
+            .bind(position_ms)
+            .bind(position.speed)
+            .bind(chapter)
+            .bind(position.last_modified.map(|last_modified| last_modified as f64))
+            .execute(&mut tx)
+            .await?;
+
+            saved += res.rows_affected() as usize;
+        }
+
+        let mut changed = 0;
+        for collection in collections.iter() {
+            let existing = sqlx::query_as::<_, IdRow>(
+                r#"SELECT id FROM collections WHERE "user" = $1 AND name = $2 ORDER BY id LIMIT 1"#,
+            )
+            .bind(user.clone())
+            .bind(collection.name.clone())
+            .fetch_optional(&mut tx)
+            .await?;
+
+            let (id, mut added) = match existing {
+                Some(row) => (row.id, false),
+                None => {
+                    let row = sqlx::query_as::<_, IdRow>(
+                        r#"INSERT INTO collections ("user", name) VALUES ($1, $2) RETURNING id"#,
+                    )
+                    .bind(user.clone())
+                    .bind(collection.name.clone())
+                    .fetch_one(&mut tx)
+                    .await?;
+                    (row.id, true)
+                }
+            };
+
+            for hash in collection.audiobooks.iter() {
+                let res = sqlx::query(
+                    r#"INSERT INTO collection_audiobooks (collection, hash) VALUES ($1, $2)
+                    ON CONFLICT DO NOTHING"#,
+                )
+                .bind(id)
+                .bind(hash.clone())
+                .execute(&mut tx)
+                .await?;
+                added |= res.rows_affected() > 0;
+            }
+
+            for share in collection.shared_with.iter() {
+                let res = sqlx::query(
+                    r#"INSERT INTO collection_shares (collection, "user") VALUES ($1, $2)
+                    ON CONFLICT DO NOTHING"#,
+                )
+                .bind(id)
+                .bind(share.clone())
+                .execute(&mut tx)
+                .await?;
+                added |= res.rows_affected() > 0;
+            }
+
+            if added {
+                changed += 1;
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok((saved, changed))
+    }
+
+    async fn query_audiobook_ref(&self, hash: String) -> Result<export::AudiobookRef, sqlx::Error> {
+        let row = sqlx::query_as::<_, AudiobookRefRow>(
+            r#"SELECT title, author FROM audiobooks WHERE hash = $1"#,
+        )
+        .bind(hash)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(export::AudiobookRef {
+            title: row.title,
+            author: row.author,
+        })
+    }
+
+    async fn query_audiobook_hash(
+        &self,
+        title: String,
+        author: String,
+        user: String,
+    ) -> Result<String, sqlx::Error> {
+        let sql = format!(
+            r#"SELECT hash FROM audiobooks
+            WHERE LOWER(title) = LOWER($1) AND LOWER(author) = LOWER($2) AND {}
+            ORDER BY hash LIMIT 1"#,
+            visible("$3")
+        );
+        let row = sqlx::query_as::<_, HashRow>(&sql)
+            .bind(title)
+            .bind(author)
+            .bind(user)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.hash)
+    }
+
+    async fn insert_user(&self, user: String, password: String) -> Result<String, sqlx::Error> {
+        let key = account::generate_key();
+
+        sqlx::query(r#"INSERT INTO accounts ("user", password, key) VALUES ($1, $2, $3)"#)
+            .bind(user)
+            .bind(password)
+            .bind(key.clone())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(key)
+    }
+
+    async fn insert_admin_user(
+        &self,
+        user: String,
+        password: String,
+        is_admin: bool,
+    ) -> Result<String, sqlx::Error> {
+        let key = account::generate_key();
+
+        sqlx::query(
+            r#"INSERT INTO accounts ("user", password, key, is_admin) VALUES ($1, $2, $3, $4)"#,
+        )
+        .bind(user)
+        .bind(password)
+        .bind(key.clone())
+        .bind(is_admin)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(key)
+    }
+
+    async fn insert_user_with_invite(
+        &self,
+        user: String,
+        password: String,
+        code: String,
+    ) -> Result<String, sqlx::Error> {
+        let key = account::generate_key();
+        let now = chrono::Utc::now().timestamp();
+
+        let mut tx = self.pool.begin().await?;
+
+        let res = sqlx::query(
+            r#"UPDATE invites SET uses = uses - 1
+            WHERE code = $1 AND uses > 0 AND (expires IS NULL OR expires > $2)"#,
+        )
+        .bind(code)
+        .bind(now)
+        .execute(&mut tx)
+        .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        sqlx::query(r#"INSERT INTO accounts ("user", password, key) VALUES ($1, $2, $3)"#)
+            .bind(user)
+            .bind(password)
+            .bind(key.clone())
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(key)
+    }
+
+    async fn select_user(&self, user: String, password: String) -> Result<String, sqlx::Error> {
+        let row = sqlx::query_as::<_, KeyRow>(
+            r#"SELECT key FROM accounts
+            WHERE "user" = $1 AND password = $2 AND NOT disabled"#,
+        )
+        .bind(user)
+        .bind(password)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.key)
+    }
+
+    async fn query_user(&self, key: String) -> Result<String, sqlx::Error> {
+        let row = sqlx::query_as::<_, UserRow>(
+            r#"SELECT "user" FROM accounts
+            WHERE NOT disabled AND (key = $1 OR "user" IN (
+            SELECT "user" FROM sessions WHERE access_token = $1 AND access_expires > $2))"#,
+        )
+        .bind(key)
+        .bind(chrono::Utc::now().timestamp())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.user)
+    }
+
+    async fn query_or_insert_user_key(&self, user: String) -> Result<String, sqlx::Error> {
+        if user.is_empty() {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        sqlx::query(
+            r#"INSERT INTO accounts ("user", password, key, auth_source)
+            SELECT $1, $2, $3, 'proxy'
+            WHERE NOT EXISTS (SELECT 1 FROM accounts WHERE LOWER("user") = LOWER($1))
+            ON CONFLICT DO NOTHING"#,
+        )
+        .bind(user.clone())
+        .bind(account::generate_key())
+        .bind(account::generate_key())
+        .execute(&self.pool)
+        .await?;
+
+        let row = sqlx::query_as::<_, KeyDisabledRow>(
+            r#"SELECT key, disabled FROM accounts WHERE "user" = $1"#,
+        )
+        .bind(user)
+        .fetch_one(&self.pool)
+        .await?;
+
+        if row.disabled {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(row.key)
+    }
+
+    async fn query_or_insert_ldap_key(&self, user: String) -> Result<String, sqlx::Error> {
+        if user.is_empty() {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        sqlx::query(
+            r#"INSERT INTO accounts ("user", password, key, auth_source)
+            SELECT $1, $2, $3, 'ldap'
+            WHERE NOT EXISTS (SELECT 1 FROM accounts WHERE LOWER("user") = LOWER($1))
+            ON CONFLICT DO NOTHING"#,
+        )
+        .bind(user.clone())
+        .bind(account::generate_key())
+        .bind(account::generate_key())
+        .execute(&self.pool)
+        .await?;
+
+        let row = sqlx::query_as::<_, KeyDisabledRow>(
+            r#"SELECT key, disabled FROM accounts WHERE "user" = $1 AND auth_source = 'ldap'"#,
+        )
+        .bind(user)
+        .fetch_one(&self.pool)
+        .await?;
+
+        if row.disabled {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(row.key)
+    }
+
+    async fn update_ldap_disabled(&self, user: String) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"UPDATE accounts SET disabled = TRUE WHERE "user" = $1 AND auth_source = 'ldap'"#,
+        )
+        .bind(user)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn query_admin(&self, key: String) -> Result<String, sqlx::Error> {
+        let row = sqlx::query_as::<_, UserRow>(
+            r#"SELECT "user" FROM accounts
+            WHERE NOT disabled AND is_admin AND (key = $1 OR "user" IN (
+            SELECT "user" FROM sessions WHERE access_token = $1 AND access_expires > $2))"#,
+        )
+        .bind(key)
+        .bind(chrono::Utc::now().timestamp())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.user)
+    }
+
+    async fn query_accounts(&self) -> Result<account::Accounts, sqlx::Error> {
+        let rows = sqlx::query_as::<_, AccountRow>(
+            r#"SELECT "user", is_admin, disabled FROM accounts ORDER BY "user""#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let accounts = rows
+            .into_iter()
+            .map(|row| account::AccountFmt {
+                user: row.user,
+                is_admin: row.is_admin,
+                disabled: row.disabled,
+            })
+            .collect();
+
+        Ok(account::Accounts { accounts })
+    }
+
+    async fn query_account(&self, user: String) -> Result<String, sqlx::Error> {
+        let row = sqlx::query_as::<_, UserRow>(r#"SELECT "user" FROM accounts WHERE "user" = $1"#)
+            .bind(user)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.user)
+    }
+
+    async fn query_account_nocase(&self, user: String) -> Result<String, sqlx::Error> {
+        let row = sqlx::query_as::<_, UserRow>(
+            r#"SELECT "user" FROM accounts WHERE LOWER("user") = LOWER($1)"#,
+        )
+        .bind(user)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.user)
+    }
+
+    async fn update_admin(&self, user: String, is_admin: bool) -> Result<(), sqlx::Error> {
+        let res = sqlx::query(r#"UPDATE accounts SET is_admin = $1 WHERE "user" = $2"#)
+            .bind(is_admin)
+            .bind(user)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    async fn update_disabled(&self, user: String, disabled: bool) -> Result<(), sqlx::Error> {
+        let res = sqlx::query(r#"UPDATE accounts SET disabled = $1 WHERE "user" = $2"#)
+            .bind(disabled)
+            .bind(user)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    async fn update_password(&self, user: String, password: String) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let res = sqlx::query(r#"UPDATE accounts SET password = $1 WHERE "user" = $2"#)
+            .bind(password)
+            .bind(user.clone())
+            .execute(&mut tx)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        sqlx::query(r#"DELETE FROM sessions WHERE "user" = $1"#)
+            .bind(user)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn update_key(&self, user: String) -> Result<String, sqlx::Error> {
+        let key = account::generate_key();
+
+        let mut tx = self.pool.begin().await?;
+
+        let res = sqlx::query(r#"UPDATE accounts SET key = $1 WHERE "user" = $2"#)
+            .bind(key.clone())
+            .bind(user.clone())
+            .execute(&mut tx)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        sqlx::query(r#"DELETE FROM sessions WHERE "user" = $1"#)
+            .bind(user)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(key)
+    }
+
+    async fn update_user(&self, user: String, name: String) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        // the foreign keys carry the new name to the tables referencing the
+        // account, these two only hold it as plain text
+        let res = sqlx::query(r#"UPDATE accounts SET "user" = $1 WHERE "user" = $2"#)
+            .bind(name.clone())
+            .bind(user.clone())
+            .execute(&mut tx)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        let references = [
+            r#"UPDATE access_rules SET subject = $1 WHERE subject_kind = 'user' AND subject = $2"#,
+            r#"UPDATE invites SET created_by = $1 WHERE created_by = $2"#,
+        ];
+        for reference in references.iter() {
+            sqlx::query(reference)
+                .bind(name.clone())
+                .bind(user.clone())
+                .execute(&mut tx)
+                .await?;
+        }
+
+        tx.commit().await
+    }
+
+    async fn delete_user(&self, user: String) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        // everything else belonging to the account goes with it through the
+        // foreign keys
+        sqlx::query(r#"DELETE FROM access_rules WHERE subject_kind = 'user' AND subject = $1"#)
+            .bind(user.clone())
+            .execute(&mut tx)
+            .await?;
+        let res = sqlx::query(r#"DELETE FROM accounts WHERE "user" = $1"#)
+            .bind(user)
+            .execute(&mut tx)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        tx.commit().await
+    }
+
+    async fn insert_session(
+        &self,
+        user: String,
+        lifetimes: &session::Lifetimes,
+    ) -> Result<session::Session, sqlx::Error> {
+        let now = chrono::Utc::now().timestamp();
+        let access_token = account::generate_key();
+        let refresh_token = account::generate_key();
+
+        sqlx::query(r#"DELETE FROM sessions WHERE refresh_expires <= $1"#)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(
+            r#"INSERT INTO sessions (access_token, refresh_token, "user", access_expires, refresh_expires)
+            VALUES ($1, $2, $3, $4, $5)"#,
+        )
+        .bind(access_token.clone())
+        .bind(refresh_token.clone())
+        .bind(user)
+        .bind(now + lifetimes.access)
+        .bind(now + lifetimes.refresh)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(session::Session {
+            access_token,
+            refresh_token,
+            expires_in: lifetimes.access,
+        })
+    }
+
+    async fn update_session(
+        &self,
+        refresh_token: String,
+        lifetimes: &session::Lifetimes,
+    ) -> Result<session::Session, sqlx::Error> {
+        let now = chrono::Utc::now().timestamp();
+        let access_token = account::generate_key();
+        let new_refresh_token = account::generate_key();
+
+        let res = sqlx::query(
+            r#"UPDATE sessions SET access_token = $1, refresh_token = $2,
+            access_expires = $3, refresh_expires = $4
+            WHERE refresh_token = $5 AND refresh_expires > $6 AND "user" IN (
+            SELECT "user" FROM accounts WHERE NOT disabled)"#,
+        )
+        .bind(access_token.clone())
+        .bind(new_refresh_token.clone())
+        .bind(now + lifetimes.access)
+        .bind(now + lifetimes.refresh)
+        .bind(refresh_token)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        Ok(session::Session {
+            access_token,
+            refresh_token: new_refresh_token,
+            expires_in: lifetimes.access,
+        })
+    }
+
+    async fn delete_session(&self, token: String) -> Result<(), sqlx::Error> {
+        sqlx::query(r#"DELETE FROM sessions WHERE access_token = $1 OR refresh_token = $1"#)
+            .bind(token)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn query_collections(
+        &self,
+        user: String,
+    ) -> Result<collection::Collections, sqlx::Error> {
+        let rows = sqlx::query_as::<_, CollectionRow>(
+            r#"SELECT id, "user", name FROM collections
+            WHERE "user" = $1 OR id IN (SELECT collection FROM collection_shares WHERE "user" = $1)
+            ORDER BY LOWER(name), id"#,
+        )
+        .bind(user.clone())
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut collections = Vec::new();
+        for row in rows {
+            collections.push(self.collection_from_row(row, user.clone()).await?);
+        }
+
+        Ok(collection::Collections { collections })
+    }
+
+    async fn query_collection(
+        &self,
+        id: i64,
+        user: String,
+    ) -> Result<collection::Collection, sqlx::Error> {
+        let row = sqlx::query_as::<_, CollectionRow>(
+            r#"SELECT id, "user", name FROM collections
+            WHERE id = $1 AND ("user" = $2 OR id IN (
+            SELECT collection FROM collection_shares WHERE "user" = $2))"#,
+        )
+        .bind(id)
+        .bind(user.clone())
+        .fetch_one(&self.pool)
+        .await?;
+
+        self.collection_from_row(row, user).await
+    }
+
+    async fn insert_collection(&self, user: String, name: String) -> Result<i64, sqlx::Error> {
+        let row = sqlx::query_as::<_, IdRow>(
+            r#"INSERT INTO collections ("user", name) VALUES ($1, $2) RETURNING id"#,
+        )
+        .bind(user)
+        .bind(name)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.id)
+    }
+
+    async fn update_collection(&self, id: i64, name: String) -> Result<(), sqlx::Error> {
+        sqlx::query(r#"UPDATE collections SET name = $1 WHERE id = $2"#)
+            .bind(name)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_collection(&self, id: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(r#"DELETE FROM collections WHERE id = $1"#)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn insert_collection_audiobook(&self, id: i64, hash: String) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"INSERT INTO collection_audiobooks (collection, hash) VALUES ($1, $2)
+            ON CONFLICT DO NOTHING"#,
+        )
+        .bind(id)
+        .bind(hash)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_collection_audiobook(&self, id: i64, hash: String) -> Result<(), sqlx::Error> {
+        sqlx::query(r#"DELETE FROM collection_audiobooks WHERE collection = $1 AND hash = $2"#)
+            .bind(id)
+            .bind(hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn insert_collection_share(&self, id: i64, user: String) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"INSERT INTO collection_shares (collection, "user") VALUES ($1, $2)
+            ON CONFLICT DO NOTHING"#,
+        )
+        .bind(id)
+        .bind(user)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_collection_share(&self, id: i64, user: String) -> Result<(), sqlx::Error> {
+        sqlx::query(r#"DELETE FROM collection_shares WHERE collection = $1 AND "user" = $2"#)
+            .bind(id)
+            .bind(user)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn insert_invite(
+        &self,
+        uses: u32,
+        expires: Option<i64>,
+        created_by: String,
+    ) -> Result<invite::Invite, sqlx::Error> {
+        let code = account::generate_key();
+
+        sqlx::query(
+            r#"INSERT INTO invites (code, uses, expires, created_by)
+            VALUES ($1, $2, $3, $4)"#,
+        )
+        .bind(code.clone())
+        .bind(uses as i64)
+        .bind(expires)
+        .bind(created_by.clone())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(invite::Invite {
+            code,
+            uses: uses as i64,
+            expires,
+            created_by,
+        })
+    }
+
+    async fn query_invites(&self) -> Result<invite::Invites, sqlx::Error> {
+        let now = chrono::Utc::now().timestamp();
+
+        let rows = sqlx::query_as::<_, InviteRow>(
+            r#"SELECT code, uses, expires, created_by FROM invites
+            WHERE uses > 0 AND (expires IS NULL OR expires > $1)
+            ORDER BY expires IS NULL, expires"#,
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let invites = rows
+            .into_iter()
+            .map(|row| invite::Invite {
+                code: row.code,
+                uses: row.uses,
+                expires: row.expires,
+                created_by: row.created_by,
+            })
+            .collect();
+
+        Ok(invite::Invites { invites })
+    }
+
+    async fn delete_invite(&self, code: String) -> Result<(), sqlx::Error> {
+        let res = sqlx::query(r#"DELETE FROM invites WHERE code = $1"#)
+            .bind(code)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    async fn query_access(&self) -> Result<access::Access, sqlx::Error> {
+        let rules = sqlx::query_as::<_, AccessRuleRow>(
+            r#"SELECT id, subject_kind, subject, kind, value FROM access_rules ORDER BY id"#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let members = sqlx::query_as::<_, GroupMemberRow>(
+            r#"SELECT group_name, "user" FROM group_members ORDER BY group_name, "user""#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut groups: Vec<access::Group> = Vec::new();
+        for member in members {
+            match groups.last_mut() {
+                Some(group) if group.name == member.group_name => {
+                    group.members.push(member.user);
+                }
+                _ => {
+                    groups.push(access::Group {
+                        name: member.group_name,
+                        members: vec![member.user],
+                    });
+                }
+            };
+        }
+
+        Ok(access::Access {
+            rules: rules
+                .into_iter()
+                .map(|row| access::AccessRule {
+                    id: row.id,
+                    subject_kind: row.subject_kind,
+                    subject: row.subject,
+                    kind: row.kind,
+                    value: row.value,
+                })
+                .collect(),
+            groups,
+        })
+    }
+
+    async fn insert_access_rule(
+        &self,
+        rule: &access::NewAccessRule,
+    ) -> Result<access::AccessRule, sqlx::Error> {
+        let row = sqlx::query_as::<_, IdRow>(
+            r#"INSERT INTO access_rules (subject_kind, subject, kind, value)
+            VALUES ($1, $2, $3, $4) RETURNING id"#,
+        )
+        .bind(rule.subject_kind.clone())
+        .bind(rule.subject.clone())
+        .bind(rule.kind.clone())
+        .bind(rule.value.clone())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(access::AccessRule {
+            id: row.id,
+            subject_kind: rule.subject_kind.clone(),
+            subject: rule.subject.clone(),
+            kind: rule.kind.clone(),
+            value: rule.value.clone(),
+        })
+    }
+
+    async fn delete_access_rule(&self, id: i64) -> Result<(), sqlx::Error> {
+        let res = sqlx::query(r#"DELETE FROM access_rules WHERE id = $1"#)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    async fn insert_group_member(&self, group: String, user: String) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"INSERT INTO group_members (group_name, "user") VALUES ($1, $2)
+            ON CONFLICT DO NOTHING"#,
+        )
+        .bind(group)
+        .bind(user)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_group_member(&self, group: String, user: String) -> Result<(), sqlx::Error> {
+        sqlx::query(r#"DELETE FROM group_members WHERE group_name = $1 AND "user" = $2"#)
+            .bind(group)
+            .bind(user)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -3,19 +3,94 @@ extern crate rocket;
 use rocket_db_pools::{self, sqlx};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use toml;
 
+use lofty::AudioFile;
+
 // use chrono;
 
+use crate::models::access;
 use crate::models::account;
 use crate::models::audiobook;
+use crate::models::author;
+use crate::models::collection;
+use crate::models::export;
+use crate::models::invite;
 use crate::models::position;
+use crate::models::series;
+use crate::models::session;
 
 #[derive(sqlx::FromRow, Debug)]
 struct AudiobookFmtRow {
     hash: String,
     title: String,
     author: String,
+    narrator: Option<String>,
+    series: Option<String>,
+    series_index: Option<f64>,
+    genre: Option<String>,
+    language: Option<String>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AudiobookPageRow {
+    #[sqlx(flatten)]
+    audiobook: AudiobookFmtRow,
+    rank: f64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AuthorRow {
+    author: String,
+    count: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct SeriesRow {
+    series: String,
+    author: String,
+    count: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct CollectionRow {
+    id: i64,
+    user: String,
+    name: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct InviteRow {
+    code: String,
+    uses: i64,
+    expires: Option<i64>,
+    created_by: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AccessRuleRow {
+    id: i64,
+    subject_kind: String,
+    subject: String,
+    kind: String,
+    value: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct GroupMemberRow {
+    group_name: String,
+    user: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct HashRow {
+    hash: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct CountRow {
+    count: i64,
 }
 
 #[derive(sqlx::FromRow, Debug)]
@@ -23,10 +98,64 @@ struct AudiobookPathRow {
     path: String,
 }
 
+#[derive(sqlx::FromRow, Debug)]
+struct DurationRow {
+    duration: Option<f64>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct ScannedAudiobookRow {
+    title: String,
+    author: String,
+    path: String,
+    narrator: Option<String>,
+    description: Option<String>,
+    series: Option<String>,
+    series_index: Option<f64>,
+    genre: Option<String>,
+    language: Option<String>,
+    directory: Option<String>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct TagRow {
+    tag: String,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct FileRow {
+    file: String,
+    duration: Option<f64>,
+}
+
 #[derive(sqlx::FromRow, Debug)]
 struct PositionPathRow {
     file: String,
-    position: u32,
+    position_ms: i64,
+    speed: Option<f64>,
+    chapter: Option<u32>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct UserPositionRow {
+    title: String,
+    author: String,
+    file: String,
+    position_ms: i64,
+    speed: Option<f64>,
+    chapter: Option<u32>,
+    last_modified: Option<i64>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct IdRow {
+    id: i64,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AudiobookRefRow {
+    title: String,
+    author: String,
 }
 
 #[derive(sqlx::FromRow, Debug)]
@@ -34,126 +163,426 @@ struct UserRow {
     user: String,
 }
 
+#[derive(sqlx::FromRow, Debug)]
+struct AccountRow {
+    user: String,
+    is_admin: bool,
+    disabled: bool,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct KeyDisabledRow {
+    key: String,
+    disabled: bool,
+}
+
 #[derive(sqlx::FromRow, Debug)]
 struct KeyRow {
     key: String,
 }
 
-pub async fn scan_audiobooks(
-    dir: &PathBuf,
+/// Condition on the audiobooks table keeping the books a user is allowed to
+/// see, it has to be bound to the user `VISIBLE_BINDS` times. Administrators
+/// and users without any access rule see the whole library, other users only
+/// see the books matched by one of their rules or the rules of their groups.
+const VISIBLE: &str = r#"(EXISTS (SELECT 1 FROM accounts WHERE user = ? AND is_admin = 1)
+    OR NOT EXISTS (SELECT 1 FROM access_rules r WHERE
+        (r.subject_kind = 'user' AND r.subject = ?)
+        OR (r.subject_kind = 'group' AND r.subject IN (
+            SELECT group_name FROM group_members WHERE user = ?)))
+    OR EXISTS (SELECT 1 FROM access_rules r WHERE
+        ((r.subject_kind = 'user' AND r.subject = ?)
+        OR (r.subject_kind = 'group' AND r.subject IN (
+            SELECT group_name FROM group_members WHERE user = ?)))
+        AND ((r.kind = 'prefix' AND substr(audiobooks.directory, 1, length(r.value)) = r.value)
+        OR (r.kind = 'tag' AND EXISTS (SELECT 1 FROM audiobook_tags t
+            WHERE t.hash = audiobooks.hash AND t.tag = r.value))
+        OR (r.kind = 'grant' AND r.value = audiobooks.hash))))"#;
+const VISIBLE_BINDS: usize = 5;
+
+fn audiobook_fmt(row: AudiobookFmtRow) -> audiobook::AudiobookFmt {
+    audiobook::AudiobookFmt {
+        hash: row.hash,
+        title: row.title,
+        author: row.author,
+        narrator: row.narrator,
+        series: row.series,
+        series_index: row.series_index,
+        genre: row.genre,
+        language: row.language,
+    }
+}
+
+/// Builds a fts5 match expression out of a free text search, every word is
+/// quoted so the user cannot inject fts5 operators and matched as a prefix
+fn fts_expression(q: &str) -> Option<String> {
+    let terms: Vec<String> = q
+        .split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        return None;
+    }
+    Some(terms.join(" "))
+}
+
+/// sql of a key the audiobooks are sorted by
+fn sort_expression(key: audiobook::SortKey) -> &'static str {
+    match key {
+        audiobook::SortKey::Rank => "bm25(audiobooks_fts)",
+        audiobook::SortKey::Title => "audiobooks.title COLLATE NOCASE",
+        audiobook::SortKey::Author => "audiobooks.author COLLATE NOCASE",
+        audiobook::SortKey::NoSeries => "(audiobooks.series IS NULL)",
+        audiobook::SortKey::Series => "COALESCE(audiobooks.series, '') COLLATE NOCASE",
+        audiobook::SortKey::SeriesIndex => "COALESCE(audiobooks.series_index, 0)",
+        audiobook::SortKey::Hash => "audiobooks.hash",
+    }
+}
+
+/// queries a page of audiobooks matching a search
+pub async fn query_audiobooks(
+    search: &audiobook::AudiobookSearch,
+    user: String,
     pool: &sqlx::Pool<sqlx::Sqlite>,
-) -> Result<(), sqlx::Error> {
-    match sqlx::query(
-        r#"CREATE TABLE IF NOT EXISTS audiobooks (
-    hash TEXT PRIMARY KEY,
-    title TEXT,
-    author TEXT,
-    path TEXT)"#,
-    )
-    .execute(pool)
-    .await
-    {
-        Ok(_) => {}
-        Err(err) => {
-            return Err(err);
+) -> Result<audiobook::Audiobooks, sqlx::Error> {
+    let mut from = String::from("audiobooks");
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut binds: Vec<String> = Vec::new();
+
+    let fts = search.q.as_deref().and_then(fts_expression);
+    if let Some(fts) = fts.clone() {
+        from.push_str(" JOIN audiobooks_fts ON audiobooks_fts.hash = audiobooks.hash");
+        conditions.push("audiobooks_fts MATCH ?");
+        binds.push(fts);
+    }
+
+    if let Some(author) = search.author.clone() {
+        conditions.push("audiobooks.author = ? COLLATE NOCASE");
+        binds.push(author);
+    }
+
+    if let Some(series) = search.series.clone() {
+        conditions.push("audiobooks.series = ? COLLATE NOCASE");
+        binds.push(series);
+    }
+
+    if let Some(genre) = search.genre.clone() {
+        conditions.push("audiobooks.genre = ? COLLATE NOCASE");
+        binds.push(genre);
+    }
+
+    if let Some(language) = search.language.clone() {
+        conditions.push("audiobooks.language = ? COLLATE NOCASE");
+        binds.push(language);
+    }
+
+    match search.status {
+        Some(audiobook::AudiobookStatus::New) => {
+            conditions
+                .push("audiobooks.hash NOT IN (SELECT hash FROM positions WHERE user = ?)");
+            binds.push(user.clone());
+        }
+        Some(audiobook::AudiobookStatus::Started) => {
+            conditions.push("audiobooks.hash IN (SELECT hash FROM positions WHERE user = ?)");
+            binds.push(user.clone());
         }
+        None => {}
     };
 
-    let audiobooks = scan_audiobook_direcories(Path::new(dir));
+    if let Some(collection) = search.collection {
+        conditions.push(
+            "audiobooks.hash IN (SELECT hash FROM collection_audiobooks WHERE collection IN (
+            SELECT id FROM collections WHERE id = ? AND (user = ? OR id IN (
+            SELECT collection FROM collection_shares WHERE user = ?))))",
+        );
+        binds.push(collection.to_string());
+        binds.push(user.clone());
+        binds.push(user.clone());
+    }
 
-    for audiobook in audiobooks {
-        match insert_audiobook(audiobook, pool).await {
-            Ok(_) => {}
-            Err(err) => {
-                return Err(err);
+    conditions.push(VISIBLE);
+    for _ in 0..VISIBLE_BINDS {
+        binds.push(user.clone());
+    }
+
+    let filter = format!(" WHERE {}", conditions.join(" AND "));
+
+    let keys = search.sort.keys(fts.is_some());
+    let order: Vec<String> = keys
+        .iter()
+        .map(|(key, desc)| {
+            format!(
+                "{} {}",
+                sort_expression(*key),
+                if *desc { "DESC" } else { "ASC" }
+            )
+        })
+        .collect();
+    let rank = if fts.is_some() {
+        sort_expression(audiobook::SortKey::Rank)
+    } else {
+        "0.0"
+    };
+
+    // the books sorted after the cursor, key by key: a greater first key, or
+    // the same first key and a greater second one, and so on
+    let mut after = String::new();
+    let mut after_binds = Vec::new();
+    if let Some(cursor) = &search.after {
+        let mut terms = Vec::new();
+        for (i, (key, desc)) in keys.iter().enumerate() {
+            let mut parts = Vec::new();
+            for (previous, _) in keys[..i].iter() {
+                parts.push(format!("{} = ?", sort_expression(*previous)));
+                after_binds.push(cursor.value(*previous));
             }
+            parts.push(format!(
+                "{} {} ?",
+                sort_expression(*key),
+                if *desc { "<" } else { ">" }
+            ));
+            after_binds.push(cursor.value(*key));
+            terms.push(format!("({})", parts.join(" AND ")));
+        }
+        after = format!(" AND ({})", terms.join(" OR "));
+    }
+
+    let count_sql = format!("SELECT COUNT(*) AS count FROM {}{}", from, filter);
+    let mut count_query = sqlx::query_as::<_, CountRow>(&count_sql);
+    for bind in binds.iter() {
+        count_query = count_query.bind(bind.clone());
+    }
+    let total = count_query.fetch_one(pool).await?.count;
+
+    // one more book than asked for tells whether there is a next page
+    let rows_sql = format!(
+        r#"SELECT audiobooks.hash, audiobooks.title, audiobooks.author, audiobooks.narrator,
+        audiobooks.series, audiobooks.series_index, audiobooks.genre, audiobooks.language,
+        {} AS rank
+        FROM {}{}{} ORDER BY {} LIMIT {}"#,
+        rank,
+        from,
+        filter,
+        after,
+        order.join(", "),
+        search.limit + 1
+    );
+    let mut rows_query = sqlx::query_as::<_, AudiobookPageRow>(&rows_sql);
+    for bind in binds.iter() {
+        rows_query = rows_query.bind(bind.clone());
+    }
+    for bind in after_binds {
+        rows_query = match bind {
+            audiobook::SortValue::Text(text) => rows_query.bind(text),
+            audiobook::SortValue::Real(real) => rows_query.bind(real),
         };
     }
+    let mut rows = rows_query.fetch_all(pool).await?;
 
-    Ok(())
-}
+    let more = rows.len() as i64 > search.limit;
+    rows.truncate(search.limit as usize);
+    let last_rank = rows.last().map(|row| row.rank);
 
-pub async fn create_positions(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
-    match sqlx::query(
-        r#"CREATE TABLE IF NOT EXISTS positions (
-        hash TEXT,
-        user TEXT,
-        file TEXT,
-        position NUMBER, 
-        last_modified DATE)"#,
-    )
-    .execute(pool)
-    .await
-    {
-        Ok(_) => {}
-        Err(err) => {
-            return Err(err);
+    let audiobooks: Vec<audiobook::AudiobookFmt> = rows
+        .into_iter()
+        .map(|row| audiobook_fmt(row.audiobook))
+        .collect();
+
+    let next = match (more, audiobooks.last(), last_rank) {
+        (true, Some(last), Some(rank)) => {
+            Some(audiobook::AudiobookCursor::new(last, rank).encode())
         }
+        _ => None,
+    };
+
+    Ok(audiobook::Audiobooks {
+        audiobooks,
+        total,
+        next,
+    })
+}
+
+/// queries every author of the library along with their number of audiobooks
+pub async fn query_authors(
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<author::Authors, sqlx::Error> {
+    let sql = format!(
+        r#"SELECT author, COUNT(*) AS count FROM audiobooks WHERE {}
+        GROUP BY author ORDER BY author COLLATE NOCASE"#,
+        VISIBLE
+    );
+    let mut query = sqlx::query_as::<_, AuthorRow>(&sql);
+    for _ in 0..VISIBLE_BINDS {
+        query = query.bind(user.clone());
     }
+    let rows = query.fetch_all(pool).await?;
 
-    Ok(())
+    let authors = rows
+        .into_iter()
+        .map(|row| author::AuthorFmt {
+            id: author::compute_id(row.author.clone()),
+            name: row.author,
+            count: row.count,
+        })
+        .collect();
+
+    Ok(author::Authors { authors })
 }
 
-pub async fn create_accounts(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
-    match sqlx::query(
-        r#"CREATE TABLE IF NOT EXISTS accounts (
-        user TEXT PRIMARY KEY,
-        password TEXT,
-        key TEXT)"#,
-    )
-    .execute(pool)
-    .await
-    {
-        Ok(_) => {}
-        Err(err) => {
-            return Err(err);
+/// queries the audiobooks of an author, books of a same series are grouped
+/// together and ordered by their index in the series
+pub async fn query_author_audiobooks(
+    id: String,
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<author::AuthorAudiobooks, sqlx::Error> {
+    let authors = query_authors(user.clone(), pool).await?;
+    let name = match authors.authors.into_iter().find(|author| author.id == id) {
+        Some(author) => author.name,
+        None => {
+            return Err(sqlx::Error::RowNotFound);
         }
+    };
+
+    let sql = format!(
+        r#"SELECT hash, title, author, narrator, series, series_index, genre, language
+        FROM audiobooks WHERE author = ? AND {}
+        ORDER BY series IS NULL, series COLLATE NOCASE, series_index, title COLLATE NOCASE"#,
+        VISIBLE
+    );
+    let mut query = sqlx::query_as::<_, AudiobookFmtRow>(&sql).bind(name.clone());
+    for _ in 0..VISIBLE_BINDS {
+        query = query.bind(user.clone());
     }
+    let rows = query.fetch_all(pool).await?;
 
-    Ok(())
+    Ok(author::AuthorAudiobooks {
+        id,
+        name,
+        audiobooks: rows.into_iter().map(audiobook_fmt).collect(),
+    })
 }
 
-/// queries a list of all audiobooks
-pub async fn query_audiobooks(
+/// queries every series of the library along with their number of audiobooks
+pub async fn query_series(
+    user: String,
     pool: &sqlx::Pool<sqlx::Sqlite>,
-) -> Result<audiobook::Audiobooks, sqlx::Error> {
-    let rows =
-        sqlx::query_as::<_, AudiobookFmtRow>(r#"SELECT hash, title, author FROM audiobooks"#)
-            .fetch_all(pool)
-            .await?;
+) -> Result<series::Series, sqlx::Error> {
+    let sql = format!(
+        r#"SELECT series, author, COUNT(*) AS count FROM audiobooks
+        WHERE series IS NOT NULL AND {}
+        GROUP BY series, author ORDER BY series COLLATE NOCASE, author COLLATE NOCASE"#,
+        VISIBLE
+    );
+    let mut query = sqlx::query_as::<_, SeriesRow>(&sql);
+    for _ in 0..VISIBLE_BINDS {
+        query = query.bind(user.clone());
+    }
+    let rows = query.fetch_all(pool).await?;
 
-    let audiobooks: Vec<audiobook::AudiobookFmt> = rows
+    let series = rows
         .into_iter()
-        .map(|row| audiobook::AudiobookFmt {
-            hash: row.hash,
-            title: row.title,
+        .map(|row| series::SeriesFmt {
+            id: series::compute_id(row.series.clone(), row.author.clone()),
+            name: row.series,
             author: row.author,
+            count: row.count,
         })
         .collect();
 
-    Ok(audiobook::Audiobooks {
-        audiobooks: audiobooks,
+    Ok(series::Series { series })
+}
+
+/// queries the audiobooks of a series ordered by their index in the series
+pub async fn query_series_audiobooks(
+    id: String,
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<series::SeriesAudiobooks, sqlx::Error> {
+    let series = query_series(user.clone(), pool).await?;
+    let (name, author) = match series.series.into_iter().find(|series| series.id == id) {
+        Some(series) => (series.name, series.author),
+        None => {
+            return Err(sqlx::Error::RowNotFound);
+        }
+    };
+
+    let sql = format!(
+        r#"SELECT hash, title, author, narrator, series, series_index, genre, language
+        FROM audiobooks WHERE series = ? AND author = ? AND {}
+        ORDER BY series_index IS NULL, series_index, title COLLATE NOCASE"#,
+        VISIBLE
+    );
+    let mut query = sqlx::query_as::<_, AudiobookFmtRow>(&sql)
+        .bind(name.clone())
+        .bind(author.clone());
+    for _ in 0..VISIBLE_BINDS {
+        query = query.bind(user.clone());
+    }
+    let rows = query.fetch_all(pool).await?;
+
+    Ok(series::SeriesAudiobooks {
+        id,
+        name,
+        author,
+        audiobooks: rows.into_iter().map(audiobook_fmt).collect(),
     })
 }
 
+/// queries the path of an audiobook, books the user is not allowed to see
+/// are reported as not found
 pub async fn query_audiobook(
     hash: String,
+    user: String,
     pool: &sqlx::Pool<sqlx::Sqlite>,
 ) -> Result<String, sqlx::Error> {
-    let row =
-        sqlx::query_as::<_, AudiobookPathRow>(r#"SELECT path FROM audiobooks WHERE hash = ?"#)
-            .bind(hash)
-            .fetch_one(pool)
-            .await?;
+    let sql = format!(
+        r#"SELECT path FROM audiobooks WHERE hash = ? AND {}"#,
+        VISIBLE
+    );
+    let mut query = sqlx::query_as::<_, AudiobookPathRow>(&sql).bind(hash);
+    for _ in 0..VISIBLE_BINDS {
+        query = query.bind(user.clone());
+    }
+    let row = query.fetch_one(pool).await?;
 
     Ok(row.path)
 }
 
-pub async fn create_pool(address: PathBuf) -> sqlx::Pool<sqlx::Sqlite> {
-    match sqlx::SqlitePool::connect_with(
-        sqlx::sqlite::SqliteConnectOptions::new().filename(address),
-    )
-    .await
+/// Settings of the connection pool to the sqlite database
+#[derive(Clone, Copy)]
+pub struct PoolSettings {
+    pub max_connections: u32,
+    /// How long a connection waits for a lock held by another one
+    pub busy_timeout: Duration,
+}
+
+/// Opens the database, creating it and its directory if needed
+pub async fn create_pool(address: PathBuf, settings: PoolSettings) -> sqlx::Pool<sqlx::Sqlite> {
+    if let Some(parent) = address.parent() {
+        if !parent.as_os_str().is_empty() {
+            if let Err(err) = fs::create_dir_all(parent) {
+                eprintln!("Could not create '{}': {}", parent.display(), err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // wal lets readers go on while the scanner or a client writes, with it a
+    // normal synchronous mode is still safe against corruption
+    let options = sqlx::sqlite::SqliteConnectOptions::new()
+        .filename(address)
+        .create_if_missing(true)
+        .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
+        .synchronous(sqlx::sqlite::SqliteSynchronous::Normal)
+        .busy_timeout(settings.busy_timeout)
+        .foreign_keys(true);
+
+    match sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(settings.max_connections)
+        .connect_with(options)
+        .await
     {
         Ok(pool) => pool,
         Err(err) => {
@@ -163,24 +592,62 @@ pub async fn create_pool(address: PathBuf) -> sqlx::Pool<sqlx::Sqlite> {
     }
 }
 
+/// opens a database that must already exist, without creating it or changing
+/// its journal mode, for the commands that are not supposed to write to it
+pub async fn open_pool(
+    address: &Path,
+    read_only: bool,
+) -> Result<sqlx::Pool<sqlx::Sqlite>, sqlx::Error> {
+    let options = sqlx::sqlite::SqliteConnectOptions::new()
+        .filename(address)
+        .read_only(read_only);
+
+    sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+}
+
+/// inserts or updates an audiobook, the row is updated in place rather than
+/// replaced so the positions and collections referencing it are kept
 pub async fn insert_audiobook(
     audiobook: audiobook::Audiobook,
     pool: &sqlx::Pool<sqlx::Sqlite>,
 ) -> Result<(), sqlx::Error> {
     let hash = audiobook::compute_hash(audiobook.title.clone(), audiobook.author.clone());
-    let title = audiobook.title;
-    let author = audiobook.author;
-    let path = audiobook.path;
+
+    // the tags, files and search row are rewritten along with the book, so a
+    // failure halfway must not leave it without them
+    let mut tx = pool.begin().await?;
 
     match sqlx::query(
-        r#"INSERT OR REPLACE INTO audiobooks (hash, title, author, path)
-        VALUES (?, ?, ?, ?)"#,
+        r#"INSERT INTO audiobooks (hash, title, author, path, narrator,
+        description, series, series_index, genre, language, directory)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (hash) DO UPDATE SET
+        title = excluded.title,
+        author = excluded.author,
+        path = excluded.path,
+        narrator = excluded.narrator,
+        description = excluded.description,
+        series = excluded.series,
+        series_index = excluded.series_index,
+        genre = excluded.genre,
+        language = excluded.language,
+        directory = excluded.directory"#,
     )
-    .bind(hash)
-    .bind(title)
-    .bind(author)
-    .bind(path)
-    .execute(pool)
+    .bind(hash.clone())
+    .bind(audiobook.title.clone())
+    .bind(audiobook.author.clone())
+    .bind(audiobook.path)
+    .bind(audiobook.narrator.clone())
+    .bind(audiobook.description.clone())
+    .bind(audiobook.series)
+    .bind(audiobook.series_index)
+    .bind(audiobook.genre)
+    .bind(audiobook.language)
+    .bind(audiobook.directory)
+    .execute(&mut tx)
     .await
     {
         Ok(_) => {}
@@ -189,52 +656,81 @@ pub async fn insert_audiobook(
         }
     };
 
-    Ok(())
-}
+    match sqlx::query(r#"DELETE FROM audiobook_tags WHERE hash = ?"#)
+        .bind(hash.clone())
+        .execute(&mut tx)
+        .await
+    {
+        Ok(_) => {}
+        Err(err) => {
+            return Err(err);
+        }
+    };
 
-pub async fn insert_position(
-    hash: String,
-    user: String,
-    file: String,
-    position: u32,
-    pool: &sqlx::Pool<sqlx::Sqlite>,
-) -> Result<(), sqlx::Error> {
-    // updating positions for the ones that already exist
+    for tag in audiobook.tags {
+        match sqlx::query(r#"INSERT OR IGNORE INTO audiobook_tags (hash, tag) VALUES (?, ?)"#)
+            .bind(hash.clone())
+            .bind(tag)
+            .execute(&mut tx)
+            .await
+        {
+            Ok(_) => {}
+            Err(err) => {
+                return Err(err);
+            }
+        };
+    }
+
+    match sqlx::query(r#"DELETE FROM audiobook_files WHERE hash = ?"#)
+        .bind(hash.clone())
+        .execute(&mut tx)
+        .await
+    {
+        Ok(_) => {}
+        Err(err) => {
+            return Err(err);
+        }
+    };
 
-    // let now = chrono::Local::now();
-    // let iso_date_time = now.to_rcf3339();
+    for file in audiobook.files {
+        match sqlx::query(
+            r#"INSERT OR REPLACE INTO audiobook_files (hash, file, duration) VALUES (?, ?, ?)"#,
+        )
+        .bind(hash.clone())
+        .bind(file.name)
+        .bind(file.duration)
+        .execute(&mut tx)
+        .await
+        {
+            Ok(_) => {}
+            Err(err) => {
+                return Err(err);
+            }
+        };
+    }
 
-    match sqlx::query(
-        r#"UPDATE positions
-        SET file = ?, position = ?
-        WHERE hash = ? AND user = ?"#,
-    )
-    .bind(file.clone())
-    .bind(position.clone())
-    // .bind(iso_date_time)
-    .bind(hash.clone())
-    .bind(user.clone())
-    .execute(pool)
-    .await
+    // keeping the search index in sync with the audiobooks table
+    match sqlx::query(r#"DELETE FROM audiobooks_fts WHERE hash = ?"#)
+        .bind(hash.clone())
+        .execute(&mut tx)
+        .await
     {
         Ok(_) => {}
         Err(err) => {
             return Err(err);
         }
     };
+
     match sqlx::query(
-        r#"INSERT INTO positions (hash, user, file, position)
-        SELECT ?, ?, ?, ?
-        WHERE NOT EXISTS (
-        SELECT 1 FROM positions WHERE hash = ? AND user = ?)"#,
+        r#"INSERT INTO audiobooks_fts (hash, title, author, narrator, description)
+        VALUES (?, ?, ?, ?, ?)"#,
     )
-    .bind(hash.clone())
-    .bind(user.clone())
-    .bind(file.clone())
-    .bind(position.clone())
-    .bind(hash.clone())
-    .bind(user.clone())
-    .execute(pool)
+    .bind(hash)
+    .bind(audiobook.title)
+    .bind(audiobook.author)
+    .bind(audiobook.narrator)
+    .bind(audiobook.description)
+    .execute(&mut tx)
     .await
     {
         Ok(_) => {}
@@ -242,35 +738,351 @@ pub async fn insert_position(
             return Err(err);
         }
     };
-    // add new rows when a user has never had information about a book
+
+    tx.commit().await?;
+
     Ok(())
 }
-pub async fn select_position(
+
+/// queries an audiobook the way the scanner last saw it, tags and files sorted
+/// by name, `None` when it is not in the library
+pub async fn query_scanned_audiobook(
     hash: String,
-    user: String,
     pool: &sqlx::Pool<sqlx::Sqlite>,
-) -> Result<position::Position, sqlx::Error> {
-    let rows = match sqlx::query_as::<_, PositionPathRow>(
-        r#"SELECT file, position FROM positions
-        WHERE hash = ? AND user = ?"#,
+) -> Result<Option<audiobook::Audiobook>, sqlx::Error> {
+    let row = sqlx::query_as::<_, ScannedAudiobookRow>(
+        r#"SELECT title, author, path, narrator, description, series, series_index,
+        genre, language, directory
+        FROM audiobooks WHERE hash = ?"#,
     )
-    .bind(hash)
-    .bind(user)
+    .bind(hash.clone())
+    .fetch_optional(pool)
+    .await?;
+
+    let row = match row {
+        Some(row) => row,
+        None => {
+            return Ok(None);
+        }
+    };
+
+    let tags = sqlx::query_as::<_, TagRow>(
+        r#"SELECT tag FROM audiobook_tags WHERE hash = ? ORDER BY tag"#,
+    )
+    .bind(hash.clone())
+    .fetch_all(pool)
+    .await?;
+
+    let files = sqlx::query_as::<_, FileRow>(
+        r#"SELECT file, duration FROM audiobook_files WHERE hash = ? ORDER BY file"#,
+    )
+    .bind(hash)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(Some(audiobook::Audiobook {
+        title: row.title,
+        author: row.author,
+        path: row.path,
+        narrator: row.narrator,
+        description: row.description,
+        series: row.series,
+        series_index: row.series_index,
+        genre: row.genre,
+        language: row.language,
+        tags: tags.into_iter().map(|row| row.tag).collect(),
+        directory: row.directory.unwrap_or_default(),
+        files: files
+            .into_iter()
+            .map(|row| audiobook::AudiobookFile {
+                name: row.file,
+                duration: row.duration,
+            })
+            .collect(),
+    }))
+}
+
+/// queries the duration of one of the files of an audiobook, RowNotFound
+/// when the book has no such file
+pub async fn query_audiobook_file(
+    hash: String,
+    file: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<Option<f64>, sqlx::Error> {
+    let row = sqlx::query_as::<_, DurationRow>(
+        r#"SELECT duration FROM audiobook_files WHERE hash = ? AND file = ?"#,
+    )
+    .bind(hash)
+    .bind(file)
     .fetch_one(pool)
+    .await?;
+
+    Ok(row.duration)
+}
+
+pub async fn insert_position(
+    hash: String,
+    user: String,
+    file: String,
+    position_ms: u64,
+    speed: Option<f64>,
+    chapter: Option<u32>,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    // sqlite integers are signed, no book is anywhere near that long anyway
+    let position_ms = position_ms.min(i64::MAX as u64) as i64;
+    // the legacy column in seconds is kept up to date for older versions
+    let position = position_ms / 1000;
+
+    match sqlx::query(
+        r#"INSERT INTO positions (hash, user, file, position, position_ms, speed, chapter, last_modified)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (hash, user) DO UPDATE SET
+        file = excluded.file,
+        position = excluded.position,
+        position_ms = excluded.position_ms,
+        speed = excluded.speed,
+        chapter = excluded.chapter,
+        last_modified = excluded.last_modified"#,
+    )
+    .bind(hash)
+    .bind(user)
+    .bind(file)
+    .bind(position)
+    .bind(position_ms)
+    .bind(speed)
+    .bind(chapter)
+    .bind(chrono::Utc::now().timestamp())
+    .execute(pool)
     .await
     {
-        Ok(rows) => rows,
+        Ok(_) => {}
         Err(err) => {
             return Err(err);
         }
     };
+    Ok(())
+}
 
-    let position = position::Position {
-        file: rows.file,
-        position: rows.position,
+/// selects the position of a user in a book, `None` when they never started it
+pub async fn select_position(
+    hash: String,
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<Option<position::Position>, sqlx::Error> {
+    let row = match sqlx::query_as::<_, PositionPathRow>(
+        r#"SELECT file,
+        CAST(COALESCE(position_ms, position * 1000) AS INTEGER) AS position_ms,
+        speed, chapter
+        FROM positions
+        WHERE hash = ? AND user = ?"#,
+    )
+    .bind(hash)
+    .bind(user)
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(row) => row,
+        Err(err) => {
+            return Err(err);
+        }
     };
 
-    Ok(position)
+    Ok(row.map(|row| {
+        position::Position::new(
+            row.file,
+            row.position_ms.max(0) as u64,
+            row.speed,
+            row.chapter,
+        )
+    }))
+}
+
+/// selects every position of a user along with the book it is in
+pub async fn select_positions(
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<Vec<export::ExportPosition>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, UserPositionRow>(
+        r#"SELECT audiobooks.title, audiobooks.author, positions.file,
+        CAST(COALESCE(positions.position_ms, positions.position * 1000) AS INTEGER) AS position_ms,
+        positions.speed, positions.chapter,
+        CAST(positions.last_modified AS INTEGER) AS last_modified
+        FROM positions JOIN audiobooks ON audiobooks.hash = positions.hash
+        WHERE positions.user = ?
+        ORDER BY audiobooks.title COLLATE NOCASE, audiobooks.author COLLATE NOCASE"#,
+    )
+    .bind(user)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| export::ExportPosition {
+            audiobook: export::AudiobookRef {
+                title: row.title,
+                author: row.author,
+            },
+            file: row.file,
+            position_ms: row.position_ms.max(0) as u64,
+            speed: row.speed,
+            chapter: row.chapter,
+            last_modified: row.last_modified,
+        })
+        .collect())
+}
+
+/// writes what an import found in the library in a single transaction, so a
+/// failure leaves the account as it was. gives the number of positions saved
+/// and of collections something was added to
+pub async fn insert_import(
+    user: String,
+    account: Option<&export::ExportAccount>,
+    positions: &[export::ImportPosition],
+    collections: &[export::ImportCollection],
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(usize, usize), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    if let Some(account) = account {
+        let res = sqlx::query(r#"UPDATE accounts SET is_admin = ?, disabled = ? WHERE user = ?"#)
+            .bind(account.is_admin)
+            .bind(account.disabled)
+            .bind(user.clone())
+            .execute(&mut tx)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+    }
+
+    let mut saved = 0;
+    for position in positions.iter() {
+        let position_ms = position.position_ms.min(i64::MAX as u64) as i64;
+
+        // a position saved here after the one of the export is kept, and an
+        // undated one from an older export never replaces a dated one
+        let res = sqlx::query(
+            r#"INSERT INTO positions (hash, user, file, position, position_ms, speed, chapter, last_modified)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (hash, user) DO UPDATE SET
+            file = excluded.file,
+            position = excluded.position,
+            position_ms = excluded.position_ms,
+            speed = excluded.speed,
+            chapter = excluded.chapter,
+            last_modified = excluded.last_modified
+            WHERE positions.last_modified IS NULL
+            OR positions.last_modified < excluded.last_modified"#,
+        )
+        .bind(position.hash.clone())
+        .bind(user.clone())
+        .bind(position.file.clone())
+        .bind(position_ms / 1000)
+        .bind(position_ms)
+        .bind(position.speed)
+        .bind(position.chapter)
+        .bind(position.last_modified)
+        .execute(&mut tx)
+        .await?;
+
+        saved += res.rows_affected() as usize;
+    }
+
+    let mut changed = 0;
+    for collection in collections.iter() {
+        let existing = sqlx::query_as::<_, IdRow>(
+            r#"SELECT id FROM collections WHERE user = ? AND name = ? ORDER BY id LIMIT 1"#,
+        )
+        .bind(user.clone())
+        .bind(collection.name.clone())
+        .fetch_optional(&mut tx)
+        .await?;
+
+        let (id, mut added) = match existing {
+            Some(row) => (row.id, false),
+            None => {
+                let res = sqlx::query(r#"INSERT INTO collections (user, name) VALUES (?, ?)"#)
+                    .bind(user.clone())
+                    .bind(collection.name.clone())
+                    .execute(&mut tx)
+                    .await?;
+                (res.last_insert_rowid(), true)
+            }
+        };
+
+        for hash in collection.audiobooks.iter() {
+            let res = sqlx::query(
+                r#"INSERT OR IGNORE INTO collection_audiobooks (collection, hash) VALUES (?, ?)"#,
+            )
+            .bind(id)
+            .bind(hash.clone())
+            .execute(&mut tx)
+            .await?;
+            added |= res.rows_affected() > 0;
+        }
+
+        for share in collection.shared_with.iter() {
+            let res = sqlx::query(
+                r#"INSERT OR IGNORE INTO collection_shares (collection, user) VALUES (?, ?)"#,
+            )
+            .bind(id)
+            .bind(share.clone())
+            .execute(&mut tx)
+            .await?;
+            added |= res.rows_affected() > 0;
+        }
+
+        if added {
+            changed += 1;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok((saved, changed))
+}
+
+/// queries the title and author of an audiobook
+pub async fn query_audiobook_ref(
+    hash: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<export::AudiobookRef, sqlx::Error> {
+    let row = sqlx::query_as::<_, AudiobookRefRow>(
+        r#"SELECT title, author FROM audiobooks WHERE hash = ?"#,
+    )
+    .bind(hash)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(export::AudiobookRef {
+        title: row.title,
+        author: row.author,
+    })
+}
+
+/// finds the hash of an audiobook the user can see from its title and author,
+/// ignoring their case
+pub async fn query_audiobook_hash(
+    title: String,
+    author: String,
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<String, sqlx::Error> {
+    let sql = format!(
+        r#"SELECT hash FROM audiobooks
+        WHERE title = ? COLLATE NOCASE AND author = ? COLLATE NOCASE AND {}
+        ORDER BY hash LIMIT 1"#,
+        VISIBLE
+    );
+    let mut query = sqlx::query_as::<_, HashRow>(&sql).bind(title).bind(author);
+    for _ in 0..VISIBLE_BINDS {
+        query = query.bind(user.clone());
+    }
+    let row = query.fetch_one(pool).await?;
+
+    Ok(row.hash)
 }
 
 pub async fn insert_user(
@@ -299,6 +1111,70 @@ pub async fn insert_user(
     Ok(key)
 }
 
+/// creates an account for an administrator, with its admin flag set at once
+pub async fn insert_admin_user(
+    user: String,
+    password: String,
+    is_admin: bool,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<String, sqlx::Error> {
+    let key = account::generate_key();
+
+    sqlx::query(
+        r#"INSERT INTO accounts (user, password, key, is_admin)
+        VALUES (?, ?, ?, ?)"#,
+    )
+    .bind(user)
+    .bind(password)
+    .bind(key.clone())
+    .bind(is_admin)
+    .execute(pool)
+    .await?;
+
+    Ok(key)
+}
+
+/// registers a user with an invite code, the code is only consumed if the
+/// account could be created
+pub async fn insert_user_with_invite(
+    user: String,
+    password: String,
+    code: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<String, sqlx::Error> {
+    let key = account::generate_key();
+    let now = chrono::Utc::now().timestamp();
+
+    let mut tx = pool.begin().await?;
+
+    let res = sqlx::query(
+        r#"UPDATE invites SET uses = uses - 1
+        WHERE code = ? AND uses > 0 AND (expires IS NULL OR expires > ?)"#,
+    )
+    .bind(code)
+    .bind(now)
+    .execute(&mut tx)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    sqlx::query(
+        r#"INSERT INTO accounts (user, password, key)
+        VALUES (?, ?, ?)"#,
+    )
+    .bind(user)
+    .bind(password)
+    .bind(key.clone())
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(key)
+}
+
 pub async fn select_user(
     user: String,
     password: String,
@@ -306,7 +1182,7 @@ pub async fn select_user(
 ) -> Result<String, sqlx::Error> {
     let row = match sqlx::query_as::<_, KeyRow>(
         r#"SELECT key FROM accounts
-        WHERE user = ? AND password = ?"#,
+        WHERE user = ? AND password = ? AND disabled = 0"#,
     )
     .bind(user)
     .bind(password)
@@ -327,9 +1203,12 @@ pub async fn query_user(
 ) -> Result<String, sqlx::Error> {
     let row = match sqlx::query_as::<_, UserRow>(
         r#"SELECT user FROM accounts
-        WHERE key = ?"#,
+        WHERE disabled = 0 AND (key = ? OR user IN (
+        SELECT user FROM sessions WHERE access_token = ? AND access_expires > ?))"#,
     )
+    .bind(key.clone())
     .bind(key)
+    .bind(chrono::Utc::now().timestamp())
     .fetch_one(pool)
     .await
     {
@@ -342,58 +1221,1614 @@ pub async fn query_user(
     Ok(row.user)
 }
 
-pub fn scan_audiobook_direcories(dir: &Path) -> Vec<audiobook::Audiobook> {
-    let mut audiobooks = Vec::new();
+/// queries the api key of a user authenticated by a reverse proxy, creating
+/// their account the first time they are seen unless another account has that
+/// name in any case
+pub async fn query_or_insert_user_key(
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<String, sqlx::Error> {
+    if user.is_empty() {
+        return Err(sqlx::Error::RowNotFound);
+    }
 
-    if let Ok(entries) = std::fs::read_dir(dir) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                let dir_path = entry.path();
-                if dir_path.is_dir() {
-                    if let Some(audiobook) = scan_audiobook_directory(&dir_path) {
-                        audiobooks.push(audiobook);
-                    }
-                }
-            }
-        }
+    // a single statement, so two first requests at once can not both insert.
+    // nobody knows the password of these accounts, they can only be reached
+    // through the proxy
+    sqlx::query(
+        r#"INSERT INTO accounts (user, password, key, auth_source)
+        SELECT ?, ?, ?, 'proxy'
+        WHERE NOT EXISTS (SELECT 1 FROM accounts WHERE user = ? COLLATE NOCASE)
+        ON CONFLICT DO NOTHING"#,
+    )
+    .bind(user.clone())
+    .bind(account::generate_key())
+    .bind(account::generate_key())
+    .bind(user.clone())
+    .execute(pool)
+    .await?;
+
+    let row = sqlx::query_as::<_, KeyDisabledRow>(
+        r#"SELECT key, disabled FROM accounts WHERE user = ?"#,
+    )
+    .bind(user)
+    .fetch_one(pool)
+    .await?;
+
+    if row.disabled {
+        return Err(sqlx::Error::RowNotFound);
     }
-    audiobooks
+    Ok(row.key)
 }
 
-pub fn scan_audiobook_directory(path: &PathBuf) -> Option<audiobook::Audiobook> {
-    let info_file_path = path.join("info.toml");
-    let metadata_str = fs::read_to_string(&info_file_path).ok()?;
-    let metadata: toml::Value = metadata_str.parse().ok()?;
-    let title = metadata.get("title").and_then(|v| v.as_str())?.to_owned();
-    let author = metadata.get("author").and_then(|v| v.as_str())?.to_owned();
-    let path = path.to_string_lossy().to_string();
+/// queries the key of an account created from ldap, creating it on the first
+/// login unless an account of another origin has that name in any case
+pub async fn query_or_insert_ldap_key(
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<String, sqlx::Error> {
+    if user.is_empty() {
+        return Err(sqlx::Error::RowNotFound);
+    }
 
-    // let cover_path = ["cover.jpg", "cover.jpeg", "cover.png"]
-    //     .iter()
-    //     .map(|name| path.join(name))
-    //     .filter(|path| path.exists())
-    //     .next();
+    // a single statement, so two first logins at once can not both insert
+    sqlx::query(
+        r#"INSERT INTO accounts (user, password, key, auth_source)
+        SELECT ?, ?, ?, 'ldap'
+        WHERE NOT EXISTS (SELECT 1 FROM accounts WHERE user = ? COLLATE NOCASE)
+        ON CONFLICT DO NOTHING"#,
+    )
+    .bind(user.clone())
+    .bind(account::generate_key())
+    .bind(account::generate_key())
+    .bind(user.clone())
+    .execute(pool)
+    .await?;
 
-    // let audio_exts = ["ogg", "mp3", "webm"];
-    // let audio_paths = fs::read_dir(&path)
-    //     .ok()?
-    //     .filter_map(Result::ok)
-    //     .filter(|entry| entry.file_type().ok().map_or(false, |ft| ft.is_file()))
-    //     .filter_map(|entry| {
-    //         let path = entry.path();
-    //         let ext = path.extension()?.to_str()?;
-    //         if audio_exts.contains(&ext) {
-    //             Some(path)
-    //         } else {
-    //             None
-    //         }
-    //     })
-    //     .collect::<Vec<_>>();
+    let row = sqlx::query_as::<_, KeyDisabledRow>(
+        r#"SELECT key, disabled FROM accounts WHERE user = ? AND auth_source = 'ldap'"#,
+    )
+    .bind(user)
+    .fetch_one(pool)
+    .await?;
 
-    let audiobook = audiobook::Audiobook {
-        title,
-        author,
-        path,
-    };
-    Some(audiobook)
+    if row.disabled {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(row.key)
+}
+
+pub async fn update_ldap_disabled(
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"UPDATE accounts SET disabled = 1 WHERE user = ? AND auth_source = 'ldap'"#)
+        .bind(user)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// queries the user of a key if they are an administrator
+pub async fn query_admin(
+    key: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<String, sqlx::Error> {
+    let row = sqlx::query_as::<_, UserRow>(
+        r#"SELECT user FROM accounts
+        WHERE disabled = 0 AND is_admin = 1 AND (key = ? OR user IN (
+        SELECT user FROM sessions WHERE access_token = ? AND access_expires > ?))"#,
+    )
+    .bind(key.clone())
+    .bind(key)
+    .bind(chrono::Utc::now().timestamp())
+    .fetch_one(pool)
+    .await?;
+
+    Ok(row.user)
+}
+
+pub async fn query_accounts(
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<account::Accounts, sqlx::Error> {
+    let rows = sqlx::query_as::<_, AccountRow>(
+        r#"SELECT user, is_admin, disabled FROM accounts ORDER BY user"#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let accounts = rows
+        .into_iter()
+        .map(|row| account::AccountFmt {
+            user: row.user,
+            is_admin: row.is_admin,
+            disabled: row.disabled,
+        })
+        .collect();
+
+    Ok(account::Accounts { accounts })
+}
+
+pub async fn update_admin(
+    user: String,
+    is_admin: bool,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    let res = sqlx::query(r#"UPDATE accounts SET is_admin = ? WHERE user = ?"#)
+        .bind(is_admin)
+        .bind(user)
+        .execute(pool)
+        .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(())
+}
+
+pub async fn update_disabled(
+    user: String,
+    disabled: bool,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    let res = sqlx::query(r#"UPDATE accounts SET disabled = ? WHERE user = ?"#)
+        .bind(disabled)
+        .bind(user)
+        .execute(pool)
+        .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(())
+}
+
+/// changes the password of a user, closing all their sessions
+pub async fn update_password(
+    user: String,
+    password: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let res = sqlx::query(r#"UPDATE accounts SET password = ? WHERE user = ?"#)
+        .bind(password)
+        .bind(user.clone())
+        .execute(&mut tx)
+        .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    sqlx::query(r#"DELETE FROM sessions WHERE user = ?"#)
+        .bind(user)
+        .execute(&mut tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// replaces the api key of a user, the old key and every session stop
+/// working immediately
+pub async fn update_key(
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<String, sqlx::Error> {
+    let key = account::generate_key();
+
+    let mut tx = pool.begin().await?;
+
+    let res = sqlx::query(r#"UPDATE accounts SET key = ? WHERE user = ?"#)
+        .bind(key.clone())
+        .bind(user.clone())
+        .execute(&mut tx)
+        .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    sqlx::query(r#"DELETE FROM sessions WHERE user = ?"#)
+        .bind(user)
+        .execute(&mut tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(key)
+}
+
+/// opens a session for a user, expired sessions are cleaned up on the way
+pub async fn insert_session(
+    user: String,
+    lifetimes: &session::Lifetimes,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<session::Session, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let access_token = account::generate_key();
+    let refresh_token = account::generate_key();
+
+    sqlx::query(r#"DELETE FROM sessions WHERE refresh_expires <= ?"#)
+        .bind(now)
+        .execute(pool)
+        .await?;
+
+    sqlx::query(
+        r#"INSERT INTO sessions (access_token, refresh_token, user, access_expires, refresh_expires)
+        VALUES (?, ?, ?, ?, ?)"#,
+    )
+    .bind(access_token.clone())
+    .bind(refresh_token.clone())
+    .bind(user)
+    .bind(now + lifetimes.access)
+    .bind(now + lifetimes.refresh)
+    .execute(pool)
+    .await?;
+
+    Ok(session::Session {
+        access_token,
+        refresh_token,
+        expires_in: lifetimes.access,
+    })
+}
+
+/// replaces a session by a new one, the refresh token can only be used once
+pub async fn update_session(
+    refresh_token: String,
+    lifetimes: &session::Lifetimes,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<session::Session, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+    let access_token = account::generate_key();
+    let new_refresh_token = account::generate_key();
+
+    let res = sqlx::query(
+        r#"UPDATE sessions SET access_token = ?, refresh_token = ?,
+        access_expires = ?, refresh_expires = ?
+        WHERE refresh_token = ? AND refresh_expires > ? AND user IN (
+        SELECT user FROM accounts WHERE disabled = 0)"#,
+    )
+    .bind(access_token.clone())
+    .bind(new_refresh_token.clone())
+    .bind(now + lifetimes.access)
+    .bind(now + lifetimes.refresh)
+    .bind(refresh_token)
+    .bind(now)
+    .execute(pool)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    Ok(session::Session {
+        access_token,
+        refresh_token: new_refresh_token,
+        expires_in: lifetimes.access,
+    })
+}
+
+/// closes the session an access or a refresh token belongs to
+pub async fn delete_session(
+    token: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"DELETE FROM sessions WHERE access_token = ? OR refresh_token = ?"#)
+        .bind(token.clone())
+        .bind(token)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// renames an account everywhere it is referenced
+pub async fn update_user(
+    user: String,
+    name: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let res = sqlx::query(r#"UPDATE accounts SET user = ? WHERE user = ?"#)
+        .bind(name.clone())
+        .bind(user.clone())
+        .execute(&mut tx)
+        .await?;
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    let references = [
+        r#"UPDATE positions SET user = ? WHERE user = ?"#,
+        r#"UPDATE collections SET user = ? WHERE user = ?"#,
+        r#"UPDATE collection_shares SET user = ? WHERE user = ?"#,
+        r#"UPDATE access_rules SET subject = ? WHERE subject_kind = 'user' AND subject = ?"#,
+        r#"UPDATE group_members SET user = ? WHERE user = ?"#,
+        r#"UPDATE invites SET created_by = ? WHERE created_by = ?"#,
+        r#"UPDATE sessions SET user = ? WHERE user = ?"#,
+    ];
+    for reference in references.iter() {
+        sqlx::query(reference)
+            .bind(name.clone())
+            .bind(user.clone())
+            .execute(&mut tx)
+            .await?;
+    }
+
+    tx.commit().await
+}
+
+/// deletes an account along with every piece of data that belongs to it
+pub async fn delete_user(user: String, pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(r#"DELETE FROM positions WHERE user = ?"#)
+        .bind(user.clone())
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(
+        r#"DELETE FROM collection_audiobooks
+        WHERE collection IN (SELECT id FROM collections WHERE user = ?)"#,
+    )
+    .bind(user.clone())
+    .execute(&mut tx)
+    .await?;
+    sqlx::query(
+        r#"DELETE FROM collection_shares
+        WHERE user = ? OR collection IN (SELECT id FROM collections WHERE user = ?)"#,
+    )
+    .bind(user.clone())
+    .bind(user.clone())
+    .execute(&mut tx)
+    .await?;
+    sqlx::query(r#"DELETE FROM collections WHERE user = ?"#)
+        .bind(user.clone())
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(r#"DELETE FROM access_rules WHERE subject_kind = 'user' AND subject = ?"#)
+        .bind(user.clone())
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(r#"DELETE FROM group_members WHERE user = ?"#)
+        .bind(user.clone())
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(r#"DELETE FROM sessions WHERE user = ?"#)
+        .bind(user.clone())
+        .execute(&mut tx)
+        .await?;
+    let res = sqlx::query(r#"DELETE FROM accounts WHERE user = ?"#)
+        .bind(user)
+        .execute(&mut tx)
+        .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    tx.commit().await
+}
+
+/// queries the collections a user owns or that are shared with them
+pub async fn query_collections(
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<collection::Collections, sqlx::Error> {
+    let rows = sqlx::query_as::<_, CollectionRow>(
+        r#"SELECT id, user, name FROM collections
+        WHERE user = ? OR id IN (SELECT collection FROM collection_shares WHERE user = ?)
+        ORDER BY name COLLATE NOCASE, id"#,
+    )
+    .bind(user.clone())
+    .bind(user.clone())
+    .fetch_all(pool)
+    .await?;
+
+    let mut collections = Vec::new();
+    for row in rows {
+        collections.push(collection_from_row(row, user.clone(), pool).await?);
+    }
+
+    Ok(collection::Collections { collections })
+}
+
+/// queries a collection if the user owns it or it is shared with them
+pub async fn query_collection(
+    id: i64,
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<collection::Collection, sqlx::Error> {
+    let row = sqlx::query_as::<_, CollectionRow>(
+        r#"SELECT id, user, name FROM collections
+        WHERE id = ? AND (user = ? OR id IN (
+        SELECT collection FROM collection_shares WHERE user = ?))"#,
+    )
+    .bind(id)
+    .bind(user.clone())
+    .bind(user.clone())
+    .fetch_one(pool)
+    .await?;
+
+    collection_from_row(row, user, pool).await
+}
+
+async fn collection_from_row(
+    row: CollectionRow,
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<collection::Collection, sqlx::Error> {
+    let sql = format!(
+        r#"SELECT audiobooks.hash FROM collection_audiobooks
+        JOIN audiobooks ON audiobooks.hash = collection_audiobooks.hash
+        WHERE collection_audiobooks.collection = ? AND {}
+        ORDER BY audiobooks.hash"#,
+        VISIBLE
+    );
+    let mut query = sqlx::query_as::<_, HashRow>(&sql).bind(row.id);
+    for _ in 0..VISIBLE_BINDS {
+        query = query.bind(user.clone());
+    }
+    let audiobooks = query.fetch_all(pool).await?;
+
+    let shared_with = if row.user == user {
+        sqlx::query_as::<_, UserRow>(
+            r#"SELECT user FROM collection_shares WHERE collection = ? ORDER BY user"#,
+        )
+        .bind(row.id)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|share| share.user)
+        .collect()
+    } else {
+        Vec::new()
+    };
+
+    Ok(collection::Collection {
+        id: row.id,
+        name: row.name,
+        owner: row.user,
+        shared_with,
+        audiobooks: audiobooks.into_iter().map(|row| row.hash).collect(),
+    })
+}
+
+pub async fn insert_collection(
+    user: String,
+    name: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<i64, sqlx::Error> {
+    let res = sqlx::query(r#"INSERT INTO collections (user, name) VALUES (?, ?)"#)
+        .bind(user)
+        .bind(name)
+        .execute(pool)
+        .await?;
+
+    Ok(res.last_insert_rowid())
+}
+
+pub async fn update_collection(
+    id: i64,
+    name: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"UPDATE collections SET name = ? WHERE id = ?"#)
+        .bind(name)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_collection(id: i64, pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(r#"DELETE FROM collection_audiobooks WHERE collection = ?"#)
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(r#"DELETE FROM collection_shares WHERE collection = ?"#)
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(r#"DELETE FROM collections WHERE id = ?"#)
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+
+    tx.commit().await
+}
+
+pub async fn insert_collection_audiobook(
+    id: i64,
+    hash: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"INSERT OR IGNORE INTO collection_audiobooks (collection, hash) VALUES (?, ?)"#)
+        .bind(id)
+        .bind(hash)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_collection_audiobook(
+    id: i64,
+    hash: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"DELETE FROM collection_audiobooks WHERE collection = ? AND hash = ?"#)
+        .bind(id)
+        .bind(hash)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn insert_collection_share(
+    id: i64,
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"INSERT OR IGNORE INTO collection_shares (collection, user) VALUES (?, ?)"#)
+        .bind(id)
+        .bind(user)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_collection_share(
+    id: i64,
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"DELETE FROM collection_shares WHERE collection = ? AND user = ?"#)
+        .bind(id)
+        .bind(user)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn insert_invite(
+    uses: u32,
+    expires: Option<i64>,
+    created_by: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<invite::Invite, sqlx::Error> {
+    let code = account::generate_key();
+
+    sqlx::query(
+        r#"INSERT INTO invites (code, uses, expires, created_by)
+        VALUES (?, ?, ?, ?)"#,
+    )
+    .bind(code.clone())
+    .bind(uses)
+    .bind(expires)
+    .bind(created_by.clone())
+    .execute(pool)
+    .await?;
+
+    Ok(invite::Invite {
+        code,
+        uses: uses as i64,
+        expires,
+        created_by,
+    })
+}
+
+/// queries the invite codes that can still be used
+pub async fn query_invites(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<invite::Invites, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+
+    let rows = sqlx::query_as::<_, InviteRow>(
+        r#"SELECT code, uses, expires, created_by FROM invites
+        WHERE uses > 0 AND (expires IS NULL OR expires > ?)
+        ORDER BY expires IS NULL, expires"#,
+    )
+    .bind(now)
+    .fetch_all(pool)
+    .await?;
+
+    let invites = rows
+        .into_iter()
+        .map(|row| invite::Invite {
+            code: row.code,
+            uses: row.uses,
+            expires: row.expires,
+            created_by: row.created_by,
+        })
+        .collect();
+
+    Ok(invite::Invites { invites })
+}
+
+pub async fn delete_invite(code: String, pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+    let res = sqlx::query(r#"DELETE FROM invites WHERE code = ?"#)
+        .bind(code)
+        .execute(pool)
+        .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(())
+}
+
+/// queries every access rule and group of the server
+pub async fn query_access(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<access::Access, sqlx::Error> {
+    let rules = sqlx::query_as::<_, AccessRuleRow>(
+        r#"SELECT id, subject_kind, subject, kind, value FROM access_rules ORDER BY id"#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let members = sqlx::query_as::<_, GroupMemberRow>(
+        r#"SELECT group_name, user FROM group_members ORDER BY group_name, user"#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut groups: Vec<access::Group> = Vec::new();
+    for member in members {
+        match groups.last_mut() {
+            Some(group) if group.name == member.group_name => {
+                group.members.push(member.user);
+            }
+            _ => {
+                groups.push(access::Group {
+                    name: member.group_name,
+                    members: vec![member.user],
+                });
+            }
+        };
+    }
+
+    Ok(access::Access {
+        rules: rules
+            .into_iter()
+            .map(|row| access::AccessRule {
+                id: row.id,
+                subject_kind: row.subject_kind,
+                subject: row.subject,
+                kind: row.kind,
+                value: row.value,
+            })
+            .collect(),
+        groups,
+    })
+}
+
+pub async fn insert_access_rule(
+    rule: &access::NewAccessRule,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<access::AccessRule, sqlx::Error> {
+    let res = sqlx::query(
+        r#"INSERT INTO access_rules (subject_kind, subject, kind, value)
+        VALUES (?, ?, ?, ?)"#,
+    )
+    .bind(rule.subject_kind.clone())
+    .bind(rule.subject.clone())
+    .bind(rule.kind.clone())
+    .bind(rule.value.clone())
+    .execute(pool)
+    .await?;
+
+    Ok(access::AccessRule {
+        id: res.last_insert_rowid(),
+        subject_kind: rule.subject_kind.clone(),
+        subject: rule.subject.clone(),
+        kind: rule.kind.clone(),
+        value: rule.value.clone(),
+    })
+}
+
+pub async fn delete_access_rule(id: i64, pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+    let res = sqlx::query(r#"DELETE FROM access_rules WHERE id = ?"#)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(())
+}
+
+pub async fn insert_group_member(
+    group: String,
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"INSERT OR IGNORE INTO group_members (group_name, user) VALUES (?, ?)"#)
+        .bind(group)
+        .bind(user)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_group_member(
+    group: String,
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"DELETE FROM group_members WHERE group_name = ? AND user = ?"#)
+        .bind(group)
+        .bind(user)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// checks that an account exists, returns its username
+pub async fn query_account(
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<String, sqlx::Error> {
+    let row = sqlx::query_as::<_, UserRow>(r#"SELECT user FROM accounts WHERE user = ?"#)
+        .bind(user)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(row.user)
+}
+
+/// finds an account whose name only differs by its case, returns its username
+pub async fn query_account_nocase(
+    user: String,
+    pool: &sqlx::Pool<sqlx::Sqlite>,
+) -> Result<String, sqlx::Error> {
+    let row = sqlx::query_as::<_, UserRow>(
+        r#"SELECT user FROM accounts WHERE user = ? COLLATE NOCASE"#,
+    )
+    .bind(user)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(row.user)
+}
+
+pub fn scan_audiobook_direcories(dir: &Path) -> Vec<audiobook::Audiobook> {
+    let mut audiobooks = Vec::new();
+
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let dir_path = entry.path();
+            if dir_path.is_dir() {
+                if let Some(audiobook) = scan_audiobook_directory(&dir_path) {
+                    audiobooks.push(audiobook);
+                }
+            }
+        }
+    }
+    audiobooks
+}
+
+pub fn scan_audiobook_directory(path: &Path) -> Option<audiobook::Audiobook> {
+    let info_file_path = path.join("info.toml");
+    let metadata_str = fs::read_to_string(&info_file_path).ok()?;
+    let metadata: toml::Value = metadata_str.parse().ok()?;
+    let title = metadata.get("title").and_then(|v| v.as_str())?.to_owned();
+    let author = metadata.get("author").and_then(|v| v.as_str())?.to_owned();
+    let narrator = metadata
+        .get("narrator")
+        .and_then(|v| v.as_str())
+        .map(str::to_owned);
+    let description = metadata
+        .get("description")
+        .and_then(|v| v.as_str())
+        .map(str::to_owned);
+    let series = metadata
+        .get("series")
+        .and_then(|v| v.as_str())
+        .map(str::to_owned);
+    let series_index = metadata
+        .get("series_index")
+        .and_then(|v| v.as_float().or_else(|| v.as_integer().map(|i| i as f64)));
+    let genre = metadata
+        .get("genre")
+        .and_then(|v| v.as_str())
+        .map(str::to_owned);
+    let language = metadata
+        .get("language")
+        .and_then(|v| v.as_str())
+        .map(str::to_owned);
+    let tags = metadata
+        .get("tags")
+        .and_then(|v| v.as_array())
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|tag| tag.as_str().map(str::to_owned))
+                .collect()
+        })
+        .unwrap_or_default();
+    let directory = path.file_name()?.to_string_lossy().to_string();
+    let path = path.to_string_lossy().to_string();
+
+    // let cover_path = ["cover.jpg", "cover.jpeg", "cover.png"]
+    //     .iter()
+    //     .map(|name| path.join(name))
+    //     .filter(|path| path.exists())
+    //     .next();
+
+    let files = scan_audio_files(Path::new(&path));
+
+    let audiobook = audiobook::Audiobook {
+        title,
+        author,
+        path,
+        narrator,
+        description,
+        series,
+        series_index,
+        genre,
+        language,
+        tags,
+        directory,
+        files,
+    };
+    Some(audiobook)
+}
+
+/// Lists the audio files at the root of a book directory, the way they are
+/// named in its archive, along with their duration
+fn scan_audio_files(path: &Path) -> Vec<audiobook::AudiobookFile> {
+    let audio_exts = ["ogg", "opus", "mp3", "m4a", "m4b", "flac", "wav", "webm"];
+
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut files: Vec<audiobook::AudiobookFile> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| {
+                    audio_exts.contains(&ext.to_lowercase().as_str())
+                })
+        })
+        .filter_map(|path| {
+            let name = path.file_name()?.to_string_lossy().to_string();
+            let duration = lofty::read_from_path(&path)
+                .ok()
+                .map(|file| file.properties().duration().as_secs_f64());
+            Some(audiobook::AudiobookFile { name, duration })
+        })
+        .collect();
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::migrations;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Opens an empty database of its own for a test
+    async fn pool() -> sqlx::Pool<sqlx::Sqlite> {
+        static DATABASES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "illiad-schema-{}-{}.sqlite",
+            std::process::id(),
+            DATABASES.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_file(&path);
+
+        let pool = create_pool(
+            path,
+            PoolSettings {
+                max_connections: 5,
+                busy_timeout: std::time::Duration::from_secs(5),
+            },
+        )
+        .await;
+        migrations::migrate(&pool).await.unwrap();
+        pool
+    }
+
+    fn book(title: &str, author: &str) -> audiobook::Audiobook {
+        audiobook::Audiobook {
+            title: String::from(title),
+            author: String::from(author),
+            path: format!("/data/{}", title),
+            narrator: None,
+            description: None,
+            series: None,
+            series_index: None,
+            genre: None,
+            language: None,
+            tags: Vec::new(),
+            directory: String::from(title),
+            files: vec![audiobook::AudiobookFile {
+                name: String::from("01.mp3"),
+                duration: None,
+            }],
+        }
+    }
+
+    fn query() -> audiobook::AudiobookQuery {
+        audiobook::AudiobookQuery {
+            q: None,
+            author: None,
+            series: None,
+            genre: None,
+            language: None,
+            status: None,
+            collection: None,
+            sort: None,
+            cursor: None,
+            limit: None,
+        }
+    }
+
+    async fn titles(
+        query: &audiobook::AudiobookQuery,
+        pool: &sqlx::Pool<sqlx::Sqlite>,
+    ) -> Vec<String> {
+        let search = query.parse().unwrap();
+        query_audiobooks(&search, String::from("alice"), pool)
+            .await
+            .unwrap()
+            .audiobooks
+            .into_iter()
+            .map(|audiobook| audiobook.title)
+            .collect()
+    }
+
+    #[rocket::async_test]
+    async fn pages_through_the_whole_list() {
+        let pool = pool().await;
+        for title in ["Emma", "Dune", "Persuasion", "Beloved", "Ulysses"] {
+            insert_audiobook(book(title, "Someone"), &pool)
+                .await
+                .unwrap();
+        }
+
+        let mut query = query();
+        query.limit = Some(2);
+        let mut seen = Vec::new();
+        loop {
+            let page = query_audiobooks(&query.parse().unwrap(), String::from("alice"), &pool)
+                .await
+                .unwrap();
+            assert_eq!(page.total, 5);
+            assert!(page.audiobooks.len() <= 2);
+            seen.extend(page.audiobooks.into_iter().map(|audiobook| audiobook.title));
+            match page.next {
+                Some(next) => query.cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(seen, ["Beloved", "Dune", "Emma", "Persuasion", "Ulysses"]);
+
+        // a cursor is only ever one given by a previous page
+        query.cursor = Some(String::from("2"));
+        assert!(query.parse().is_none());
+        query.cursor = Some(String::from("7b7d"));
+        assert!(query.parse().is_none());
+    }
+
+    #[rocket::async_test]
+    async fn searches_filters_and_sorts() {
+        let pool = pool().await;
+        let mut dune = book("Dune", "Frank Herbert");
+        dune.narrator = Some(String::from("Scott Brick"));
+        dune.genre = Some(String::from("Science fiction"));
+        insert_audiobook(dune, &pool).await.unwrap();
+        let mut emma = book("Emma", "Jane Austen");
+        emma.genre = Some(String::from("Novel"));
+        insert_audiobook(emma, &pool).await.unwrap();
+        insert_audiobook(book("Persuasion", "Jane Austen"), &pool)
+            .await
+            .unwrap();
+
+        // every word is a prefix, whatever field it is found in
+        let mut search = query();
+        search.q = Some(String::from("sco bri"));
+        assert_eq!(titles(&search, &pool).await, ["Dune"]);
+        // fts5 operators are searched for as text
+        search.q = Some(String::from("\"dune OR *"));
+        assert!(titles(&search, &pool).await.is_empty());
+
+        let mut search = query();
+        search.author = Some(String::from("jane austen"));
+        assert_eq!(titles(&search, &pool).await, ["Emma", "Persuasion"]);
+        search.genre = Some(String::from("novel"));
+        assert_eq!(titles(&search, &pool).await, ["Emma"]);
+
+        let mut search = query();
+        search.sort = Some(String::from("-author"));
+        assert_eq!(titles(&search, &pool).await, ["Persuasion", "Emma", "Dune"]);
+        search.sort = Some(String::from("length"));
+        assert!(search.parse().is_none());
+    }
+
+    #[rocket::async_test]
+    async fn inserts_an_audiobook_whole_or_not_at_all() {
+        let pool = pool().await;
+        sqlx::query(
+            r#"CREATE TRIGGER no_files BEFORE INSERT ON audiobook_files
+            BEGIN SELECT RAISE(ABORT, 'no files'); END"#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert!(insert_audiobook(book("Dune", "Frank Herbert"), &pool)
+            .await
+            .is_err());
+        let hash = audiobook::compute_hash(String::from("Dune"), String::from("Frank Herbert"));
+        assert!(query_scanned_audiobook(hash, &pool)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(titles(&query(), &pool).await.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn groups_books_by_author_and_series() {
+        let pool = pool().await;
+        let mut dune = book("Dune", "Frank Herbert");
+        dune.series = Some(String::from("Dune"));
+        dune.series_index = Some(1.0);
+        let mut messiah = book("Dune Messiah", "Frank Herbert");
+        messiah.series = Some(String::from("Dune"));
+        messiah.series_index = Some(2.0);
+        for audiobook in [
+            messiah,
+            book("The Dosadi Experiment", "Frank Herbert"),
+            dune,
+            book("Emma", "Jane Austen"),
+        ] {
+            insert_audiobook(audiobook, &pool).await.unwrap();
+        }
+
+        let authors = query_authors(String::from("alice"), &pool)
+            .await
+            .unwrap()
+            .authors;
+        let counts: Vec<(&str, i64)> = authors
+            .iter()
+            .map(|author| (author.name.as_str(), author.count))
+            .collect();
+        assert_eq!(counts, [("Frank Herbert", 3), ("Jane Austen", 1)]);
+
+        // books of a series come first, in the order of the series
+        let books = query_author_audiobooks(authors[0].id.clone(), String::from("alice"), &pool)
+            .await
+            .unwrap();
+        let titles: Vec<&str> = books
+            .audiobooks
+            .iter()
+            .map(|audiobook| audiobook.title.as_str())
+            .collect();
+        assert_eq!(titles, ["Dune", "Dune Messiah", "The Dosadi Experiment"]);
+        assert!(matches!(
+            query_author_audiobooks(String::from("unknown"), String::from("alice"), &pool).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+
+        let series = query_series(String::from("alice"), &pool)
+            .await
+            .unwrap()
+            .series;
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].count, 2);
+        let books = query_series_audiobooks(series[0].id.clone(), String::from("alice"), &pool)
+            .await
+            .unwrap();
+        assert_eq!(books.audiobooks[1].title, "Dune Messiah");
+    }
+
+    #[rocket::async_test]
+    async fn shares_collections_read_only() {
+        let pool = pool().await;
+        for user in ["alice", "bob"] {
+            insert_user(String::from(user), String::from("secret"), &pool)
+                .await
+                .unwrap();
+        }
+        insert_audiobook(book("Dune", "Frank Herbert"), &pool)
+            .await
+            .unwrap();
+        insert_audiobook(book("Emma", "Jane Austen"), &pool)
+            .await
+            .unwrap();
+        let dune = audiobook::compute_hash(String::from("Dune"), String::from("Frank Herbert"));
+
+        let id = insert_collection(String::from("alice"), String::from("Commute"), &pool)
+            .await
+            .unwrap();
+        insert_collection_audiobook(id, dune.clone(), &pool)
+            .await
+            .unwrap();
+
+        // the collection filters the list of its owner only
+        let mut search = query();
+        search.collection = Some(id);
+        assert_eq!(titles(&search, &pool).await, ["Dune"]);
+        let search = search.parse().unwrap();
+        let page = query_audiobooks(&search, String::from("bob"), &pool)
+            .await
+            .unwrap();
+        assert!(page.audiobooks.is_empty());
+        assert!(matches!(
+            query_collection(id, String::from("bob"), &pool).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+
+        insert_collection_share(id, String::from("bob"), &pool)
+            .await
+            .unwrap();
+        let shared = query_collection(id, String::from("bob"), &pool)
+            .await
+            .unwrap();
+        assert_eq!(shared.owner, "alice");
+        assert_eq!(shared.audiobooks, [dune]);
+        // only the owner is told who the collection is shared with
+        assert!(shared.shared_with.is_empty());
+        let owned = query_collection(id, String::from("alice"), &pool)
+            .await
+            .unwrap();
+        assert_eq!(owned.shared_with, ["bob"]);
+        let page = query_audiobooks(&search, String::from("bob"), &pool)
+            .await
+            .unwrap();
+        assert_eq!(page.total, 1);
+
+        delete_collection(id, &pool).await.unwrap();
+        assert!(query_collections(String::from("bob"), &pool)
+            .await
+            .unwrap()
+            .collections
+            .is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn administers_accounts() {
+        let pool = pool().await;
+        let key = insert_user(String::from("alice"), String::from("secret"), &pool)
+            .await
+            .unwrap();
+        insert_user(String::from("bob"), String::from("secret"), &pool)
+            .await
+            .unwrap();
+        let id = insert_collection(String::from("bob"), String::from("Commute"), &pool)
+            .await
+            .unwrap();
+        insert_collection_share(id, String::from("alice"), &pool)
+            .await
+            .unwrap();
+
+        assert!(query_admin(key.clone(), &pool).await.is_err());
+        update_admin(String::from("alice"), true, &pool)
+            .await
+            .unwrap();
+        assert_eq!(query_admin(key.clone(), &pool).await.unwrap(), "alice");
+
+        // a disabled account can neither login nor use its key
+        update_disabled(String::from("alice"), true, &pool)
+            .await
+            .unwrap();
+        assert!(
+            select_user(String::from("alice"), String::from("secret"), &pool)
+                .await
+                .is_err()
+        );
+        assert!(query_user(key.clone(), &pool).await.is_err());
+        assert!(query_admin(key.clone(), &pool).await.is_err());
+        update_disabled(String::from("alice"), false, &pool)
+            .await
+            .unwrap();
+
+        let new_key = update_key(String::from("alice"), &pool).await.unwrap();
+        assert!(query_user(key, &pool).await.is_err());
+        assert_eq!(query_user(new_key, &pool).await.unwrap(), "alice");
+        assert!(matches!(
+            update_admin(String::from("nobody"), true, &pool).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+
+        // deleting an account takes its collections along
+        delete_user(String::from("bob"), &pool).await.unwrap();
+        assert!(query_collections(String::from("alice"), &pool)
+            .await
+            .unwrap()
+            .collections
+            .is_empty());
+        let accounts = query_accounts(&pool).await.unwrap().accounts;
+        assert_eq!(accounts.len(), 1);
+        assert!(accounts[0].is_admin && !accounts[0].disabled);
+    }
+
+    #[rocket::async_test]
+    async fn registers_with_invites() {
+        let pool = pool().await;
+        insert_user(String::from("alice"), String::from("secret"), &pool)
+            .await
+            .unwrap();
+        let invite = insert_invite(2, None, String::from("alice"), &pool)
+            .await
+            .unwrap();
+        let expired = insert_invite(1, Some(0), String::from("alice"), &pool)
+            .await
+            .unwrap();
+        assert_eq!(query_invites(&pool).await.unwrap().invites.len(), 1);
+
+        assert!(matches!(
+            insert_user_with_invite(
+                String::from("bob"),
+                String::from("secret"),
+                expired.code,
+                &pool
+            )
+            .await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        // a refused registration does not use the invite up
+        assert!(insert_user_with_invite(
+            String::from("alice"),
+            String::from("secret"),
+            invite.code.clone(),
+            &pool
+        )
+        .await
+        .is_err());
+        for user in ["bob", "carol"] {
+            insert_user_with_invite(
+                String::from(user),
+                String::from("secret"),
+                invite.code.clone(),
+                &pool,
+            )
+            .await
+            .unwrap();
+        }
+        assert!(insert_user_with_invite(
+            String::from("dave"),
+            String::from("secret"),
+            invite.code.clone(),
+            &pool
+        )
+        .await
+        .is_err());
+        assert!(query_invites(&pool).await.unwrap().invites.is_empty());
+        assert!(matches!(
+            delete_invite(String::from("unknown"), &pool).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+    }
+
+    async fn rule(
+        subject_kind: &str,
+        subject: &str,
+        kind: &str,
+        value: &str,
+        pool: &sqlx::Pool<sqlx::Sqlite>,
+    ) -> i64 {
+        insert_access_rule(
+            &access::NewAccessRule {
+                subject_kind: String::from(subject_kind),
+                subject: String::from(subject),
+                kind: String::from(kind),
+                value: String::from(value),
+            },
+            pool,
+        )
+        .await
+        .unwrap()
+        .id
+    }
+
+    #[rocket::async_test]
+    async fn restricts_the_library_to_the_rules() {
+        let pool = pool().await;
+        let mut dune = book("Dune", "Frank Herbert");
+        dune.directory = String::from("kids/dune");
+        insert_audiobook(dune, &pool).await.unwrap();
+        let mut emma = book("Emma", "Jane Austen");
+        emma.tags = vec![String::from("classic")];
+        insert_audiobook(emma, &pool).await.unwrap();
+        insert_audiobook(book("Ulysses", "James Joyce"), &pool)
+            .await
+            .unwrap();
+        let ulysses = audiobook::compute_hash(String::from("Ulysses"), String::from("James Joyce"));
+        for user in ["alice", "bob", "carol", "root"] {
+            insert_user(String::from(user), String::from("secret"), &pool)
+                .await
+                .unwrap();
+        }
+        update_admin(String::from("root"), true, &pool)
+            .await
+            .unwrap();
+
+        let visible = |user: &'static str| {
+            let pool = pool.clone();
+            async move {
+                let search = query().parse().unwrap();
+                query_audiobooks(&search, String::from(user), &pool)
+                    .await
+                    .unwrap()
+                    .audiobooks
+                    .into_iter()
+                    .map(|audiobook| audiobook.title)
+                    .collect::<Vec<String>>()
+            }
+        };
+
+        let prefix = rule("user", "alice", "prefix", "kids/", &pool).await;
+        rule("user", "alice", "grant", &ulysses, &pool).await;
+        rule("group", "readers", "tag", "classic", &pool).await;
+        insert_group_member(String::from("readers"), String::from("bob"), &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(visible("alice").await, ["Dune", "Ulysses"]);
+        assert_eq!(visible("bob").await, ["Emma"]);
+        // without any rule, or as an administrator, everything is visible
+        assert_eq!(visible("carol").await.len(), 3);
+        assert_eq!(visible("root").await.len(), 3);
+
+        // hidden books can not be reached through their hash either
+        let emma = audiobook::compute_hash(String::from("Emma"), String::from("Jane Austen"));
+        assert!(query_audiobook(emma.clone(), String::from("alice"), &pool)
+            .await
+            .is_err());
+        assert!(query_audiobook(emma, String::from("bob"), &pool)
+            .await
+            .is_ok());
+        assert_eq!(
+            query_authors(String::from("bob"), &pool)
+                .await
+                .unwrap()
+                .authors
+                .len(),
+            1
+        );
+
+        delete_access_rule(prefix, &pool).await.unwrap();
+        assert_eq!(visible("alice").await, ["Ulysses"]);
+        delete_group_member(String::from("readers"), String::from("bob"), &pool)
+            .await
+            .unwrap();
+        assert_eq!(visible("bob").await.len(), 3);
+    }
+
+    #[rocket::async_test]
+    async fn renames_accounts_everywhere() {
+        let pool = pool().await;
+        insert_audiobook(book("Dune", "Frank Herbert"), &pool)
+            .await
+            .unwrap();
+        for user in ["alice", "bob"] {
+            insert_user(String::from(user), String::from("secret"), &pool)
+                .await
+                .unwrap();
+        }
+        let id = insert_collection(String::from("alice"), String::from("Commute"), &pool)
+            .await
+            .unwrap();
+        insert_collection_share(id, String::from("bob"), &pool)
+            .await
+            .unwrap();
+        rule("user", "bob", "prefix", "kids/", &pool).await;
+
+        update_user(String::from("bob"), String::from("robert"), &pool)
+            .await
+            .unwrap();
+        assert!(
+            select_user(String::from("bob"), String::from("secret"), &pool)
+                .await
+                .is_err()
+        );
+        assert!(
+            select_user(String::from("robert"), String::from("secret"), &pool)
+                .await
+                .is_ok()
+        );
+        let shared = query_collections(String::from("robert"), &pool)
+            .await
+            .unwrap();
+        assert_eq!(shared.collections.len(), 1);
+        // the rules follow the account, so the library stays restricted
+        let search = query().parse().unwrap();
+        let page = query_audiobooks(&search, String::from("robert"), &pool)
+            .await
+            .unwrap();
+        assert!(page.audiobooks.is_empty());
+
+        assert!(matches!(
+            update_user(String::from("bob"), String::from("carol"), &pool).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert_eq!(
+            query_account_nocase(String::from("ROBERT"), &pool)
+                .await
+                .unwrap(),
+            "robert"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn expires_and_rotates_sessions() {
+        let pool = pool().await;
+        insert_user(String::from("alice"), String::from("secret"), &pool)
+            .await
+            .unwrap();
+        let lifetimes = session::Lifetimes {
+            access: 60,
+            refresh: 3600,
+        };
+
+        let first = insert_session(String::from("alice"), &lifetimes, &pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            query_user(first.access_token.clone(), &pool).await.unwrap(),
+            "alice"
+        );
+
+        // the refresh token is spent by the refresh, and so is the old access token
+        let second = update_session(first.refresh_token.clone(), &lifetimes, &pool)
+            .await
+            .unwrap();
+        assert!(query_user(first.access_token, &pool).await.is_err());
+        assert!(update_session(first.refresh_token, &lifetimes, &pool)
+            .await
+            .is_err());
+        assert!(query_user(second.access_token.clone(), &pool).await.is_ok());
+
+        delete_session(second.access_token.clone(), &pool)
+            .await
+            .unwrap();
+        assert!(query_user(second.access_token, &pool).await.is_err());
+
+        let expired = session::Lifetimes {
+            access: 0,
+            refresh: 0,
+        };
+        let stale = insert_session(String::from("alice"), &expired, &pool)
+            .await
+            .unwrap();
+        assert!(query_user(stale.access_token, &pool).await.is_err());
+        assert!(update_session(stale.refresh_token, &lifetimes, &pool)
+            .await
+            .is_err());
+
+        // a new api key closes every session
+        let third = insert_session(String::from("alice"), &lifetimes, &pool)
+            .await
+            .unwrap();
+        update_key(String::from("alice"), &pool).await.unwrap();
+        assert!(query_user(third.access_token, &pool).await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn creates_the_accounts_of_proxy_users() {
+        let pool = pool().await;
+
+        let key = query_or_insert_user_key(String::from("alice"), &pool)
+            .await
+            .unwrap();
+        assert_eq!(query_user(key.clone(), &pool).await.unwrap(), "alice");
+        assert_eq!(
+            query_or_insert_user_key(String::from("alice"), &pool)
+                .await
+                .unwrap(),
+            key
+        );
+
+        update_disabled(String::from("alice"), true, &pool)
+            .await
+            .unwrap();
+        assert!(query_or_insert_user_key(String::from("alice"), &pool)
+            .await
+            .is_err());
+        assert!(query_or_insert_user_key(String::new(), &pool)
+            .await
+            .is_err());
+    }
+
+    #[rocket::async_test]
+    async fn saves_positions_per_user() {
+        let pool = pool().await;
+        for user in ["alice", "carol"] {
+            insert_user(String::from(user), String::from("secret"), &pool)
+                .await
+                .unwrap();
+        }
+        insert_audiobook(book("Dune", "Frank Herbert"), &pool)
+            .await
+            .unwrap();
+        let dune = audiobook::compute_hash(String::from("Dune"), String::from("Frank Herbert"));
+
+        assert!(select_position(dune.clone(), String::from("alice"), &pool)
+            .await
+            .unwrap()
+            .is_none());
+
+        for position_ms in [10000, 42500] {
+            insert_position(
+                dune.clone(),
+                String::from("alice"),
+                String::from("01.mp3"),
+                position_ms,
+                Some(1.5),
+                Some(3),
+                &pool,
+            )
+            .await
+            .unwrap();
+        }
+        let position = select_position(dune.clone(), String::from("alice"), &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(position.file, "01.mp3");
+        assert_eq!((position.position_ms, position.position), (42500, 42));
+        assert_eq!((position.speed, position.chapter), (Some(1.5), Some(3)));
+
+        // positions saved in seconds only are converted when read
+        sqlx::query(r#"INSERT INTO positions (hash, user, file, position) VALUES (?, 'carol', '01.mp3', 42)"#)
+            .bind(dune.clone())
+            .execute(&pool)
+            .await
+            .unwrap();
+        let position = select_position(dune.clone(), String::from("carol"), &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((position.position_ms, position.speed), (42000, None));
+        assert!(select_position(dune, String::from("bob"), &pool)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[rocket::async_test]
+    async fn creates_the_database_and_its_directory() {
+        let dir = std::env::temp_dir().join(format!("illiad-schema-{}-new", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("data").join("database.sqlite");
+
+        let pool = create_pool(
+            path.clone(),
+            PoolSettings {
+                max_connections: 2,
+                busy_timeout: Duration::from_secs(5),
+            },
+        )
+        .await;
+        assert!(path.is_file());
+
+        let (journal_mode,): (String,) = sqlx::query_as(r#"PRAGMA journal_mode"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        let (foreign_keys,): (i64,) = sqlx::query_as(r#"PRAGMA foreign_keys"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(foreign_keys, 1);
+    }
 }
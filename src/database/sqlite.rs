@@ -0,0 +1,328 @@
+use rocket::async_trait;
+use rocket_db_pools::sqlx;
+
+use crate::database::migrations;
+use crate::database::schema;
+use crate::database::storage::Storage;
+use crate::models::access;
+use crate::models::account;
+use crate::models::audiobook;
+use crate::models::author;
+use crate::models::collection;
+use crate::models::export;
+use crate::models::invite;
+use crate::models::position;
+use crate::models::series;
+use crate::models::session;
+
+/// The default backend, a sqlite database next to the server
+pub struct SqliteStorage {
+    pub pool: sqlx::Pool<sqlx::Sqlite>,
+}
+
+impl SqliteStorage {
+    pub fn new(pool: sqlx::Pool<sqlx::Sqlite>) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn migrate(&self) -> Result<usize, migrations::MigrationError> {
+        migrations::migrate(&self.pool).await
+    }
+
+    async fn schema_version(&self) -> Result<i64, sqlx::Error> {
+        migrations::current_version(&self.pool).await
+    }
+
+    async fn query_audiobooks(
+        &self,
+        search: &audiobook::AudiobookSearch,
+        user: String,
+    ) -> Result<audiobook::Audiobooks, sqlx::Error> {
+        schema::query_audiobooks(search, user, &self.pool).await
+    }
+
+    async fn query_authors(&self, user: String) -> Result<author::Authors, sqlx::Error> {
+        schema::query_authors(user, &self.pool).await
+    }
+
+    async fn query_author_audiobooks(
+        &self,
+        id: String,
+        user: String,
+    ) -> Result<author::AuthorAudiobooks, sqlx::Error> {
+        schema::query_author_audiobooks(id, user, &self.pool).await
+    }
+
+    async fn query_series(&self, user: String) -> Result<series::Series, sqlx::Error> {
+        schema::query_series(user, &self.pool).await
+    }
+
+    async fn query_series_audiobooks(
+        &self,
+        id: String,
+        user: String,
+    ) -> Result<series::SeriesAudiobooks, sqlx::Error> {
+        schema::query_series_audiobooks(id, user, &self.pool).await
+    }
+
+    async fn query_audiobook(&self, hash: String, user: String) -> Result<String, sqlx::Error> {
+        schema::query_audiobook(hash, user, &self.pool).await
+    }
+
+    async fn insert_audiobook(&self, audiobook: audiobook::Audiobook) -> Result<(), sqlx::Error> {
+        schema::insert_audiobook(audiobook, &self.pool).await
+    }
+
+    async fn query_scanned_audiobook(
+        &self,
+        hash: String,
+    ) -> Result<Option<audiobook::Audiobook>, sqlx::Error> {
+        schema::query_scanned_audiobook(hash, &self.pool).await
+    }
+
+    async fn query_audiobook_file(
+        &self,
+        hash: String,
+        file: String,
+    ) -> Result<Option<f64>, sqlx::Error> {
+        schema::query_audiobook_file(hash, file, &self.pool).await
+    }
+
+    async fn insert_position(
+        &self,
+        hash: String,
+        user: String,
+        file: String,
+        position_ms: u64,
+        speed: Option<f64>,
+        chapter: Option<u32>,
+    ) -> Result<(), sqlx::Error> {
+        schema::insert_position(hash, user, file, position_ms, speed, chapter, &self.pool).await
+    }
+
+    async fn select_position(
+        &self,
+        hash: String,
+        user: String,
+    ) -> Result<Option<position::Position>, sqlx::Error> {
+        schema::select_position(hash, user, &self.pool).await
+    }
+
+    async fn select_positions(
+        &self,
+        user: String,
+    ) -> Result<Vec<export::ExportPosition>, sqlx::Error> {
+        schema::select_positions(user, &self.pool).await
+    }
+
+    async fn insert_import(
+        &self,
+        user: String,
+        account: Option<&export::ExportAccount>,
+        positions: &[export::ImportPosition],
+        collections: &[export::ImportCollection],
+    ) -> Result<(usize, usize), sqlx::Error> {
+        schema::insert_import(user, account, positions, collections, &self.pool).await
+    }
+
+    async fn query_audiobook_ref(&self, hash: String) -> Result<export::AudiobookRef, sqlx::Error> {
+        schema::query_audiobook_ref(hash, &self.pool).await
+    }
+
+    async fn query_audiobook_hash(
+        &self,
+        title: String,
+        author: String,
+        user: String,
+    ) -> Result<String, sqlx::Error> {
+        schema::query_audiobook_hash(title, author, user, &self.pool).await
+    }
+
+    async fn insert_user(&self, user: String, password: String) -> Result<String, sqlx::Error> {
+        schema::insert_user(user, password, &self.pool).await
+    }
+
+    async fn insert_admin_user(
+        &self,
+        user: String,
+        password: String,
+        is_admin: bool,
+    ) -> Result<String, sqlx::Error> {
+        schema::insert_admin_user(user, password, is_admin, &self.pool).await
+    }
+
+    async fn insert_user_with_invite(
+        &self,
+        user: String,
+        password: String,
+        code: String,
+    ) -> Result<String, sqlx::Error> {
+        schema::insert_user_with_invite(user, password, code, &self.pool).await
+    }
+
+    async fn select_user(&self, user: String, password: String) -> Result<String, sqlx::Error> {
+        schema::select_user(user, password, &self.pool).await
+    }
+
+    async fn query_user(&self, key: String) -> Result<String, sqlx::Error> {
+        schema::query_user(key, &self.pool).await
+    }
+
+    async fn query_or_insert_user_key(&self, user: String) -> Result<String, sqlx::Error> {
+        schema::query_or_insert_user_key(user, &self.pool).await
+    }
+
+    async fn query_or_insert_ldap_key(&self, user: String) -> Result<String, sqlx::Error> {
+        schema::query_or_insert_ldap_key(user, &self.pool).await
+    }
+
+    async fn update_ldap_disabled(&self, user: String) -> Result<(), sqlx::Error> {
+        schema::update_ldap_disabled(user, &self.pool).await
+    }
+
+    async fn query_admin(&self, key: String) -> Result<String, sqlx::Error> {
+        schema::query_admin(key, &self.pool).await
+    }
+
+    async fn query_accounts(&self) -> Result<account::Accounts, sqlx::Error> {
+        schema::query_accounts(&self.pool).await
+    }
+
+    async fn query_account(&self, user: String) -> Result<String, sqlx::Error> {
+        schema::query_account(user, &self.pool).await
+    }
+
+    async fn query_account_nocase(&self, user: String) -> Result<String, sqlx::Error> {
+        schema::query_account_nocase(user, &self.pool).await
+    }
+
+    async fn update_admin(&self, user: String, is_admin: bool) -> Result<(), sqlx::Error> {
+        schema::update_admin(user, is_admin, &self.pool).await
+    }
+
+    async fn update_disabled(&self, user: String, disabled: bool) -> Result<(), sqlx::Error> {
+        schema::update_disabled(user, disabled, &self.pool).await
+    }
+
+    async fn update_password(&self, user: String, password: String) -> Result<(), sqlx::Error> {
+        schema::update_password(user, password, &self.pool).await
+    }
+
+    async fn update_key(&self, user: String) -> Result<String, sqlx::Error> {
+        schema::update_key(user, &self.pool).await
+    }
+
+    async fn update_user(&self, user: String, name: String) -> Result<(), sqlx::Error> {
+        schema::update_user(user, name, &self.pool).await
+    }
+
+    async fn delete_user(&self, user: String) -> Result<(), sqlx::Error> {
+        schema::delete_user(user, &self.pool).await
+    }
+
+    async fn insert_session(
+        &self,
+        user: String,
+        lifetimes: &session::Lifetimes,
+    ) -> Result<session::Session, sqlx::Error> {
+        schema::insert_session(user, lifetimes, &self.pool).await
+    }
+
+    async fn update_session(
+        &self,
+        refresh_token: String,
+        lifetimes: &session::Lifetimes,
+    ) -> Result<session::Session, sqlx::Error> {
+        schema::update_session(refresh_token, lifetimes, &self.pool).await
+    }
+
+    async fn delete_session(&self, token: String) -> Result<(), sqlx::Error> {
+        schema::delete_session(token, &self.pool).await
+    }
+
+    async fn query_collections(
+        &self,
+        user: String,
+    ) -> Result<collection::Collections, sqlx::Error> {
+        schema::query_collections(user, &self.pool).await
+    }
+
+    async fn query_collection(
+        &self,
+        id: i64,
+        user: String,
+    ) -> Result<collection::Collection, sqlx::Error> {
+        schema::query_collection(id, user, &self.pool).await
+    }
+
+    async fn insert_collection(&self, user: String, name: String) -> Result<i64, sqlx::Error> {
+        schema::insert_collection(user, name, &self.pool).await
+    }
+
+    async fn update_collection(&self, id: i64, name: String) -> Result<(), sqlx::Error> {
+        schema::update_collection(id, name, &self.pool).await
+    }
+
+    async fn delete_collection(&self, id: i64) -> Result<(), sqlx::Error> {
+        schema::delete_collection(id, &self.pool).await
+    }
+
+    async fn insert_collection_audiobook(&self, id: i64, hash: String) -> Result<(), sqlx::Error> {
+        schema::insert_collection_audiobook(id, hash, &self.pool).await
+    }
+
+    async fn delete_collection_audiobook(&self, id: i64, hash: String) -> Result<(), sqlx::Error> {
+        schema::delete_collection_audiobook(id, hash, &self.pool).await
+    }
+
+    async fn insert_collection_share(&self, id: i64, user: String) -> Result<(), sqlx::Error> {
+        schema::insert_collection_share(id, user, &self.pool).await
+    }
+
+    async fn delete_collection_share(&self, id: i64, user: String) -> Result<(), sqlx::Error> {
+        schema::delete_collection_share(id, user, &self.pool).await
+    }
+
+    async fn insert_invite(
+        &self,
+        uses: u32,
+        expires: Option<i64>,
+        created_by: String,
+    ) -> Result<invite::Invite, sqlx::Error> {
+        schema::insert_invite(uses, expires, created_by, &self.pool).await
+    }
+
+    async fn query_invites(&self) -> Result<invite::Invites, sqlx::Error> {
+        schema::query_invites(&self.pool).await
+    }
+
+    async fn delete_invite(&self, code: String) -> Result<(), sqlx::Error> {
+        schema::delete_invite(code, &self.pool).await
+    }
+
+    async fn query_access(&self) -> Result<access::Access, sqlx::Error> {
+        schema::query_access(&self.pool).await
+    }
+
+    async fn insert_access_rule(
+        &self,
+        rule: &access::NewAccessRule,
+    ) -> Result<access::AccessRule, sqlx::Error> {
+        schema::insert_access_rule(rule, &self.pool).await
+    }
+
+    async fn delete_access_rule(&self, id: i64) -> Result<(), sqlx::Error> {
+        schema::delete_access_rule(id, &self.pool).await
+    }
+
+    async fn insert_group_member(&self, group: String, user: String) -> Result<(), sqlx::Error> {
+        schema::insert_group_member(group, user, &self.pool).await
+    }
+
+    async fn delete_group_member(&self, group: String, user: String) -> Result<(), sqlx::Error> {
+        schema::delete_group_member(group, user, &self.pool).await
+    }
+}
@@ -0,0 +1,549 @@
+use rocket::async_trait;
+use rocket_db_pools::sqlx;
+use std::path::{Path, PathBuf};
+
+use crate::database::migrations::MigrationError;
+use crate::database::schema;
+use crate::models::access;
+use crate::models::account;
+use crate::models::audiobook;
+use crate::models::author;
+use crate::models::collection;
+use crate::models::export;
+use crate::models::invite;
+use crate::models::position;
+use crate::models::series;
+use crate::models::session;
+
+/// Database illiad keeps its library, accounts and positions in. Every backend
+/// answers `sqlx::Error::RowNotFound` when the row a query targets does not
+/// exist, the controllers rely on it to tell apart missing rows and failures.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Brings the database up to the latest schema, returns the number of
+    /// migrations applied
+    async fn migrate(&self) -> Result<usize, MigrationError>;
+    /// Version the database is at, 0 before its first migration
+    async fn schema_version(&self) -> Result<i64, sqlx::Error>;
+
+    async fn query_audiobooks(
+        &self,
+        search: &audiobook::AudiobookSearch,
+        user: String,
+    ) -> Result<audiobook::Audiobooks, sqlx::Error>;
+    async fn query_authors(&self, user: String) -> Result<author::Authors, sqlx::Error>;
+    async fn query_author_audiobooks(
+        &self,
+        id: String,
+        user: String,
+    ) -> Result<author::AuthorAudiobooks, sqlx::Error>;
+    async fn query_series(&self, user: String) -> Result<series::Series, sqlx::Error>;
+    async fn query_series_audiobooks(
+        &self,
+        id: String,
+        user: String,
+    ) -> Result<series::SeriesAudiobooks, sqlx::Error>;
+    async fn query_audiobook(&self, hash: String, user: String) -> Result<String, sqlx::Error>;
+    async fn insert_audiobook(&self, audiobook: audiobook::Audiobook) -> Result<(), sqlx::Error>;
+    /// Queries an audiobook the way the scanner last saw it, with its tags and
+    /// files sorted by name
+    async fn query_scanned_audiobook(
+        &self,
+        hash: String,
+    ) -> Result<Option<audiobook::Audiobook>, sqlx::Error>;
+    async fn query_audiobook_file(
+        &self,
+        hash: String,
+        file: String,
+    ) -> Result<Option<f64>, sqlx::Error>;
+
+    async fn insert_position(
+        &self,
+        hash: String,
+        user: String,
+        file: String,
+        position_ms: u64,
+        speed: Option<f64>,
+        chapter: Option<u32>,
+    ) -> Result<(), sqlx::Error>;
+    async fn select_position(
+        &self,
+        hash: String,
+        user: String,
+    ) -> Result<Option<position::Position>, sqlx::Error>;
+    async fn select_positions(
+        &self,
+        user: String,
+    ) -> Result<Vec<export::ExportPosition>, sqlx::Error>;
+    /// Writes what an import found in the library in a single transaction,
+    /// gives the number of positions saved and of collections that changed
+    async fn insert_import(
+        &self,
+        user: String,
+        account: Option<&export::ExportAccount>,
+        positions: &[export::ImportPosition],
+        collections: &[export::ImportCollection],
+    ) -> Result<(usize, usize), sqlx::Error>;
+    async fn query_audiobook_ref(&self, hash: String) -> Result<export::AudiobookRef, sqlx::Error>;
+    async fn query_audiobook_hash(
+        &self,
+        title: String,
+        author: String,
+        user: String,
+    ) -> Result<String, sqlx::Error>;
+
+    async fn insert_user(&self, user: String, password: String) -> Result<String, sqlx::Error>;
+    /// Creates an account made by an administrator, possibly an administrator
+    /// itself, in a single statement
+    async fn insert_admin_user(
+        &self,
+        user: String,
+        password: String,
+        is_admin: bool,
+    ) -> Result<String, sqlx::Error>;
+    async fn insert_user_with_invite(
+        &self,
+        user: String,
+        password: String,
+        code: String,
+    ) -> Result<String, sqlx::Error>;
+    async fn select_user(&self, user: String, password: String) -> Result<String, sqlx::Error>;
+    async fn query_user(&self, key: String) -> Result<String, sqlx::Error>;
+    /// Queries the key of a user authenticated by a reverse proxy, creating
+    /// the account on their first request unless another account has that
+    /// name in any case
+    async fn query_or_insert_user_key(&self, user: String) -> Result<String, sqlx::Error>;
+    /// Key of the account an ldap user logs into, created on their first
+    /// login. Accounts that were not created from ldap are never given out,
+    /// even when their name only differs by case.
+    async fn query_or_insert_ldap_key(&self, user: String) -> Result<String, sqlx::Error>;
+    /// Disables an account created from ldap, other accounts are left alone
+    async fn update_ldap_disabled(&self, user: String) -> Result<(), sqlx::Error>;
+    async fn query_admin(&self, key: String) -> Result<String, sqlx::Error>;
+    async fn query_accounts(&self) -> Result<account::Accounts, sqlx::Error>;
+    async fn query_account(&self, user: String) -> Result<String, sqlx::Error>;
+    async fn query_account_nocase(&self, user: String) -> Result<String, sqlx::Error>;
+    async fn update_admin(&self, user: String, is_admin: bool) -> Result<(), sqlx::Error>;
+    async fn update_disabled(&self, user: String, disabled: bool) -> Result<(), sqlx::Error>;
+    /// Changes the password of a user and closes all their sessions
+    async fn update_password(&self, user: String, password: String) -> Result<(), sqlx::Error>;
+    async fn update_key(&self, user: String) -> Result<String, sqlx::Error>;
+    async fn update_user(&self, user: String, name: String) -> Result<(), sqlx::Error>;
+    async fn delete_user(&self, user: String) -> Result<(), sqlx::Error>;
+
+    async fn insert_session(
+        &self,
+        user: String,
+        lifetimes: &session::Lifetimes,
+    ) -> Result<session::Session, sqlx::Error>;
+    async fn update_session(
+        &self,
+        refresh_token: String,
+        lifetimes: &session::Lifetimes,
+    ) -> Result<session::Session, sqlx::Error>;
+    /// Closes the session an access or a refresh token belongs to
+    async fn delete_session(&self, token: String) -> Result<(), sqlx::Error>;
+
+    async fn query_collections(&self, user: String)
+        -> Result<collection::Collections, sqlx::Error>;
+    async fn query_collection(
+        &self,
+        id: i64,
+        user: String,
+    ) -> Result<collection::Collection, sqlx::Error>;
+    async fn insert_collection(&self, user: String, name: String) -> Result<i64, sqlx::Error>;
+    async fn update_collection(&self, id: i64, name: String) -> Result<(), sqlx::Error>;
+    async fn delete_collection(&self, id: i64) -> Result<(), sqlx::Error>;
+    async fn insert_collection_audiobook(&self, id: i64, hash: String) -> Result<(), sqlx::Error>;
+    async fn delete_collection_audiobook(&self, id: i64, hash: String) -> Result<(), sqlx::Error>;
+    async fn insert_collection_share(&self, id: i64, user: String) -> Result<(), sqlx::Error>;
+    async fn delete_collection_share(&self, id: i64, user: String) -> Result<(), sqlx::Error>;
+
+    async fn insert_invite(
+        &self,
+        uses: u32,
+        expires: Option<i64>,
+        created_by: String,
+    ) -> Result<invite::Invite, sqlx::Error>;
+    async fn query_invites(&self) -> Result<invite::Invites, sqlx::Error>;
+    async fn delete_invite(&self, code: String) -> Result<(), sqlx::Error>;
+
+    async fn query_access(&self) -> Result<access::Access, sqlx::Error>;
+    async fn insert_access_rule(
+        &self,
+        rule: &access::NewAccessRule,
+    ) -> Result<access::AccessRule, sqlx::Error>;
+    async fn delete_access_rule(&self, id: i64) -> Result<(), sqlx::Error>;
+    async fn insert_group_member(&self, group: String, user: String) -> Result<(), sqlx::Error>;
+    async fn delete_group_member(&self, group: String, user: String) -> Result<(), sqlx::Error>;
+}
+
+/// Tells whether an insert or update failed on a unique constraint, like a
+/// username taken in another case by a concurrent request
+pub fn is_unique_violation(err: &sqlx::Error) -> bool {
+    match err {
+        // sqlite extended codes of unique and primary key constraints, then
+        // the postgresql one
+        sqlx::Error::Database(err) => matches!(
+            err.code().as_deref(),
+            Some("2067") | Some("1555") | Some("23505")
+        ),
+        _ => false,
+    }
+}
+
+/// Scans the data directory into the library. Books that did not change since
+/// the last scan are left alone, the report tells which ones were added or
+/// updated and which audio files have no known duration.
+pub async fn scan_audiobooks(
+    dir: &PathBuf,
+    db: &dyn Storage,
+) -> Result<audiobook::ScanReport, sqlx::Error> {
+    let audiobooks = schema::scan_audiobook_direcories(Path::new(dir));
+
+    let mut report = audiobook::ScanReport {
+        audiobooks: audiobooks.len(),
+        added: 0,
+        updated: 0,
+        unchanged: 0,
+        no_duration: Vec::new(),
+    };
+
+    for mut audiobook in audiobooks {
+        for file in audiobook.files.iter() {
+            if file.duration.is_none() {
+                report
+                    .no_duration
+                    .push(format!("{}/{}", audiobook.directory, file.name));
+            }
+        }
+
+        // the library keeps tags as a set
+        audiobook.tags.sort();
+        audiobook.tags.dedup();
+
+        let hash = audiobook::compute_hash(audiobook.title.clone(), audiobook.author.clone());
+        match db.query_scanned_audiobook(hash).await? {
+            Some(existing) if existing == audiobook => {
+                report.unchanged += 1;
+                continue;
+            }
+            Some(_) => {
+                report.updated += 1;
+            }
+            None => {
+                report.added += 1;
+            }
+        };
+
+        db.insert_audiobook(audiobook).await?;
+    }
+
+    Ok(report)
+}
+
+/// Gathers the positions and collections of a user into an export
+pub async fn export_user(user: String, db: &dyn Storage) -> Result<export::Export, sqlx::Error> {
+    let positions = db.select_positions(user.clone()).await?;
+
+    let mut collections = Vec::new();
+    for collection in db.query_collections(user.clone()).await?.collections {
+        if collection.owner != user {
+            continue;
+        }
+
+        let mut audiobooks = Vec::new();
+        for hash in collection.audiobooks {
+            audiobooks.push(db.query_audiobook_ref(hash).await?);
+        }
+
+        collections.push(export::ExportCollection {
+            name: collection.name,
+            audiobooks,
+            shared_with: collection.shared_with,
+        });
+    }
+
+    let account = db
+        .query_accounts()
+        .await?
+        .accounts
+        .into_iter()
+        .find(|account| account.user == user)
+        .map(|account| export::ExportAccount {
+            is_admin: account.is_admin,
+            disabled: account.disabled,
+        });
+
+    Ok(export::Export {
+        version: export::EXPORT_VERSION,
+        user,
+        exported_at: chrono::Utc::now().timestamp(),
+        account,
+        positions,
+        collections,
+    })
+}
+
+/// Finds an audiobook of an export in the library, noting it as missing when
+/// the user can not see any book with that title and author
+async fn find_audiobook(
+    audiobook: &export::AudiobookRef,
+    user: &str,
+    missing: &mut Vec<export::AudiobookRef>,
+    db: &dyn Storage,
+) -> Result<Option<String>, sqlx::Error> {
+    match db
+        .query_audiobook_hash(
+            audiobook.title.clone(),
+            audiobook.author.clone(),
+            user.to_string(),
+        )
+        .await
+    {
+        Ok(hash) => Ok(Some(hash)),
+        Err(sqlx::Error::RowNotFound) => {
+            if !missing.contains(audiobook) {
+                missing.push(audiobook.clone());
+            }
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Imports an export into the account of `user`. Positions replace the ones
+/// saved for the same books unless those are more recent, collections are
+/// merged into the ones of the same name, so importing the same file twice
+/// changes nothing. The books are looked up first, then everything is written
+/// in a single transaction. The flags of the account are only restored when
+/// `restore_account` is set, a user importing into their own account could
+/// otherwise make themselves an administrator.
+pub async fn import_user(
+    user: String,
+    data: &export::Export,
+    restore_account: bool,
+    db: &dyn Storage,
+) -> Result<export::ImportReport, sqlx::Error> {
+    let mut missing = Vec::new();
+
+    let mut positions = Vec::new();
+    for position in data.positions.iter() {
+        let hash = match find_audiobook(&position.audiobook, &user, &mut missing, db).await? {
+            Some(hash) => hash,
+            None => {
+                continue;
+            }
+        };
+
+        // two copies of a book can be split in different files, a position
+        // in a file this copy does not have can not be resumed
+        match db
+            .query_audiobook_file(hash.clone(), position.file.clone())
+            .await
+        {
+            Ok(_) => {}
+            Err(sqlx::Error::RowNotFound) => {
+                continue;
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        };
+
+        positions.push(export::ImportPosition {
+            hash,
+            file: position.file.clone(),
+            position_ms: position.position_ms,
+            speed: position.speed,
+            chapter: position.chapter,
+            last_modified: position.last_modified,
+        });
+    }
+
+    let mut collections = Vec::new();
+    for collection in data.collections.iter() {
+        let mut audiobooks = Vec::new();
+        for audiobook in collection.audiobooks.iter() {
+            if let Some(hash) = find_audiobook(audiobook, &user, &mut missing, db).await? {
+                audiobooks.push(hash);
+            }
+        }
+
+        // a collection none of whose books are here would only be an empty
+        // shell, empty collections of the export are still created
+        if audiobooks.is_empty() && !collection.audiobooks.is_empty() {
+            continue;
+        }
+
+        // shares with people who have no account here are dropped
+        let mut shared_with = Vec::new();
+        for share in collection.shared_with.iter() {
+            if *share == user {
+                continue;
+            }
+            match db.query_account(share.clone()).await {
+                Ok(share) => {
+                    shared_with.push(share);
+                }
+                Err(sqlx::Error::RowNotFound) => {}
+                Err(err) => {
+                    return Err(err);
+                }
+            };
+        }
+
+        collections.push(export::ImportCollection {
+            name: collection.name.clone(),
+            audiobooks,
+            shared_with,
+        });
+    }
+
+    let account = match restore_account {
+        true => data.account.as_ref(),
+        false => None,
+    };
+    let (positions, collections) = db
+        .insert_import(user, account, &positions, &collections)
+        .await?;
+
+    Ok(export::ImportReport {
+        positions,
+        collections,
+        missing,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::sqlite::SqliteStorage;
+
+    async fn storage() -> SqliteStorage {
+        let path =
+            std::env::temp_dir().join(format!("illiad-storage-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = schema::create_pool(
+            path,
+            schema::PoolSettings {
+                max_connections: 5,
+                busy_timeout: std::time::Duration::from_secs(5),
+            },
+        )
+        .await;
+        let db = SqliteStorage::new(pool);
+        db.migrate().await.unwrap();
+        db
+    }
+
+    fn book(title: &str, author: &str) -> audiobook::Audiobook {
+        audiobook::Audiobook {
+            title: String::from(title),
+            author: String::from(author),
+            path: format!("/data/{}", title),
+            narrator: None,
+            description: None,
+            series: None,
+            series_index: None,
+            genre: None,
+            language: None,
+            tags: Vec::new(),
+            directory: String::from(title),
+            files: vec![audiobook::AudiobookFile {
+                name: String::from("01.mp3"),
+                duration: None,
+            }],
+        }
+    }
+
+    #[rocket::async_test]
+    async fn moves_positions_and_collections_between_accounts() {
+        let db = storage().await;
+        for user in ["alice", "bob", "carol"] {
+            db.insert_user(String::from(user), String::from("secret"))
+                .await
+                .unwrap();
+        }
+        db.insert_audiobook(book("Dune", "Frank Herbert"))
+            .await
+            .unwrap();
+        db.insert_audiobook(book("Emma", "Jane Austen"))
+            .await
+            .unwrap();
+        let dune = audiobook::compute_hash(String::from("Dune"), String::from("Frank Herbert"));
+        let emma = audiobook::compute_hash(String::from("Emma"), String::from("Jane Austen"));
+
+        db.insert_position(
+            dune.clone(),
+            String::from("alice"),
+            String::from("01.mp3"),
+            42500,
+            Some(1.25),
+            None,
+        )
+        .await
+        .unwrap();
+        let id = db
+            .insert_collection(String::from("alice"), String::from("Commute"))
+            .await
+            .unwrap();
+        db.insert_collection_audiobook(id, dune.clone())
+            .await
+            .unwrap();
+        db.insert_collection_audiobook(id, emma).await.unwrap();
+        db.insert_collection_share(id, String::from("carol"))
+            .await
+            .unwrap();
+
+        let mut data = export_user(String::from("alice"), &db).await.unwrap();
+        assert_eq!(data.positions.len(), 1);
+        assert_eq!(data.collections[0].audiobooks.len(), 2);
+
+        // books missing from the library and unknown users are left out
+        let missing = export::AudiobookRef {
+            title: String::from("Ulysses"),
+            author: String::from("James Joyce"),
+        };
+        data.positions.push(export::ExportPosition {
+            audiobook: missing.clone(),
+            file: String::from("01.mp3"),
+            position_ms: 1000,
+            speed: None,
+            chapter: None,
+            last_modified: None,
+        });
+        data.collections[0].shared_with.push(String::from("zoe"));
+
+        let report = import_user(String::from("bob"), &data, false, &db)
+            .await
+            .unwrap();
+        assert_eq!((report.positions, report.collections), (1, 1));
+        assert!(report.missing == [missing.clone()]);
+        // a second import finds nothing left to change
+        let report = import_user(String::from("bob"), &data, false, &db)
+            .await
+            .unwrap();
+        assert_eq!((report.positions, report.collections), (0, 0));
+        assert!(report.missing == [missing]);
+
+        let position = db
+            .select_position(dune, String::from("bob"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((position.position_ms, position.speed), (42500, Some(1.25)));
+        // importing twice merged the collection into the first import
+        let collections: Vec<collection::Collection> = db
+            .query_collections(String::from("bob"))
+            .await
+            .unwrap()
+            .collections
+            .into_iter()
+            .filter(|collection| collection.owner == "bob")
+            .collect();
+        assert_eq!(collections.len(), 1);
+        assert_eq!(collections[0].audiobooks.len(), 2);
+        assert_eq!(collections[0].shared_with, ["carol"]);
+    }
+}
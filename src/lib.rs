@@ -0,0 +1,6 @@
+extern crate rocket;
+
+pub mod api;
+pub mod database;
+pub mod models;
+pub mod utils;
@@ -3,44 +3,215 @@ extern crate rocket;
 // use tokio;
 // use tokio::sync::Mutex;
 
-mod api;
-mod database;
-mod models;
-mod utils;
+use illiad::{api, database, models, utils};
 
 #[rocket::main]
-async fn main() -> Result<(), rocket::Error> {
+async fn main() -> Result<(), Box<rocket::Error>> {
     let config = utils::cli::parse_args();
 
-    let pool = database::schema::create_pool(config.sql).await;
-    // let shared_pool = Arc::new(Mutex::new(pool));
+    // the database must not be open while it is replaced
+    if let utils::cli::Command::Restore(path) = &config.command {
+        if config.postgres_url.is_some() {
+            eprintln!("Could not restore the database: restore postgresql with pg_restore");
+            std::process::exit(1);
+        }
+        match database::backup::restore(path, &config.sql).await {
+            Ok(_) => {
+                println!("Restored the database from '{}'", path.display());
+            }
+            Err(err) => {
+                eprintln!("Could not restore the database: {}", err);
+                std::process::exit(1);
+            }
+        };
+        return Ok(());
+    }
 
-    match database::schema::create_accounts(&pool).await {
-        Ok(_) => {}
-        Err(_) => {
-            eprintln!("Could not create account");
+    // reports pending migrations rather than applying them, on a database
+    // that is only read
+    if let utils::cli::Command::CheckConfig = &config.command {
+        utils::commands::check_config(&config).await;
+        return Ok(());
+    }
+
+    // taken before migrating, so a backup made ahead of an upgrade can still
+    // be read by the previous version. the database is opened as it is, a
+    // missing one is not created just to back it up
+    if let utils::cli::Command::Backup(path) = &config.command {
+        if config.postgres_url.is_some() {
+            eprintln!("Could not back up the database: back postgresql up with pg_dump");
             std::process::exit(1);
         }
+        let pool = match database::schema::open_pool(&config.sql, true).await {
+            Ok(pool) => pool,
+            Err(err) => {
+                eprintln!(
+                    "Could not open the database '{}': {}",
+                    config.sql.display(),
+                    err
+                );
+                std::process::exit(1);
+            }
+        };
+        match database::backup::backup(path, &pool).await {
+            Ok(_) => {
+                println!("Backed up the database to '{}'", path.display());
+            }
+            Err(err) => {
+                eprintln!("Could not back up the database: {}", err);
+                std::process::exit(1);
+            }
+        };
+        return Ok(());
+    }
+
+    let (db, sqlite_pool): (Box<dyn database::storage::Storage>, _) = match config
+        .postgres_url
+        .clone()
+    {
+        #[cfg(feature = "postgres")]
+        Some(url) => {
+            match database::postgres::PostgresStorage::connect(&url, config.pool.max_connections)
+                .await
+            {
+                Ok(db) => (Box::new(db), None),
+                Err(err) => {
+                    eprintln!("Could not connect to the postgresql database: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => {
+            let pool = database::schema::create_pool(config.sql.clone(), config.pool).await;
+            (
+                Box::new(database::sqlite::SqliteStorage::new(pool.clone())),
+                Some(pool),
+            )
+        }
     };
 
-    match database::schema::create_positions(&pool).await {
-        Ok(_) => {}
-        Err(_) => {
-            eprintln!("Could not create positions");
+    match db.migrate().await {
+        Ok(applied) => {
+            if applied > 0 {
+                println!(
+                    "Migrated the database to schema version {}",
+                    database::migrations::latest_version()
+                );
+            }
+        }
+        Err(err) => {
+            eprintln!("Could not migrate the database: {}", err);
             std::process::exit(1);
         }
     };
 
-    match database::schema::scan_audiobooks(&config.data, &pool).await {
+    match &config.command {
+        utils::cli::Command::Serve => {}
+        utils::cli::Command::DbMigrate => {
+            return Ok(());
+        }
+        utils::cli::Command::Scan => {
+            utils::commands::scan(&config.data, db.as_ref()).await;
+            return Ok(());
+        }
+        utils::cli::Command::UserAdd {
+            user,
+            admin,
+            password_stdin,
+        } => {
+            utils::commands::user_add(user, *admin, *password_stdin, &config.rules, db.as_ref())
+                .await;
+            return Ok(());
+        }
+        utils::cli::Command::UserList => {
+            utils::commands::user_list(db.as_ref()).await;
+            return Ok(());
+        }
+        utils::cli::Command::UserDelete(user) => {
+            utils::commands::user_delete(user, db.as_ref()).await;
+            return Ok(());
+        }
+        utils::cli::Command::UserPasswd {
+            user,
+            password_stdin,
+        } => {
+            utils::commands::user_passwd(user, *password_stdin, &config.rules, db.as_ref()).await;
+            return Ok(());
+        }
+        utils::cli::Command::KeyRevoke(user) => {
+            utils::commands::key_revoke(user, db.as_ref()).await;
+            return Ok(());
+        }
+        utils::cli::Command::Export(user, path) => {
+            utils::commands::export(user, path, db.as_ref()).await;
+            return Ok(());
+        }
+        utils::cli::Command::Import(user, path) => {
+            utils::commands::import(user, path, db.as_ref()).await;
+            return Ok(());
+        }
+        // handled before the database is opened
+        utils::cli::Command::CheckConfig
+        | utils::cli::Command::Backup(_)
+        | utils::cli::Command::Restore(_) => {
+            return Ok(());
+        }
+    };
+
+    if let Some(admin) = config.admin.clone() {
+        if db.query_account(admin.clone()).await.is_err() {
+            let password = models::account::generate_key();
+            match db.insert_user(admin.clone(), password.clone()).await {
+                Ok(_) => {
+                    println!("Created account '{}' with password '{}'", admin, password);
+                }
+                Err(_) => {
+                    eprintln!("Could not create admin account");
+                    std::process::exit(1);
+                }
+            };
+        }
+
+        match db.update_admin(admin, true).await {
+            Ok(_) => {}
+            Err(_) => {
+                eprintln!("Could not grant administrator rights");
+                std::process::exit(1);
+            }
+        };
+    }
+
+    match database::storage::scan_audiobooks(&config.data, db.as_ref()).await {
         Ok(_) => {}
         Err(_) => {
-            eprintln!("Could not create positions");
+            eprintln!("Could not scan audiobooks");
             std::process::exit(1);
         }
     };
 
-    let rocket =
-        api::routes::create_rocket(config.port, config.register, config.address.into(), pool);
+    if let (Some(backup), Some(pool)) = (config.backup, sqlite_pool) {
+        database::backup::schedule(backup, pool);
+    }
+
+    let proxy_auth = config
+        .proxy_auth_header
+        .clone()
+        .map(|header| api::routes::ProxyAuth {
+            header,
+            proxies: config.limits.trusted_proxies.clone(),
+        });
+
+    let state = api::routes::ServerState {
+        register: config.register,
+        data: config.data,
+        limiter: utils::limiter::RateLimiter::new(config.limits),
+        rules: config.rules,
+        lifetimes: config.lifetimes,
+        proxy_auth,
+        ldap: config.ldap,
+    };
+
+    let rocket = api::routes::create_rocket(config.port, config.address.into(), state, db);
 
     let _ = rocket.launch().await?;
     Ok(())
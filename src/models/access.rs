@@ -0,0 +1,42 @@
+use rocket::serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub struct Access {
+    pub rules: Vec<AccessRule>,
+    pub groups: Vec<Group>,
+}
+
+/// Gives a user or the members of a group access to a part of the library
+#[derive(Serialize)]
+pub struct AccessRule {
+    pub id: i64,
+    /// Either `user` or `group`
+    pub subject_kind: String,
+    pub subject: String,
+    /// Either `prefix` (of the book directory), `tag` or `grant` (of a hash)
+    pub kind: String,
+    pub value: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewAccessRule {
+    pub subject_kind: String,
+    pub subject: String,
+    pub kind: String,
+    pub value: String,
+}
+
+impl NewAccessRule {
+    pub fn is_valid(&self) -> bool {
+        ["user", "group"].contains(&self.subject_kind.as_str())
+            && ["prefix", "tag", "grant"].contains(&self.kind.as_str())
+            && !self.subject.is_empty()
+            && !self.value.is_empty()
+    }
+}
+
+#[derive(Serialize)]
+pub struct Group {
+    pub name: String,
+    pub members: Vec<String>,
+}
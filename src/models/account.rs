@@ -13,6 +13,49 @@ pub struct Account {
 pub struct NewAccount {
     pub user: String,
     pub password: String,
+    /// Invite code, required to register when the server is invite only
+    pub invite: Option<String>,
+    /// Asks login for expiring session tokens instead of the api key
+    #[serde(default)]
+    pub session: bool,
+}
+
+#[derive(serde::Serialize)]
+pub struct Accounts {
+    pub accounts: Vec<AccountFmt>,
+}
+
+#[derive(serde::Serialize)]
+pub struct AccountFmt {
+    pub user: String,
+    pub is_admin: bool,
+    pub disabled: bool,
+}
+
+/// Account created by an administrator
+#[derive(serde::Deserialize)]
+pub struct AdminNewAccount {
+    pub user: String,
+    pub password: String,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewPassword {
+    pub password: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasswordChange {
+    pub old_password: String,
+    pub password: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct Rename {
+    pub user: String,
+    pub password: String,
 }
 
 /// Computes the api key for the account
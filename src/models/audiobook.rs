@@ -1,16 +1,37 @@
 use crc32fast::Hasher;
-use rocket::serde::Serialize;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::FromForm;
 
-#[derive(Serialize)]
+#[derive(Serialize, PartialEq)]
 pub struct Audiobook {
     pub title: String,
     pub author: String,
     pub path: String,
+    pub narrator: Option<String>,
+    pub description: Option<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub genre: Option<String>,
+    pub language: Option<String>,
+    pub tags: Vec<String>,
+    /// Name of the directory of the book, relative to the data directory
+    pub directory: String,
+    pub files: Vec<AudiobookFile>,
+}
+
+/// Audio file of a book, as named in the archive of the book
+#[derive(Serialize, PartialEq)]
+pub struct AudiobookFile {
+    pub name: String,
+    /// Length of the file in seconds, unknown when it could not be read
+    pub duration: Option<f64>,
 }
 
 #[derive(Serialize)]
 pub struct Audiobooks {
     pub audiobooks: Vec<AudiobookFmt>,
+    pub total: i64,
+    pub next: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -18,11 +39,243 @@ pub struct AudiobookFmt {
     pub hash: String,
     pub title: String,
     pub author: String,
+    pub narrator: Option<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub genre: Option<String>,
+    pub language: Option<String>,
+}
+
+/// What a scan of the data directory found
+#[derive(Serialize)]
+pub struct ScanReport {
+    /// Number of audiobooks in the data directory
+    pub audiobooks: usize,
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    /// Audio files whose duration could not be read, as `directory/file`
+    pub no_duration: Vec<String>,
+}
+
+/// Search, filter, sort and pagination parameters of `GET /audiobooks`
+#[derive(FromForm)]
+pub struct AudiobookQuery {
+    pub q: Option<String>,
+    pub author: Option<String>,
+    pub series: Option<String>,
+    pub genre: Option<String>,
+    pub language: Option<String>,
+    pub status: Option<String>,
+    pub collection: Option<i64>,
+    pub sort: Option<String>,
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+/// Default number of audiobooks in a page
+const DEFAULT_PAGE_SIZE: u32 = 50;
+/// Maximum number of audiobooks a client may ask for in a single page
+const MAX_PAGE_SIZE: u32 = 200;
+
+pub enum AudiobookSort {
+    Relevance,
+    Title,
+    TitleDesc,
+    Author,
+    AuthorDesc,
+    Series,
+}
+
+/// A column the audiobooks are sorted by
+#[derive(Clone, Copy)]
+pub enum SortKey {
+    /// Relevance of the book to the search, lower is more relevant
+    Rank,
+    Title,
+    Author,
+    /// Books without a series come after the others
+    NoSeries,
+    Series,
+    SeriesIndex,
+    Hash,
+}
+
+impl AudiobookSort {
+    /// The keys of the sort and whether each is descending, the hash comes
+    /// last so that no two books are ever equal
+    pub fn keys(&self, ranked: bool) -> Vec<(SortKey, bool)> {
+        match self {
+            AudiobookSort::Relevance if ranked => vec![
+                (SortKey::Rank, false),
+                (SortKey::Title, false),
+                (SortKey::Hash, false),
+            ],
+            AudiobookSort::Relevance | AudiobookSort::Title => {
+                vec![(SortKey::Title, false), (SortKey::Hash, false)]
+            }
+            AudiobookSort::TitleDesc => vec![(SortKey::Title, true), (SortKey::Hash, true)],
+            AudiobookSort::Author => vec![
+                (SortKey::Author, false),
+                (SortKey::Title, false),
+                (SortKey::Hash, false),
+            ],
+            AudiobookSort::AuthorDesc => vec![
+                (SortKey::Author, true),
+                (SortKey::Title, true),
+                (SortKey::Hash, true),
+            ],
+            AudiobookSort::Series => vec![
+                (SortKey::NoSeries, false),
+                (SortKey::Series, false),
+                (SortKey::SeriesIndex, false),
+                (SortKey::Title, false),
+                (SortKey::Hash, false),
+            ],
+        }
+    }
+}
+
+/// Value of a sort key, as bound to a query
+pub enum SortValue {
+    Text(String),
+    Real(f64),
+}
+
+/// The sort keys of the last audiobook of a page, the next page starts right
+/// after it so books added or removed meanwhile do not shift the pages
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct AudiobookCursor {
+    pub rank: f64,
+    pub title: String,
+    pub author: String,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub hash: String,
+}
+
+impl AudiobookCursor {
+    pub fn new(audiobook: &AudiobookFmt, rank: f64) -> AudiobookCursor {
+        AudiobookCursor {
+            rank,
+            title: audiobook.title.clone(),
+            author: audiobook.author.clone(),
+            series: audiobook.series.clone(),
+            series_index: audiobook.series_index,
+            hash: audiobook.hash.clone(),
+        }
+    }
+
+    /// Encodes the cursor for clients, in hex so it needs no escaping in urls
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        json.bytes().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Decodes a cursor given by a client, none if it was not made by `encode`
+    pub fn decode(cursor: &str) -> Option<AudiobookCursor> {
+        if !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+            return None;
+        }
+        let bytes = (0..cursor.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    pub fn value(&self, key: SortKey) -> SortValue {
+        match key {
+            SortKey::Rank => SortValue::Real(self.rank),
+            SortKey::Title => SortValue::Text(self.title.clone()),
+            SortKey::Author => SortValue::Text(self.author.clone()),
+            SortKey::NoSeries => SortValue::Real(if self.series.is_none() { 1.0 } else { 0.0 }),
+            SortKey::Series => SortValue::Text(self.series.clone().unwrap_or_default()),
+            SortKey::SeriesIndex => SortValue::Real(self.series_index.unwrap_or(0.0)),
+            SortKey::Hash => SortValue::Text(self.hash.clone()),
+        }
+    }
+}
+
+pub enum AudiobookStatus {
+    New,
+    Started,
+}
+
+/// A validated `AudiobookQuery`, ready to be turned into sql
+pub struct AudiobookSearch {
+    pub q: Option<String>,
+    pub author: Option<String>,
+    pub series: Option<String>,
+    pub genre: Option<String>,
+    pub language: Option<String>,
+    pub status: Option<AudiobookStatus>,
+    pub collection: Option<i64>,
+    pub sort: AudiobookSort,
+    pub after: Option<AudiobookCursor>,
+    pub limit: i64,
+}
+
+impl AudiobookQuery {
+    /// Validates the query, returns none if a parameter has an unknown value
+    pub fn parse(&self) -> Option<AudiobookSearch> {
+        let sort = match self.sort.as_deref() {
+            None => {
+                if self.q.is_some() {
+                    AudiobookSort::Relevance
+                } else {
+                    AudiobookSort::Title
+                }
+            }
+            Some("relevance") => AudiobookSort::Relevance,
+            Some("title") => AudiobookSort::Title,
+            Some("-title") => AudiobookSort::TitleDesc,
+            Some("author") => AudiobookSort::Author,
+            Some("-author") => AudiobookSort::AuthorDesc,
+            Some("series") => AudiobookSort::Series,
+            Some(_) => return None,
+        };
+
+        let status = match self.status.as_deref() {
+            None => None,
+            Some("new") => Some(AudiobookStatus::New),
+            Some("started") => Some(AudiobookStatus::Started),
+            Some(_) => return None,
+        };
+
+        let after = match self.cursor.as_deref() {
+            None => None,
+            Some(cursor) => Some(AudiobookCursor::decode(cursor)?),
+        };
+
+        let limit = match self.limit {
+            None => DEFAULT_PAGE_SIZE,
+            Some(0) => return None,
+            Some(limit) => limit.min(MAX_PAGE_SIZE),
+        };
+
+        Some(AudiobookSearch {
+            q: self.q.clone(),
+            author: self.author.clone(),
+            series: self.series.clone(),
+            genre: self.genre.clone(),
+            language: self.language.clone(),
+            status,
+            collection: self.collection,
+            sort,
+            after,
+            limit: limit as i64,
+        })
+    }
 }
 
 /// Computes the hash of a audiobook
 pub fn compute_hash(title: String, author: String) -> String {
-    let input = format!("{}{}", title, author);
+    compute_id(format!("{}{}", title, author))
+}
+
+/// Computes a short stable identifier out of a string
+pub fn compute_id(input: String) -> String {
     let mut hasher = Hasher::new();
     hasher.update(input.as_bytes());
     let crc = hasher.finalize();
@@ -0,0 +1,27 @@
+use rocket::serde::Serialize;
+
+use crate::models::audiobook;
+
+#[derive(Serialize)]
+pub struct Authors {
+    pub authors: Vec<AuthorFmt>,
+}
+
+#[derive(Serialize)]
+pub struct AuthorFmt {
+    pub id: String,
+    pub name: String,
+    pub count: i64,
+}
+
+#[derive(Serialize)]
+pub struct AuthorAudiobooks {
+    pub id: String,
+    pub name: String,
+    pub audiobooks: Vec<audiobook::AudiobookFmt>,
+}
+
+/// Computes the identifier of an author
+pub fn compute_id(name: String) -> String {
+    audiobook::compute_id(name)
+}
@@ -0,0 +1,21 @@
+use rocket::serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub struct Collections {
+    pub collections: Vec<Collection>,
+}
+
+#[derive(Serialize)]
+pub struct Collection {
+    pub id: i64,
+    pub name: String,
+    pub owner: String,
+    /// Accounts the collection is shared with, only given to its owner
+    pub shared_with: Vec<String>,
+    pub audiobooks: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NewCollection {
+    pub name: String,
+}
@@ -0,0 +1,85 @@
+use rocket::serde::{Deserialize, Serialize};
+
+/// Version of the export format, exports of a newer version are refused.
+/// Version 2 added the account and the time positions were saved at.
+pub const EXPORT_VERSION: u32 = 2;
+
+/// Everything a user owns on a server. Audiobooks are referred to by their
+/// title and author, their hashes only make sense on the server they are on.
+/// Illiad keeps no listening history or bookmarks yet, so there are none.
+#[derive(Serialize, Deserialize)]
+pub struct Export {
+    pub version: u32,
+    pub user: String,
+    /// Unix timestamp of the export
+    pub exported_at: i64,
+    /// Missing from exports of the first version
+    #[serde(default)]
+    pub account: Option<ExportAccount>,
+    #[serde(default)]
+    pub positions: Vec<ExportPosition>,
+    #[serde(default)]
+    pub collections: Vec<ExportCollection>,
+}
+
+/// Flags of the account, only restored by the `import` command of the server
+#[derive(Serialize, Deserialize)]
+pub struct ExportAccount {
+    pub is_admin: bool,
+    pub disabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct AudiobookRef {
+    pub title: String,
+    pub author: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportPosition {
+    pub audiobook: AudiobookRef,
+    pub file: String,
+    pub position_ms: u64,
+    pub speed: Option<f64>,
+    pub chapter: Option<u32>,
+    /// Unix timestamp of when the position was saved, unknown for positions
+    /// saved by older versions
+    #[serde(default)]
+    pub last_modified: Option<i64>,
+}
+
+/// Collection owned by the user, collections shared with them are left to
+/// their owner's export
+#[derive(Serialize, Deserialize)]
+pub struct ExportCollection {
+    pub name: String,
+    #[serde(default)]
+    pub audiobooks: Vec<AudiobookRef>,
+    #[serde(default)]
+    pub shared_with: Vec<String>,
+}
+
+/// What an import did, audiobooks the server does not have are skipped
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub positions: usize,
+    pub collections: usize,
+    pub missing: Vec<AudiobookRef>,
+}
+
+/// Position of an import, matched to a book of the library
+pub struct ImportPosition {
+    pub hash: String,
+    pub file: String,
+    pub position_ms: u64,
+    pub speed: Option<f64>,
+    pub chapter: Option<u32>,
+    pub last_modified: Option<i64>,
+}
+
+/// Collection of an import, with the books and accounts found on this server
+pub struct ImportCollection {
+    pub name: String,
+    pub audiobooks: Vec<String>,
+    pub shared_with: Vec<String>,
+}
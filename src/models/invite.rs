@@ -0,0 +1,23 @@
+use rocket::serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub struct Invites {
+    pub invites: Vec<Invite>,
+}
+
+#[derive(Serialize)]
+pub struct Invite {
+    pub code: String,
+    /// Number of accounts that can still register with this code
+    pub uses: i64,
+    /// Unix timestamp after which the code is no longer valid
+    pub expires: Option<i64>,
+    pub created_by: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewInvite {
+    pub uses: Option<u32>,
+    /// Number of seconds the code stays valid for
+    pub expires_in: Option<i64>,
+}
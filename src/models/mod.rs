@@ -1,3 +1,13 @@
+pub mod access;
 pub mod account;
+// the FromForm derive of rocket 0.5.0-rc.3 allows the removed
+// `private_in_public` lint
+#[allow(renamed_and_removed_lints)]
 pub mod audiobook;
+pub mod author;
+pub mod collection;
+pub mod export;
+pub mod invite;
 pub mod position;
+pub mod series;
+pub mod session;
@@ -1,5 +1,49 @@
-#[derive(serde::Serialize, serde::Deserialize)]
+use rocket::serde::{Deserialize, Serialize};
+
+/// Version of the position format answered by the api
+pub const POSITION_VERSION: u32 = 2;
+
+#[derive(Serialize)]
 pub struct Position {
+    pub version: u32,
     pub file: String,
-    pub position: u32,
+    /// Number of milliseconds into `file`
+    pub position_ms: u64,
+    /// Same position in whole seconds, for clients predating `position_ms`
+    pub position: u64,
+    /// Playback speed the user listens at
+    pub speed: Option<f64>,
+    /// Index of the chapter the position is in
+    pub chapter: Option<u32>,
+}
+
+/// Position sent by clients, older ones only send `position` in seconds
+#[derive(Deserialize)]
+pub struct NewPosition {
+    pub file: String,
+    pub position_ms: Option<u64>,
+    pub position: Option<u64>,
+    pub speed: Option<f64>,
+    pub chapter: Option<u32>,
+}
+
+impl NewPosition {
+    /// The position in milliseconds, `position_ms` wins over the legacy field
+    pub fn to_ms(&self) -> Option<u64> {
+        self.position_ms
+            .or_else(|| self.position.map(|seconds| seconds.saturating_mul(1000)))
+    }
+}
+
+impl Position {
+    pub fn new(file: String, position_ms: u64, speed: Option<f64>, chapter: Option<u32>) -> Self {
+        Self {
+            version: POSITION_VERSION,
+            file,
+            position_ms,
+            position: position_ms / 1000,
+            speed,
+            chapter,
+        }
+    }
 }
@@ -0,0 +1,30 @@
+use rocket::serde::Serialize;
+
+use crate::models::audiobook;
+
+#[derive(Serialize)]
+pub struct Series {
+    pub series: Vec<SeriesFmt>,
+}
+
+#[derive(Serialize)]
+pub struct SeriesFmt {
+    pub id: String,
+    pub name: String,
+    pub author: String,
+    pub count: i64,
+}
+
+#[derive(Serialize)]
+pub struct SeriesAudiobooks {
+    pub id: String,
+    pub name: String,
+    pub author: String,
+    pub audiobooks: Vec<audiobook::AudiobookFmt>,
+}
+
+/// Computes the identifier of a series, series of different authors may have
+/// the same name
+pub fn compute_id(name: String, author: String) -> String {
+    audiobook::compute_id(format!("{}\n{}", name, author))
+}
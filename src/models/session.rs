@@ -0,0 +1,21 @@
+use rocket::serde::{Deserialize, Serialize};
+
+/// Short lived access token along with the refresh token used to renew it
+#[derive(Serialize)]
+pub struct Session {
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Number of seconds the access token is valid for
+    pub expires_in: i64,
+}
+
+#[derive(Deserialize)]
+pub struct Refresh {
+    pub refresh_token: String,
+}
+
+/// Number of seconds access and refresh tokens are valid for
+pub struct Lifetimes {
+    pub access: i64,
+    pub refresh: i64,
+}
@@ -0,0 +1,427 @@
+use rocket_db_pools::sqlx;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::process::exit;
+
+use crate::database;
+use crate::database::migrations;
+use crate::database::storage::Storage;
+use crate::models::account;
+use crate::models::export;
+use crate::utils::cli::Config;
+use crate::utils::validation;
+
+/// Reads a password from the first line of stdin, so it does not end up in
+/// the shell history
+fn read_password() -> String {
+    let mut password = String::new();
+    if let Err(err) = std::io::stdin().lock().read_line(&mut password) {
+        eprintln!("Could not read the password: {}", err);
+        exit(1);
+    }
+    password.trim_end_matches(['\n', '\r']).to_string()
+}
+
+/// The password given on stdin if asked for, checked against the rules, or a
+/// generated one that is printed
+fn new_password(password_stdin: bool, rules: &validation::Rules) -> (String, bool) {
+    if !password_stdin {
+        return (account::generate_key(), true);
+    }
+
+    let password = read_password();
+    if let Err(err) = rules.check_password(&password) {
+        eprintln!("{}", err.msg);
+        exit(1);
+    }
+    (password, false)
+}
+
+pub async fn scan(data: &PathBuf, db: &dyn Storage) {
+    match database::storage::scan_audiobooks(data, db).await {
+        Ok(report) => {
+            println!(
+                "Found {} audiobooks in '{}': {} added, {} updated, {} unchanged",
+                report.audiobooks,
+                data.display(),
+                report.added,
+                report.updated,
+                report.unchanged
+            );
+            for file in report.no_duration {
+                println!("  - no duration: {}", file);
+            }
+        }
+        Err(err) => {
+            eprintln!("Could not scan audiobooks: {}", err);
+            exit(1);
+        }
+    };
+}
+
+pub async fn user_add(
+    user: &str,
+    admin: bool,
+    password_stdin: bool,
+    rules: &validation::Rules,
+    db: &dyn Storage,
+) {
+    if let Err(err) = rules.check_user(user) {
+        eprintln!("{}", err.msg);
+        exit(1);
+    }
+
+    match db.query_account_nocase(user.to_string()).await {
+        Ok(existing) => {
+            eprintln!("Could not create '{}': '{}' already exists", user, existing);
+            exit(1);
+        }
+        Err(sqlx::Error::RowNotFound) => {}
+        Err(err) => {
+            eprintln!("Could not create '{}': {}", user, err);
+            exit(1);
+        }
+    };
+
+    let (password, generated) = new_password(password_stdin, rules);
+    let key = match db.insert_user(user.to_string(), password.clone()).await {
+        Ok(key) => key,
+        Err(err) => {
+            eprintln!("Could not create '{}': {}", user, err);
+            exit(1);
+        }
+    };
+
+    if admin {
+        if let Err(err) = db.update_admin(user.to_string(), true).await {
+            eprintln!("Could not grant administrator rights: {}", err);
+            exit(1);
+        }
+    }
+
+    if generated {
+        println!("Created account '{}' with password '{}'", user, password);
+    } else {
+        println!("Created account '{}'", user);
+    }
+    println!("Its api key is '{}'", key);
+}
+
+pub async fn user_list(db: &dyn Storage) {
+    let accounts = match db.query_accounts().await {
+        Ok(accounts) => accounts,
+        Err(err) => {
+            eprintln!("Could not list the accounts: {}", err);
+            exit(1);
+        }
+    };
+
+    for account in accounts.accounts {
+        let mut flags = Vec::new();
+        if account.is_admin {
+            flags.push("admin");
+        }
+        if account.disabled {
+            flags.push("disabled");
+        }
+        if flags.is_empty() {
+            println!("{}", account.user);
+        } else {
+            println!("{} ({})", account.user, flags.join(", "));
+        }
+    }
+}
+
+pub async fn user_delete(user: &str, db: &dyn Storage) {
+    match db.delete_user(user.to_string()).await {
+        Ok(_) => {
+            println!("Deleted account '{}'", user);
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            eprintln!("Could not delete '{}': no such account", user);
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!("Could not delete '{}': {}", user, err);
+            exit(1);
+        }
+    };
+}
+
+pub async fn user_passwd(
+    user: &str,
+    password_stdin: bool,
+    rules: &validation::Rules,
+    db: &dyn Storage,
+) {
+    if db.query_account(user.to_string()).await.is_err() {
+        eprintln!(
+            "Could not change the password of '{}': no such account",
+            user
+        );
+        exit(1);
+    }
+
+    let (password, generated) = new_password(password_stdin, rules);
+    match db.update_password(user.to_string(), password.clone()).await {
+        Ok(_) => {
+            if generated {
+                println!("The password of '{}' is now '{}'", user, password);
+            } else {
+                println!("Changed the password of '{}'", user);
+            }
+        }
+        Err(err) => {
+            eprintln!("Could not change the password of '{}': {}", user, err);
+            exit(1);
+        }
+    };
+}
+
+pub async fn key_revoke(user: &str, db: &dyn Storage) {
+    match db.update_key(user.to_string()).await {
+        Ok(key) => {
+            println!("The api key of '{}' is now '{}'", user, key);
+        }
+        Err(sqlx::Error::RowNotFound) => {
+            eprintln!("Could not revoke the key of '{}': no such account", user);
+            exit(1);
+        }
+        Err(err) => {
+            eprintln!("Could not revoke the key of '{}': {}", user, err);
+            exit(1);
+        }
+    };
+}
+
+pub async fn export(user: &str, path: &Option<PathBuf>, db: &dyn Storage) {
+    if db.query_account(user.to_string()).await.is_err() {
+        eprintln!("Could not export '{}': no such account", user);
+        exit(1);
+    }
+
+    let export = match database::storage::export_user(user.to_string(), db).await {
+        Ok(export) => export,
+        Err(err) => {
+            eprintln!("Could not export '{}': {}", user, err);
+            exit(1);
+        }
+    };
+
+    let json = serde_json::to_string_pretty(&export).unwrap();
+    match path {
+        Some(path) => {
+            if let Err(err) = std::fs::write(path, json) {
+                eprintln!("Could not write '{}': {}", path.display(), err);
+                exit(1);
+            }
+        }
+        None => {
+            println!("{}", json);
+        }
+    };
+}
+
+pub async fn import(user: &str, path: &PathBuf, db: &dyn Storage) {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Could not read '{}': {}", path.display(), err);
+            exit(1);
+        }
+    };
+    let data = match serde_json::from_str::<export::Export>(&data) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Could not import '{}': {}", path.display(), err);
+            exit(1);
+        }
+    };
+    if data.version > export::EXPORT_VERSION {
+        eprintln!(
+            "Could not import '{}': it was exported by a newer version of illiad",
+            path.display()
+        );
+        exit(1);
+    }
+
+    if db.query_account(user.to_string()).await.is_err() {
+        let password = account::generate_key();
+        match db.insert_user(user.to_string(), password.clone()).await {
+            Ok(_) => {
+                println!("Created account '{}' with password '{}'", user, password);
+            }
+            Err(_) => {
+                eprintln!("Could not create account '{}'", user);
+                exit(1);
+            }
+        };
+    }
+
+    // the operator of the server can restore the flags of the account
+    match database::storage::import_user(user.to_string(), &data, true, db).await {
+        Ok(report) => {
+            println!(
+                "Imported {} positions and {} collections into '{}'",
+                report.positions, report.collections, user
+            );
+            for audiobook in report.missing {
+                println!(
+                    "  - not in the library: {} by {}",
+                    audiobook.title, audiobook.author
+                );
+            }
+        }
+        Err(err) => {
+            eprintln!("Could not import '{}': {}", path.display(), err);
+            exit(1);
+        }
+    };
+}
+
+/// Reads the schema version without writing anything, `None` when the sqlite
+/// database does not exist yet
+async fn read_schema_version(config: &Config) -> Result<Option<i64>, sqlx::Error> {
+    match config.postgres_url.clone() {
+        #[cfg(feature = "postgres")]
+        Some(url) => {
+            let db = database::postgres::PostgresStorage::connect(&url, 1).await?;
+            db.schema_version().await.map(Some)
+        }
+        _ => {
+            if !config.sql.exists() {
+                return Ok(None);
+            }
+            let pool = database::schema::open_pool(&config.sql, true).await?;
+            let version = migrations::current_version(&pool).await;
+            pool.close().await;
+            version.map(Some)
+        }
+    }
+}
+
+/// Reports the settings the config resolves to, and fails if the server
+/// could not start with them. The database is only read, a missing one is
+/// not created.
+pub async fn check_config(config: &Config) {
+    let mut valid = true;
+
+    if config.data.is_dir() {
+        println!("data: '{}'", config.data.display());
+    } else {
+        println!("data: '{}' is not a directory", config.data.display());
+        valid = false;
+    }
+
+    if config.postgres_url.is_some() {
+        println!("database: postgresql");
+    } else {
+        println!("database: '{}'", config.sql.display());
+    }
+
+    let latest = migrations::latest_version();
+    match read_schema_version(config).await {
+        Ok(None) => {
+            println!("schema: the database does not exist yet, it is created on the first start");
+        }
+        Ok(Some(version)) if version > latest => {
+            println!(
+                "schema: version {} is newer than this illiad, which knows up to {}",
+                version, latest
+            );
+            valid = false;
+        }
+        Ok(Some(version)) if version < latest => {
+            println!(
+                "schema: version {}, {} migrations are applied on the next start",
+                version,
+                latest - version
+            );
+        }
+        Ok(Some(version)) => {
+            println!("schema: version {}, up to date", version);
+        }
+        Err(err) => {
+            println!("schema: could not be read, {}", err);
+            valid = false;
+        }
+    };
+
+    println!("listening on: {}:{}", config.address, config.port);
+
+    if let Some(backup) = &config.backup {
+        println!(
+            "backups: every {} hours in '{}', keeping {}",
+            backup.interval.as_secs() / 3600,
+            backup.dir.display(),
+            backup.keep
+        );
+    }
+
+    if !valid {
+        eprintln!("The config has errors");
+        exit(1);
+    }
+    println!("The config is valid");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::schema;
+    use crate::database::sqlite::SqliteStorage;
+
+    async fn storage() -> SqliteStorage {
+        let path =
+            std::env::temp_dir().join(format!("illiad-commands-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = schema::create_pool(
+            path,
+            schema::PoolSettings {
+                max_connections: 5,
+                busy_timeout: std::time::Duration::from_secs(5),
+            },
+        )
+        .await;
+        let db = SqliteStorage::new(pool);
+        db.migrate().await.unwrap();
+        db
+    }
+
+    #[rocket::async_test]
+    async fn administers_accounts_from_the_command_line() {
+        let db = storage().await;
+        let rules = validation::Rules {
+            user_min_length: 3,
+            user_max_length: 32,
+            user_chars: String::from("-_."),
+            password_min_length: 8,
+            password_min_classes: 2,
+        };
+
+        user_add("alice", true, false, &rules, &db).await;
+        let accounts = db.query_accounts().await.unwrap().accounts;
+        assert_eq!(accounts.len(), 1);
+        assert!(accounts[0].is_admin);
+        let key = db
+            .query_or_insert_user_key(String::from("alice"))
+            .await
+            .unwrap();
+
+        key_revoke("alice", &db).await;
+        assert!(db.query_user(key).await.is_err());
+
+        // the generated password replaces the old one
+        db.update_password(String::from("alice"), String::from("secret"))
+            .await
+            .unwrap();
+        user_passwd("alice", false, &rules, &db).await;
+        assert!(db
+            .select_user(String::from("alice"), String::from("secret"))
+            .await
+            .is_err());
+
+        user_delete("alice", &db).await;
+        assert!(db.query_accounts().await.unwrap().accounts.is_empty());
+    }
+}
@@ -1,75 +1,465 @@
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+
+/// Answer of the api, on success as well as on errors. The numeric `code` is
+/// kept for older clients, newer ones should rely on the http status and on
+/// `kind`.
 #[derive(serde::Serialize)]
 pub struct Answer {
     pub code: u32,
+    pub kind: &'static str,
     pub msg: String,
+    /// Field of the request that was rejected, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+    #[serde(skip)]
+    pub status: Status,
 }
 
-pub fn success() -> Answer {
-    Answer {
-        code: 0,
-        msg: String::from("Success"),
+impl Answer {
+    fn new(status: Status, code: u32, kind: &'static str, msg: &str) -> Self {
+        Self {
+            code,
+            kind,
+            msg: String::from(msg),
+            field: None,
+            details: None,
+            status,
+        }
     }
 }
 
-pub fn not_found() -> Answer {
-    Answer {
-        code: 1,
-        msg: String::from("Error, endpoint does not exists"),
+impl<'r> Responder<'r, 'static> for Answer {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status;
+        Response::build_from(Json(self).respond_to(req)?)
+            .status(status)
+            .ok()
     }
 }
 
+pub fn success() -> Answer {
+    Answer::new(Status::Ok, 0, "success", "Success")
+}
+
+pub fn not_found() -> Answer {
+    Answer::new(
+        Status::NotFound,
+        1,
+        "endpoint_not_found",
+        "Error, endpoint does not exists",
+    )
+}
+
 pub fn cant_auth() -> Answer {
-    Answer {
-        code: 2,
-        msg: String::from("Error, could not authenticate"),
-    }
+    Answer::new(
+        Status::Unauthorized,
+        2,
+        "unauthorized",
+        "Error, could not authenticate",
+    )
 }
 
 pub fn position_cant_update() -> Answer {
-    Answer {
-        code: 3,
-        msg: String::from("Error, could not update position"),
-    }
+    Answer::new(
+        Status::InternalServerError,
+        3,
+        "position_update_failed",
+        "Error, could not update position",
+    )
 }
 
 pub fn hash_cant_query() -> Answer {
-    Answer {
-        code: 4,
-        msg: String::from("Error, hash not available"),
-    }
+    Answer::new(
+        Status::NotFound,
+        4,
+        "audiobook_not_found",
+        "Error, hash not available",
+    )
 }
 
 pub fn binary_cant_create() -> Answer {
-    Answer {
-        code: 5,
-        msg: String::from("Error, could not create binary data"),
-    }
+    Answer::new(
+        Status::InternalServerError,
+        5,
+        "archive_failed",
+        "Error, could not create binary data",
+    )
 }
 
 pub fn position_cant_query() -> Answer {
-    Answer {
-        code: 6,
-        msg: String::from("Error, could not get position"),
-    }
+    Answer::new(
+        Status::InternalServerError,
+        6,
+        "position_query_failed",
+        "Error, could not get position",
+    )
 }
 
 pub fn cant_register() -> Answer {
-    Answer {
-        code: 7,
-        msg: String::from("Error, could not register"),
-    }
+    Answer::new(
+        Status::InternalServerError,
+        7,
+        "register_failed",
+        "Error, could not register",
+    )
 }
 
 pub fn cant_login() -> Answer {
-    Answer {
-        code: 8,
-        msg: String::from("Error, could not login"),
-    }
+    Answer::new(
+        Status::Unauthorized,
+        8,
+        "login_failed",
+        "Error, could not login",
+    )
 }
 
 pub fn audiobooks_cant_query() -> Answer {
-    Answer {
-        code: 9,
-        msg: String::from("Error, could not query audiobooks"),
+    Answer::new(
+        Status::InternalServerError,
+        9,
+        "audiobooks_query_failed",
+        "Error, could not query audiobooks",
+    )
+}
+
+pub fn query_invalid() -> Answer {
+    Answer::new(
+        Status::UnprocessableEntity,
+        10,
+        "invalid_query",
+        "Error, invalid query parameters",
+    )
+}
+
+pub fn authors_cant_query() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        11,
+        "authors_query_failed",
+        "Error, could not query authors",
+    )
+}
+
+pub fn author_not_found() -> Answer {
+    Answer::new(
+        Status::NotFound,
+        12,
+        "author_not_found",
+        "Error, author not available",
+    )
+}
+
+pub fn series_cant_query() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        13,
+        "series_query_failed",
+        "Error, could not query series",
+    )
+}
+
+pub fn series_not_found() -> Answer {
+    Answer::new(
+        Status::NotFound,
+        14,
+        "series_not_found",
+        "Error, series not available",
+    )
+}
+
+pub fn collection_cant_query() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        15,
+        "collections_query_failed",
+        "Error, could not query collections",
+    )
+}
+
+pub fn collection_not_found() -> Answer {
+    Answer::new(
+        Status::NotFound,
+        16,
+        "collection_not_found",
+        "Error, collection not available",
+    )
+}
+
+pub fn collection_forbidden() -> Answer {
+    Answer::new(
+        Status::Forbidden,
+        17,
+        "collection_forbidden",
+        "Error, collection is not yours",
+    )
+}
+
+pub fn collection_cant_update() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        18,
+        "collection_update_failed",
+        "Error, could not update collection",
+    )
+}
+
+pub fn account_not_found() -> Answer {
+    Answer::new(
+        Status::NotFound,
+        19,
+        "account_not_found",
+        "Error, account not available",
+    )
+}
+
+pub fn not_admin() -> Answer {
+    Answer::new(
+        Status::Forbidden,
+        20,
+        "admin_required",
+        "Error, administrator rights required",
+    )
+}
+
+pub fn account_cant_update() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        21,
+        "account_update_failed",
+        "Error, could not update account",
+    )
+}
+
+pub fn library_cant_scan() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        22,
+        "scan_failed",
+        "Error, could not scan library",
+    )
+}
+
+pub fn invite_invalid() -> Answer {
+    Answer::new(
+        Status::Forbidden,
+        23,
+        "invalid_invite",
+        "Error, invalid or expired invite code",
+    )
+}
+
+pub fn invite_cant_update() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        24,
+        "invites_update_failed",
+        "Error, could not update invites",
+    )
+}
+
+pub fn access_rule_invalid() -> Answer {
+    Answer::new(
+        Status::UnprocessableEntity,
+        25,
+        "invalid_access_rule",
+        "Error, invalid access rule",
+    )
+}
+
+pub fn access_cant_update() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        26,
+        "access_update_failed",
+        "Error, could not update access rules",
+    )
+}
+
+pub fn password_invalid() -> Answer {
+    Answer::new(
+        Status::Forbidden,
+        27,
+        "wrong_password",
+        "Error, wrong password",
+    )
+}
+
+pub fn account_exists() -> Answer {
+    let mut answer = Answer::new(
+        Status::Conflict,
+        28,
+        "account_exists",
+        "Error, account already exists",
+    );
+    answer.field = Some(String::from("user"));
+    answer
+}
+
+pub fn too_many_attempts() -> Answer {
+    Answer::new(
+        Status::TooManyRequests,
+        29,
+        "too_many_attempts",
+        "Error, too many attempts, try again later",
+    )
+}
+
+pub fn field_invalid(field: &str, reason: &str) -> Answer {
+    let mut answer = Answer::new(
+        Status::UnprocessableEntity,
+        30,
+        "invalid_field",
+        &format!("Error, invalid {}: {}", field, reason),
+    );
+    answer.field = Some(String::from(field));
+    answer.details = Some(serde_json::json!({ "field": field, "reason": reason }));
+    answer
+}
+
+pub fn session_invalid() -> Answer {
+    Answer::new(
+        Status::Unauthorized,
+        31,
+        "invalid_session",
+        "Error, invalid or expired refresh token",
+    )
+}
+
+pub fn cant_modify_self() -> Answer {
+    Answer::new(
+        Status::Conflict,
+        32,
+        "self_modification",
+        "Error, administrators can not disable or delete themselves",
+    )
+}
+
+pub fn access_rule_not_found() -> Answer {
+    Answer::new(
+        Status::NotFound,
+        33,
+        "access_rule_not_found",
+        "Error, access rule not available",
+    )
+}
+
+pub fn request_invalid() -> Answer {
+    Answer::new(
+        Status::UnprocessableEntity,
+        34,
+        "invalid_request",
+        "Error, the request body is malformed",
+    )
+}
+
+pub fn bad_request() -> Answer {
+    Answer::new(
+        Status::BadRequest,
+        35,
+        "bad_request",
+        "Error, the request is malformed",
+    )
+}
+
+pub fn internal_error() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        36,
+        "internal_error",
+        "Error, internal server error",
+    )
+}
+
+pub fn position_not_found() -> Answer {
+    Answer::new(
+        Status::NotFound,
+        37,
+        "position_not_found",
+        "Error, no position saved for this audiobook",
+    )
+}
+
+pub fn export_cant_query() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        38,
+        "export_failed",
+        "Error, could not export the account",
+    )
+}
+
+pub fn import_cant_update() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        39,
+        "import_failed",
+        "Error, could not import the account",
+    )
+}
+
+pub fn export_version_invalid() -> Answer {
+    Answer::new(
+        Status::UnprocessableEntity,
+        40,
+        "unsupported_export_version",
+        "Error, the export was made by a newer version of illiad",
+    )
+}
+
+pub fn accounts_cant_query() -> Answer {
+    Answer::new(
+        Status::InternalServerError,
+        41,
+        "accounts_query_failed",
+        "Error, could not query the accounts",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::blocking::Client;
+
+    // the routes export `uri!` macros the tests never use
+    #[allow(unused_imports)]
+    mod routes {
+        use super::super::*;
+
+        #[rocket::get("/<field>")]
+        pub fn invalid(field: &str) -> Answer {
+            field_invalid(field, "is too short")
+        }
+
+        #[rocket::get("/")]
+        pub fn unknown_hash() -> Answer {
+            hash_cant_query()
+        }
+    }
+
+    #[test]
+    fn answers_with_the_status_and_kind() {
+        let rocket =
+            rocket::build().mount("/", rocket::routes![routes::invalid, routes::unknown_hash]);
+        let client = Client::untracked(rocket).unwrap();
+
+        let response = client.get("/").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["code"], 4);
+        assert_eq!(body["kind"], "audiobook_not_found");
+        assert!(body.get("field").is_none());
+        assert!(body.get("status").is_none());
+
+        let response = client.get("/user").dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["kind"], "invalid_field");
+        assert_eq!(body["field"], "user");
+        assert_eq!(body["details"]["reason"], "is too short");
     }
 }
@@ -0,0 +1,116 @@
+#[cfg(feature = "ldap")]
+use ldap3::{ldap_escape, LdapConnAsync, Scope, SearchEntry};
+
+/// The `[ldap]` section of the config
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+pub struct LdapConfig {
+    /// Address of the server, like `ldap://localhost:389`
+    pub url: String,
+    /// Account used to search for users, anonymous if not given
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    /// Where users are searched
+    pub base_dn: String,
+    /// Filter finding a user, `{user}` is replaced by the escaped username
+    #[serde(default = "default_user_filter")]
+    pub user_filter: String,
+    /// Group users must be a member of to login
+    pub group_dn: Option<String>,
+    /// Filter a user must match in `group_dn`, `{dn}` is replaced by their dn
+    #[serde(default = "default_group_filter")]
+    pub group_filter: String,
+}
+
+fn default_user_filter() -> String {
+    String::from("(uid={user})")
+}
+
+fn default_group_filter() -> String {
+    String::from("(member={dn})")
+}
+
+/// What the ldap server answered to a login
+#[cfg(feature = "ldap")]
+#[derive(Debug, PartialEq)]
+pub enum Authentication {
+    Accepted,
+    /// The user exists but is not a member of `group_dn`
+    NotInGroup,
+    /// The user does not exist or the password is wrong
+    Rejected,
+}
+
+/// Checks a username and password against the ldap server
+#[cfg(feature = "ldap")]
+pub async fn authenticate(
+    config: &LdapConfig,
+    user: &str,
+    password: &str,
+) -> Result<Authentication, ldap3::LdapError> {
+    // an empty password would be an unauthenticated bind, which succeeds
+    if password.is_empty() {
+        return Ok(Authentication::Rejected);
+    }
+
+    let (conn, mut ldap) = LdapConnAsync::new(&config.url).await?;
+    ldap3::drive!(conn);
+
+    if let Some(bind_dn) = &config.bind_dn {
+        let bind_password = config.bind_password.clone().unwrap_or_default();
+        ldap.simple_bind(bind_dn, &bind_password).await?.success()?;
+    }
+
+    let filter = config.user_filter.replace("{user}", &ldap_escape(user));
+    let (entries, _) = ldap
+        .search(&config.base_dn, Scope::Subtree, &filter, vec!["dn"])
+        .await?
+        .success()?;
+    if entries.len() != 1 {
+        ldap.unbind().await?;
+        return Ok(Authentication::Rejected);
+    }
+    let dn = SearchEntry::construct(entries.into_iter().next().unwrap()).dn;
+
+    if let Some(group_dn) = &config.group_dn {
+        let filter = config
+            .group_filter
+            .replace("{dn}", &ldap_escape(dn.as_str()));
+        let (groups, _) = ldap
+            .search(group_dn, Scope::Base, &filter, vec!["dn"])
+            .await?
+            .success()?;
+        if groups.is_empty() {
+            ldap.unbind().await?;
+            return Ok(Authentication::NotInGroup);
+        }
+    }
+
+    let authenticated = ldap.simple_bind(&dn, password).await?.success().is_ok();
+    ldap.unbind().await?;
+
+    if authenticated {
+        Ok(Authentication::Accepted)
+    } else {
+        Ok(Authentication::Rejected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_the_filters() {
+        let config: LdapConfig = toml::from_str(
+            r#"
+            url = "ldap://localhost:389"
+            base_dn = "ou=people,dc=example,dc=org"
+            group_dn = "cn=readers,ou=groups,dc=example,dc=org"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.user_filter, "(uid={user})");
+        assert_eq!(config.group_filter, "(member={dn})");
+        assert!(config.bind_dn.is_none());
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of tracked clients above which stale entries get cleaned up
+const PRUNE_THRESHOLD: usize = 1024;
+
+pub struct Limits {
+    /// Failed attempts allowed from an ip address before it gets locked out
+    pub ip_attempts: u32,
+    /// Failed attempts allowed on a username before it gets locked out
+    pub user_attempts: u32,
+    /// Duration of the first lockout, doubled on every further failure
+    pub lockout: Duration,
+    pub max_lockout: Duration,
+    /// Proxies allowed to give the address of the client in X-Forwarded-For
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+struct Entry {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Keeps track of failed login and register attempts and of the accounts
+/// created, per ip address and per username, and locks them out with an
+/// exponential backoff
+pub struct RateLimiter {
+    limits: Limits,
+    ips: Mutex<HashMap<IpAddr, Entry>>,
+    users: Mutex<HashMap<String, Entry>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            ips: Mutex::new(HashMap::new()),
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Finds the address of the client, X-Forwarded-For is only honored when
+    /// the request comes from a trusted proxy
+    pub fn client_ip(&self, remote: IpAddr, forwarded: Option<&str>) -> IpAddr {
+        if !self.limits.trusted_proxies.contains(&remote) {
+            return remote;
+        }
+
+        let forwarded = match forwarded {
+            Some(forwarded) => forwarded,
+            None => return remote,
+        };
+
+        // every proxy appends the address it received the request from, so the
+        // client is the rightmost address that is not one of our proxies
+        let mut client = remote;
+        for address in forwarded.rsplit(',') {
+            match address.trim().parse::<IpAddr>() {
+                Ok(address) => {
+                    client = address;
+                    if !self.limits.trusted_proxies.contains(&address) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        client
+    }
+
+    /// Checks whether the ip address and username are allowed to try again
+    pub fn check(&self, ip: IpAddr, user: Option<&str>) -> bool {
+        let now = Instant::now();
+
+        if is_locked(self.ips.lock().unwrap().get(&ip), now) {
+            return false;
+        }
+
+        if let Some(user) = user {
+            if is_locked(self.users.lock().unwrap().get(user), now) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub fn record_failure(&self, ip: IpAddr, user: Option<&str>) {
+        let now = Instant::now();
+
+        let mut ips = self.ips.lock().unwrap();
+        if ips.len() > PRUNE_THRESHOLD {
+            prune(&mut ips, &self.limits, now);
+        }
+        fail(
+            ips.entry(ip).or_insert_with(|| new_entry(now)),
+            self.limits.ip_attempts,
+            &self.limits,
+            now,
+        );
+        drop(ips);
+
+        if let Some(user) = user {
+            let mut users = self.users.lock().unwrap();
+            if users.len() > PRUNE_THRESHOLD {
+                prune(&mut users, &self.limits, now);
+            }
+            fail(
+                users
+                    .entry(user.to_string())
+                    .or_insert_with(|| new_entry(now)),
+                self.limits.user_attempts,
+                &self.limits,
+                now,
+            );
+        }
+    }
+
+    /// Forgets the failures on a username once its owner logged in. Those of
+    /// the address are kept until they expire, or logging into an account of
+    /// their own between guesses would let a client try forever.
+    pub fn record_success(&self, user: &str) {
+        self.users.lock().unwrap().remove(user);
+    }
+
+    /// Counts an attempt that succeeded against the address anyway, so a
+    /// client can not create accounts without limit
+    pub fn record_attempt(&self, ip: IpAddr) {
+        self.record_failure(ip, None);
+    }
+}
+
+fn new_entry(now: Instant) -> Entry {
+    Entry {
+        failures: 0,
+        last_failure: now,
+        locked_until: None,
+    }
+}
+
+fn is_locked(entry: Option<&Entry>, now: Instant) -> bool {
+    match entry.and_then(|entry| entry.locked_until) {
+        Some(locked_until) => locked_until > now,
+        None => false,
+    }
+}
+
+fn fail(entry: &mut Entry, attempts: u32, limits: &Limits, now: Instant) {
+    // failures are forgotten once the client behaved for a whole max lockout
+    if now.duration_since(entry.last_failure) > limits.max_lockout {
+        entry.failures = 0;
+    }
+
+    entry.failures += 1;
+    entry.last_failure = now;
+
+    if entry.failures >= attempts {
+        let exponent = (entry.failures - attempts).min(31);
+        let lockout = limits
+            .lockout
+            .checked_mul(1 << exponent)
+            .unwrap_or(limits.max_lockout)
+            .min(limits.max_lockout);
+        entry.locked_until = Some(now + lockout);
+    }
+}
+
+fn prune<K>(entries: &mut HashMap<K, Entry>, limits: &Limits, now: Instant) {
+    entries.retain(|_, entry| {
+        now.duration_since(entry.last_failure) <= limits.max_lockout || is_locked(Some(entry), now)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            ip_attempts: 3,
+            user_attempts: 2,
+            lockout: Duration::from_secs(60),
+            max_lockout: Duration::from_secs(3600),
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap()],
+        }
+    }
+
+    fn locked_for(entry: &Entry, now: Instant) -> Duration {
+        entry.locked_until.unwrap().duration_since(now)
+    }
+
+    #[test]
+    fn locks_out_after_the_allowed_attempts() {
+        let limits = limits();
+        let now = Instant::now();
+        let mut entry = new_entry(now);
+
+        fail(&mut entry, 3, &limits, now);
+        fail(&mut entry, 3, &limits, now);
+        assert!(!is_locked(Some(&entry), now));
+
+        fail(&mut entry, 3, &limits, now);
+        assert!(is_locked(Some(&entry), now));
+        assert_eq!(locked_for(&entry, now), limits.lockout);
+        assert!(!is_locked(Some(&entry), now + limits.lockout));
+    }
+
+    #[test]
+    fn doubles_the_lockout_up_to_the_maximum() {
+        let limits = limits();
+        let now = Instant::now();
+        let mut entry = new_entry(now);
+
+        fail(&mut entry, 1, &limits, now);
+        assert_eq!(locked_for(&entry, now), Duration::from_secs(60));
+        fail(&mut entry, 1, &limits, now);
+        assert_eq!(locked_for(&entry, now), Duration::from_secs(120));
+        fail(&mut entry, 1, &limits, now);
+        assert_eq!(locked_for(&entry, now), Duration::from_secs(240));
+
+        for _ in 0..100 {
+            fail(&mut entry, 1, &limits, now);
+        }
+        assert_eq!(locked_for(&entry, now), limits.max_lockout);
+    }
+
+    #[test]
+    fn forgets_failures_after_a_quiet_max_lockout() {
+        let limits = limits();
+        let now = Instant::now();
+        let mut entry = new_entry(now);
+
+        fail(&mut entry, 3, &limits, now);
+        fail(&mut entry, 3, &limits, now);
+        let later = now + limits.max_lockout + Duration::from_secs(1);
+        fail(&mut entry, 3, &limits, later);
+        assert_eq!(entry.failures, 1);
+        assert!(!is_locked(Some(&entry), later));
+    }
+
+    #[test]
+    fn success_only_clears_the_username() {
+        let limiter = RateLimiter::new(limits());
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        limiter.record_failure(ip, Some("alice"));
+        limiter.record_failure(ip, Some("alice"));
+        assert!(!limiter.check(ip, Some("alice")));
+
+        limiter.record_success("alice");
+        assert!(limiter.check(ip, Some("alice")));
+        // the third failure of the address still locks it out
+        limiter.record_failure(ip, Some("carol"));
+        assert!(!limiter.check(ip, None));
+    }
+
+    #[test]
+    fn limits_accounts_created_from_an_address() {
+        let limiter = RateLimiter::new(limits());
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(limiter.check(ip, None));
+            limiter.record_attempt(ip);
+        }
+        assert!(!limiter.check(ip, None));
+    }
+
+    #[test]
+    fn only_trusts_forwarded_addresses_from_proxies() {
+        let limiter = RateLimiter::new(limits());
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(
+            limiter.client_ip(proxy, Some("198.51.100.1, 192.0.2.1")),
+            client
+        );
+        assert_eq!(limiter.client_ip(client, Some("198.51.100.1")), client);
+        assert_eq!(limiter.client_ip(proxy, None), proxy);
+    }
+
+    #[test]
+    fn prunes_only_the_stale_entries() {
+        let limits = limits();
+        let now = Instant::now();
+        let later = now + limits.max_lockout + Duration::from_secs(1);
+        let mut entries = HashMap::new();
+
+        entries.insert("recent", new_entry(later));
+        entries.insert("stale", new_entry(now));
+        let mut locked = new_entry(now);
+        locked.locked_until = Some(later + limits.lockout);
+        entries.insert("locked", locked);
+
+        prune(&mut entries, &limits, later);
+        let mut kept: Vec<&str> = entries.keys().copied().collect();
+        kept.sort();
+        assert_eq!(kept, ["locked", "recent"]);
+    }
+}
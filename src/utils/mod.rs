@@ -1,2 +1,6 @@
 pub mod cli;
+pub mod commands;
 pub mod error;
+pub mod ldap;
+pub mod limiter;
+pub mod validation;
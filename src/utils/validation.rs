@@ -0,0 +1,107 @@
+use crate::utils::error;
+
+/// Rules usernames and passwords have to follow when an account is created
+/// or modified
+pub struct Rules {
+    pub user_min_length: usize,
+    pub user_max_length: usize,
+    /// Characters allowed in usernames on top of ascii letters and digits
+    pub user_chars: String,
+    pub password_min_length: usize,
+    /// Number of character classes (lowercase, uppercase, digits and others)
+    /// a password has to mix
+    pub password_min_classes: usize,
+}
+
+impl Rules {
+    pub fn check_user(&self, user: &str) -> Result<(), error::Answer> {
+        let length = user.chars().count();
+        if length < self.user_min_length {
+            return Err(error::field_invalid(
+                "user",
+                &format!("must be at least {} characters long", self.user_min_length),
+            ));
+        }
+        if length > self.user_max_length {
+            return Err(error::field_invalid(
+                "user",
+                &format!("must be at most {} characters long", self.user_max_length),
+            ));
+        }
+        if let Some(c) = user
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && !self.user_chars.contains(*c))
+        {
+            return Err(error::field_invalid(
+                "user",
+                &format!("character {:?} is not allowed", c),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn check_password(&self, password: &str) -> Result<(), error::Answer> {
+        if password.chars().count() < self.password_min_length {
+            return Err(error::field_invalid(
+                "password",
+                &format!(
+                    "must be at least {} characters long",
+                    self.password_min_length
+                ),
+            ));
+        }
+
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_numeric()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|class| **class).count() < self.password_min_classes {
+            return Err(error::field_invalid(
+                "password",
+                &format!(
+                    "must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                    self.password_min_classes
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> Rules {
+        Rules {
+            user_min_length: 3,
+            user_max_length: 8,
+            user_chars: String::from("-_."),
+            password_min_length: 8,
+            password_min_classes: 3,
+        }
+    }
+
+    #[test]
+    fn checks_usernames() {
+        let rules = rules();
+        assert!(rules.check_user("alice").is_ok());
+        assert!(rules.check_user("j.doe-2").is_ok());
+        assert!(rules.check_user("al").is_err());
+        assert!(rules.check_user("alexandra").is_err());
+        assert!(rules.check_user("al ice").is_err());
+        assert!(rules.check_user("élise").is_err());
+    }
+
+    #[test]
+    fn checks_passwords() {
+        let rules = rules();
+        assert!(rules.check_password("Secret12").is_ok());
+        assert!(rules.check_password("secret-12").is_ok());
+        assert!(rules.check_password("Secr1").is_err());
+        assert!(rules.check_password("secretsecret").is_err());
+        assert!(rules.check_password("secret12").is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! Backs a database up while it is in use and restores it once it is not.
+
+use rocket_db_pools::sqlx;
+use std::path::{Path, PathBuf};
+
+use illiad::database::backup;
+use illiad::database::schema;
+use illiad::database::sqlite::SqliteStorage;
+use illiad::database::storage::Storage;
+
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "illiad-backup-{}-{}.sqlite",
+        name,
+        std::process::id()
+    ));
+    remove(&path);
+    path
+}
+
+fn remove(path: &Path) {
+    for suffix in ["", "-wal", "-shm"].iter() {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+}
+
+async fn open(path: &Path) -> sqlx::Pool<sqlx::Sqlite> {
+    schema::create_pool(
+        path.to_path_buf(),
+        schema::PoolSettings {
+            max_connections: 2,
+            busy_timeout: std::time::Duration::from_secs(5),
+        },
+    )
+    .await
+}
+
+/// Closes every connection of the pool, sqlx may still be handing one back
+/// when the pool closes and it then stays open until closed again
+async fn close(pool: &sqlx::Pool<sqlx::Sqlite>) {
+    pool.close().await;
+    while pool.size() > 0 {
+        rocket::tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        pool.close().await;
+    }
+}
+
+#[rocket::async_test]
+async fn backs_up_and_restores() {
+    let target = temp_path("target");
+    let copy = temp_path("copy");
+
+    let pool = open(&target).await;
+    let db = SqliteStorage::new(pool.clone());
+    db.migrate().await.unwrap();
+    db.insert_user(String::from("alice"), String::from("password"))
+        .await
+        .unwrap();
+
+    // the server keeps the database open while it is backed up
+    backup::backup(&copy, &pool).await.unwrap();
+    assert!(backup::backup(&copy, &pool).await.is_err());
+
+    db.insert_user(String::from("bob"), String::from("password"))
+        .await
+        .unwrap();
+
+    // but it must be stopped to restore one
+    assert!(backup::restore(&copy, &target).await.is_err());
+    assert!(db.query_account(String::from("bob")).await.is_ok());
+
+    close(&pool).await;
+    backup::restore(&copy, &target).await.unwrap();
+
+    let pool = open(&target).await;
+    let db = SqliteStorage::new(pool.clone());
+    assert!(db.query_account(String::from("alice")).await.is_ok());
+    assert!(db.query_account(String::from("bob")).await.is_err());
+    close(&pool).await;
+
+    remove(&target);
+    remove(&copy);
+}
+
+#[rocket::async_test]
+async fn refuses_to_restore_what_is_not_a_database() {
+    let target = temp_path("invalid-target");
+    let invalid = temp_path("invalid");
+    std::fs::write(&invalid, "not a database").unwrap();
+
+    assert!(backup::restore(&invalid, &target).await.is_err());
+    assert!(!target.exists());
+
+    remove(&invalid);
+}
@@ -0,0 +1,157 @@
+//! Logs in against a real ldap server. The tests are ignored by default, run
+//! them with a local [glauth](https://github.com/glauth/glauth) serving its
+//! sample config:
+//!
+//! ```bash
+//! glauth -c sample-simple.cfg &
+//! cargo test --features ldap --test ldap -- --ignored
+//! ```
+//!
+//! `TEST_LDAP_URL` points them to another server than `ldap://127.0.0.1:3893`.
+#![cfg(feature = "ldap")]
+
+use illiad::api::controllers;
+use illiad::database::schema;
+use illiad::database::sqlite::SqliteStorage;
+use illiad::database::storage::Storage;
+use illiad::models::session;
+use illiad::utils::ldap;
+
+fn config() -> ldap::LdapConfig {
+    ldap::LdapConfig {
+        url: std::env::var("TEST_LDAP_URL")
+            .unwrap_or_else(|_| String::from("ldap://127.0.0.1:3893")),
+        bind_dn: Some(String::from("cn=serviceuser,ou=svcaccts,dc=glauth,dc=com")),
+        bind_password: Some(String::from("mysecret")),
+        base_dn: String::from("dc=glauth,dc=com"),
+        user_filter: String::from("(cn={user})"),
+        group_dn: None,
+        group_filter: String::from("(member={dn})"),
+    }
+}
+
+async fn storage(name: &str) -> SqliteStorage {
+    let path = std::env::temp_dir().join(format!(
+        "illiad-ldap-{}-{}.sqlite",
+        name,
+        std::process::id()
+    ));
+    for suffix in ["", "-wal", "-shm"].iter() {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+
+    let pool = schema::create_pool(
+        path,
+        schema::PoolSettings {
+            max_connections: 1,
+            busy_timeout: std::time::Duration::from_secs(5),
+        },
+    )
+    .await;
+    let db = SqliteStorage::new(pool);
+    db.migrate().await.unwrap();
+    db
+}
+
+async fn login(
+    user: &str,
+    password: &str,
+    config: &ldap::LdapConfig,
+    db: &dyn Storage,
+) -> Option<String> {
+    let lifetimes = session::Lifetimes {
+        access: 3600,
+        refresh: 3600,
+    };
+    match controllers::get_account(
+        String::from(user),
+        String::from(password),
+        false,
+        &lifetimes,
+        Some(config),
+        db,
+    )
+    .await
+    {
+        Ok(login) => match login.into_inner() {
+            controllers::Login::Key(key) => Some(key.key),
+            controllers::Login::Session(_) => None,
+        },
+        Err(_) => None,
+    }
+}
+
+#[rocket::async_test]
+#[ignore]
+async fn authenticates_against_the_server() {
+    let config = config();
+    assert_eq!(
+        ldap::authenticate(&config, "hackers", "dogood")
+            .await
+            .unwrap(),
+        ldap::Authentication::Accepted
+    );
+    assert_eq!(
+        ldap::authenticate(&config, "hackers", "wrong")
+            .await
+            .unwrap(),
+        ldap::Authentication::Rejected
+    );
+    assert_eq!(
+        ldap::authenticate(&config, "nobody", "dogood")
+            .await
+            .unwrap(),
+        ldap::Authentication::Rejected
+    );
+}
+
+#[rocket::async_test]
+#[ignore]
+async fn creates_the_account_on_the_first_login() {
+    let db = storage("first").await;
+
+    let key = login("hackers", "dogood", &config(), &db).await.unwrap();
+    assert_eq!(db.query_user(key.clone()).await.unwrap(), "hackers");
+    // the second login gets the same account
+    assert_eq!(
+        login("hackers", "dogood", &config(), &db).await.unwrap(),
+        key
+    );
+    assert!(login("hackers", "wrong", &config(), &db).await.is_none());
+}
+
+#[rocket::async_test]
+#[ignore]
+async fn never_logs_into_a_local_account() {
+    let db = storage("local").await;
+    let key = db
+        .insert_user(String::from("hackers"), String::from("local"))
+        .await
+        .unwrap();
+
+    // the ldap password does not open the local account, its own still does
+    assert!(login("hackers", "dogood", &config(), &db).await.is_none());
+    assert_eq!(
+        login("hackers", "local", &config(), &db).await.unwrap(),
+        key
+    );
+}
+
+#[rocket::async_test]
+#[ignore]
+async fn disables_the_account_of_users_out_of_the_group() {
+    let db = storage("group").await;
+    login("hackers", "dogood", &config(), &db).await.unwrap();
+
+    let mut out_of_group = config();
+    out_of_group.group_dn = Some(String::from("ou=nobody,dc=glauth,dc=com"));
+    assert!(login("hackers", "dogood", &out_of_group, &db)
+        .await
+        .is_none());
+    // back in the group, an administrator has to enable the account again
+    assert!(login("hackers", "dogood", &config(), &db).await.is_none());
+    db.update_disabled(String::from("hackers"), false)
+        .await
+        .unwrap();
+    assert!(login("hackers", "dogood", &config(), &db).await.is_some());
+}